@@ -0,0 +1,269 @@
+use std::{
+	io::{self, Read, Write},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use thiserror::Error;
+
+use crate::{
+	interface::{MessageSide},
+	wire::{MessageHeader},
+};
+
+/// The bytes every capture file starts with.
+pub const CAPTURE_MAGIC: [u8; 8] = *b"WLCAPTUR";
+/// The version of the capture format written by `CaptureWriter`. Readers reject captures with a different version.
+pub const CAPTURE_VERSION: u32 = 1;
+
+// Capture files are always little endian so they can be read on a different machine than the one that wrote them.
+//
+// Layout, after the magic and the version:
+//   timestamp: u64 microseconds since the unix epoch
+//   client: u32
+//   direction: u8 (0 = request, 1 = event)
+//   sender: u32, opcode: u16, msg_size: u16
+//   payload length: u32, payload
+//   fd count: u32, then for every fd a u8 flag and, if set, a u32 length and the fd's contents
+
+/// A single message sent between the server and one of its clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+	pub timestamp: SystemTime,
+	pub client: u32,
+	/// `Request` for messages from the client, `Event` for messages from the server.
+	pub direction: MessageSide,
+	pub header: MessageHeader,
+	/// The message body, without the header.
+	pub payload: Vec<u8>,
+	/// One entry per file descriptor sent with the message, with the contents of the fd if they were recorded.
+	pub fds: Vec<Option<Vec<u8>>>,
+}
+
+impl CaptureRecord {
+	pub fn fd_count(&self) -> usize {
+		self.fds.len()
+	}
+}
+
+/// Writes capture records to a byte stream.
+#[derive(Debug)]
+pub struct CaptureWriter<W: Write> {
+	writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+	/// Writes the capture file header.
+	pub fn new(mut writer: W) -> Result<Self, CaptureError> {
+		writer.write_all(&CAPTURE_MAGIC)?;
+		writer.write_u32::<LittleEndian>(CAPTURE_VERSION)?;
+		Ok(Self {
+			writer,
+		})
+	}
+
+	pub fn write_record(&mut self, record: &CaptureRecord) -> Result<(), CaptureError> {
+		let timestamp = record.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+		self.writer.write_u64::<LittleEndian>(timestamp.as_micros() as u64)?;
+		self.writer.write_u32::<LittleEndian>(record.client)?;
+		self.writer.write_u8(match record.direction {
+			MessageSide::Request => 0,
+			MessageSide::Event => 1,
+		})?;
+		self.writer.write_u32::<LittleEndian>(record.header.sender)?;
+		self.writer.write_u16::<LittleEndian>(record.header.opcode)?;
+		self.writer.write_u16::<LittleEndian>(record.header.msg_size)?;
+		self.writer.write_u32::<LittleEndian>(record.payload.len() as u32)?;
+		self.writer.write_all(&record.payload)?;
+		self.writer.write_u32::<LittleEndian>(record.fds.len() as u32)?;
+		for contents in &record.fds {
+			match contents {
+				Some(contents) => {
+					self.writer.write_u8(1)?;
+					self.writer.write_u32::<LittleEndian>(contents.len() as u32)?;
+					self.writer.write_all(contents)?;
+				},
+				None => self.writer.write_u8(0)?,
+			}
+		}
+		Ok(())
+	}
+
+	pub fn flush(&mut self) -> Result<(), CaptureError> {
+		self.writer.flush()?;
+		Ok(())
+	}
+
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+}
+
+/// Reads capture records from a byte stream, in the order they were written.
+#[derive(Debug)]
+pub struct CaptureReader<R: Read> {
+	reader: R,
+	version: u32,
+}
+
+impl<R: Read> CaptureReader<R> {
+	/// Reads and checks the capture file header.
+	pub fn new(mut reader: R) -> Result<Self, CaptureError> {
+		let mut magic = [0u8; 8];
+		reader.read_exact(&mut magic)?;
+		if magic != CAPTURE_MAGIC {
+			return Err(CaptureError::BadMagic);
+		}
+		let version = reader.read_u32::<LittleEndian>()?;
+		if version != CAPTURE_VERSION {
+			return Err(CaptureError::UnsupportedVersion(version));
+		}
+		Ok(Self {
+			reader,
+			version,
+		})
+	}
+
+	pub fn version(&self) -> u32 {
+		self.version
+	}
+
+	/// Reads the next record, or returns `None` at the end of the capture.
+	pub fn next_record(&mut self) -> Result<Option<CaptureRecord>, CaptureError> {
+		// A capture ends cleanly only between records
+		let timestamp = match self.reader.read_u64::<LittleEndian>() {
+			Ok(timestamp) => timestamp,
+			Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+			Err(e) => return Err(e.into()),
+		};
+		let client = self.reader.read_u32::<LittleEndian>()?;
+		let direction = match self.reader.read_u8()? {
+			0 => MessageSide::Request,
+			1 => MessageSide::Event,
+			direction => return Err(CaptureError::InvalidDirection(direction)),
+		};
+		let header = MessageHeader {
+			sender: self.reader.read_u32::<LittleEndian>()?,
+			opcode: self.reader.read_u16::<LittleEndian>()?,
+			msg_size: self.reader.read_u16::<LittleEndian>()?,
+		};
+		let payload = self.read_bytes()?;
+		let fd_count = self.reader.read_u32::<LittleEndian>()?;
+		let mut fds = Vec::new();
+		for _ in 0..fd_count {
+			fds.push(match self.reader.read_u8()? {
+				0 => None,
+				_ => Some(self.read_bytes()?),
+			});
+		}
+
+		Ok(Some(CaptureRecord {
+			timestamp: UNIX_EPOCH + Duration::from_micros(timestamp),
+			client,
+			direction,
+			header,
+			payload,
+			fds,
+		}))
+	}
+
+	fn read_bytes(&mut self) -> Result<Vec<u8>, CaptureError> {
+		let len = self.reader.read_u32::<LittleEndian>()?;
+		let mut bytes = Vec::new();
+		(&mut self.reader).take(len as u64).read_to_end(&mut bytes)?;
+		if bytes.len() != len as usize {
+			return Err(CaptureError::Truncated);
+		}
+		Ok(bytes)
+	}
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+	type Item = Result<CaptureRecord, CaptureError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.next_record().transpose()
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+	#[error("Failed to access capture\n\t{0}")]
+	Io(#[from] io::Error),
+	#[error("The data is not a capture file")]
+	BadMagic,
+	#[error("Unsupported capture version {0}")]
+	UnsupportedVersion(u32),
+	#[error("Invalid message direction {0} in capture")]
+	InvalidDirection(u8),
+	#[error("The capture ended in the middle of a record")]
+	Truncated,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn records() -> Vec<CaptureRecord> {
+		vec![
+			CaptureRecord {
+				timestamp: UNIX_EPOCH + Duration::from_micros(1_600_000_000_123_456),
+				client: 1,
+				direction: MessageSide::Request,
+				header: MessageHeader {
+					sender: 1,
+					opcode: 1,
+					msg_size: 12,
+				},
+				payload: vec![2, 0, 0, 0],
+				fds: Vec::new(),
+			},
+			CaptureRecord {
+				timestamp: UNIX_EPOCH + Duration::from_micros(1_600_000_000_234_567),
+				client: 2,
+				direction: MessageSide::Event,
+				header: MessageHeader {
+					sender: 3,
+					opcode: 0,
+					msg_size: 8,
+				},
+				payload: Vec::new(),
+				fds: vec![None, Some(b"keymap".to_vec())],
+			},
+		]
+	}
+
+	fn write(records: &[CaptureRecord]) -> Vec<u8> {
+		let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+		for record in records {
+			writer.write_record(record).unwrap();
+		}
+		writer.into_inner()
+	}
+
+	#[test]
+	fn records_round_trip() {
+		let records = records();
+		let reader = CaptureReader::new(io::Cursor::new(write(&records))).unwrap();
+		assert_eq!(reader.version(), CAPTURE_VERSION);
+		assert_eq!(reader.collect::<Result<Vec<_>, _>>().unwrap(), records);
+	}
+
+	#[test]
+	fn other_files_are_rejected() {
+		let mut bytes = write(&[]);
+		bytes[8] = 2;
+		assert!(matches!(CaptureReader::new(io::Cursor::new(&bytes)), Err(CaptureError::UnsupportedVersion(2))));
+		bytes[0] = b'X';
+		assert!(matches!(CaptureReader::new(io::Cursor::new(&bytes)), Err(CaptureError::BadMagic)));
+	}
+
+	#[test]
+	fn truncated_records_are_errors() {
+		let mut bytes = write(&records());
+		bytes.truncate(bytes.len() - 2);
+		let mut reader = CaptureReader::new(io::Cursor::new(bytes)).unwrap();
+		assert!(reader.next_record().unwrap().is_some());
+		assert!(matches!(reader.next_record(), Err(CaptureError::Truncated)));
+	}
+}
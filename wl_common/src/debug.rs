@@ -0,0 +1,184 @@
+use std::{
+	fmt,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+	interface::{InterfaceSignature, MessageSignature, MessageSide},
+	wire::{DynMessage, DynArgument, ArgumentSignature},
+};
+
+/// Renders a message in the format libwayland uses for `WAYLAND_DEBUG`, for example
+/// `[1234567.890]  -> wl_registry@2.global(1, "wl_compositor", 4)`.
+///
+/// Object arguments are printed with the interface returned by the `object_interface` lookup, falling back to the
+/// interface named in the message description. Works with both static and runtime interface descriptions.
+pub struct DebugMessage<'a, I, F> {
+	interface: &'a I,
+	message: &'a DynMessage,
+	side: MessageSide,
+	outgoing: bool,
+	discarded: bool,
+	timestamp: Option<SystemTime>,
+	object_interface: F,
+}
+
+fn no_object_interface<'a>(_id: u32) -> Option<&'a str> {
+	None
+}
+
+impl<'a, I: InterfaceSignature> DebugMessage<'a, I, fn(u32) -> Option<&'a str>> {
+	pub fn new(interface: &'a I, message: &'a DynMessage, side: MessageSide) -> Self {
+		Self {
+			interface,
+			message,
+			side,
+			outgoing: false,
+			discarded: false,
+			timestamp: Some(SystemTime::now()),
+			object_interface: no_object_interface,
+		}
+	}
+}
+
+impl<'a, I: InterfaceSignature, F: Fn(u32) -> Option<&'a str>> DebugMessage<'a, I, F> {
+	/// Whether the message is being sent rather than received, which prefixes it with ` -> `.
+	pub fn outgoing(mut self, outgoing: bool) -> Self {
+		self.outgoing = outgoing;
+		self
+	}
+
+	/// Whether the message was dropped instead of being delivered, which prefixes it with `discarded `.
+	pub fn discarded(mut self, discarded: bool) -> Self {
+		self.discarded = discarded;
+		self
+	}
+
+	pub fn timestamp(mut self, timestamp: SystemTime) -> Self {
+		self.timestamp = Some(timestamp);
+		self
+	}
+
+	/// Leaves out the timestamp, for comparing messages or printing them next to other text.
+	pub fn without_timestamp(mut self) -> Self {
+		self.timestamp = None;
+		self
+	}
+
+	pub fn object_interface<G: Fn(u32) -> Option<&'a str>>(self, object_interface: G) -> DebugMessage<'a, I, G> {
+		DebugMessage {
+			interface: self.interface,
+			message: self.message,
+			side: self.side,
+			outgoing: self.outgoing,
+			discarded: self.discarded,
+			timestamp: self.timestamp,
+			object_interface,
+		}
+	}
+}
+
+impl<'a, I: InterfaceSignature, F: Fn(u32) -> Option<&'a str>> fmt::Display for DebugMessage<'a, I, F> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		// libwayland truncates the wall clock time in microseconds to 32 bits, then prints it in milliseconds
+		if let Some(timestamp) = self.timestamp {
+			let micros = timestamp.duration_since(UNIX_EPOCH).map(|duration| duration.as_micros() as u32).unwrap_or(0);
+			write!(f, "[{:7}.{:03}] ", micros / 1000, micros % 1000)?;
+		}
+		if self.discarded {
+			write!(f, "discarded ")?;
+		}
+		if self.outgoing {
+			write!(f, " -> ")?;
+		}
+		let message_desc = self.interface.message(self.side, self.message.opcode);
+		let message_name = message_desc.map(|message| message.name()).unwrap_or("[unknown]");
+		write!(f, "{}@{}.{}(", self.interface.name(), self.message.sender, message_name)?;
+
+		let args_desc = message_desc.map(|message| message.args()).unwrap_or(&[]);
+		for (i, arg) in self.message.arguments.iter().enumerate() {
+			if i > 0 {
+				write!(f, ", ")?;
+			}
+			let desc_interface = args_desc.get(i).and_then(|desc| desc.interface());
+			match *arg {
+				DynArgument::Int(v) => write!(f, "{}", v)?,
+				DynArgument::Uint(v) => write!(f, "{}", v)?,
+				DynArgument::Fixed(v) => write!(f, "{:.6}", v.0 as i32 as f64 / 256.0)?,
+				DynArgument::String(Some(ref v)) => {
+					let v = v.strip_suffix(&[0]).unwrap_or(v);
+					write!(f, "\"{}\"", String::from_utf8_lossy(v))?
+				},
+				DynArgument::String(None) => write!(f, "nil")?,
+				DynArgument::Object(Some(id)) => {
+					let interface = (self.object_interface)(id).or(desc_interface).unwrap_or("[unknown]");
+					write!(f, "{}@{}", interface, id)?
+				},
+				DynArgument::Object(None) => write!(f, "nil")?,
+				DynArgument::NewId(id, ref title) => {
+					// Untyped new_ids carry the interface name and version on the wire, as in wl_registry.bind
+					if let Some(title) = title {
+						write!(f, "\"{}\", {}, ", title.name, title.version)?;
+					}
+					if id != 0 {
+						write!(f, "new id {}@{}", desc_interface.unwrap_or("[unknown]"), id)?;
+					} else {
+						write!(f, "nil")?;
+					}
+				},
+				DynArgument::Array(ref v) => write!(f, "array[{}]", v.len())?,
+				DynArgument::Fd(fd) => write!(f, "fd {}", fd)?,
+			}
+		}
+
+		write!(f, ")")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration};
+
+	use super::*;
+	use crate::{
+		interface::{DynInterface, MessageDesc},
+		wire::{ArgumentDesc, ArgumentType},
+	};
+
+	static DISPLAY: DynInterface = DynInterface {
+		name: "wl_display",
+		version: 1,
+		requests: &[MessageDesc {
+			name: "sync",
+			since: 1,
+			destructor: false,
+			args: &[ArgumentDesc {
+				name: "callback",
+				arg_type: ArgumentType::NewId,
+				interface: Some("wl_callback"),
+				allow_null: false,
+			}],
+		}],
+		events: &[],
+	};
+
+	#[test]
+	fn null_new_ids_are_nil() {
+		let message = DynMessage::new(1, 0, vec![DynArgument::NewId(0, None)]);
+		let debug = DebugMessage::new(&DISPLAY, &message, MessageSide::Request).without_timestamp().to_string();
+		assert_eq!(debug, "wl_display@1.sync(nil)");
+
+		let message = DynMessage::new(1, 0, vec![DynArgument::NewId(2, None)]);
+		let debug = DebugMessage::new(&DISPLAY, &message, MessageSide::Request).without_timestamp().to_string();
+		assert_eq!(debug, "wl_display@1.sync(new id wl_callback@2)");
+	}
+
+	#[test]
+	fn timestamps_are_truncated_like_libwayland() {
+		let message = DynMessage::new(1, 0, vec![DynArgument::NewId(2, None)]);
+		// 2^32 + 1234567 microseconds
+		let timestamp = UNIX_EPOCH + Duration::from_micros((1 << 32) + 1_234_567);
+		let debug = DebugMessage::new(&DISPLAY, &message, MessageSide::Request).timestamp(timestamp).to_string();
+		assert_eq!(debug, "[   1234.567] wl_display@1.sync(new id wl_callback@2)");
+	}
+}
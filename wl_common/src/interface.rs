@@ -3,12 +3,12 @@ use std::{
 };
 
 use crate::{
-	wire::{ArgumentDesc, DynArgument, ArgumentError},
+	wire::{ArgumentDesc, ArgumentSignature, DynArgument, ArgumentError},
 };
 
 use thiserror::Error;
 
-pub type MessagesDesc = &'static [&'static [ArgumentDesc]];
+pub type MessagesDesc = &'static [MessageDesc];
 
 pub trait Interface {
 	type Request: Message;
@@ -69,6 +69,118 @@ impl DynInterface {
 	pub fn title(&self) -> InterfaceTitle {
 		InterfaceTitle::new(self.name.clone(), self.version)
 	}
+
+	pub fn messages(&self, side: MessageSide) -> MessagesDesc {
+		match side {
+			MessageSide::Request => self.requests,
+			MessageSide::Event => self.events,
+		}
+	}
+
+	pub fn message(&self, side: MessageSide, opcode: u16) -> Option<&'static MessageDesc> {
+		self.messages(side).get(opcode as usize)
+	}
+
+	pub fn request(&self, opcode: u16) -> Option<&'static MessageDesc> {
+		self.message(MessageSide::Request, opcode)
+	}
+
+	pub fn event(&self, opcode: u16) -> Option<&'static MessageDesc> {
+		self.message(MessageSide::Event, opcode)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageDesc {
+	pub name: &'static str,
+	/// The interface version this message was introduced in.
+	pub since: u32,
+	pub destructor: bool,
+	pub args: &'static [ArgumentDesc],
+}
+
+impl MessageDesc {
+	pub fn arg_by_name(&self, name: &str) -> Option<(usize, &'static ArgumentDesc)> {
+		self.args.iter().enumerate().find(|(_, arg)| arg.name == name)
+	}
+}
+
+/// A description of an interface, implemented by the static `DynInterface` and the runtime
+/// `registry::OwnedInterface`.
+pub trait InterfaceSignature {
+	type Message: MessageSignature;
+
+	fn name(&self) -> &str;
+	fn version(&self) -> u32;
+	fn messages(&self, side: MessageSide) -> &[Self::Message];
+
+	fn message(&self, side: MessageSide, opcode: u16) -> Option<&Self::Message> {
+		self.messages(side).get(opcode as usize)
+	}
+
+	fn request(&self, opcode: u16) -> Option<&Self::Message> {
+		self.message(MessageSide::Request, opcode)
+	}
+
+	fn event(&self, opcode: u16) -> Option<&Self::Message> {
+		self.message(MessageSide::Event, opcode)
+	}
+
+	/// Finds a message by name, returning its opcode along with its description.
+	fn message_by_name(&self, side: MessageSide, name: &str) -> Option<(u16, &Self::Message)> {
+		self.messages(side).iter().enumerate().find(|(_, message)| message.name() == name).map(|(opcode, message)| (opcode as u16, message))
+	}
+
+	fn request_by_name(&self, name: &str) -> Option<(u16, &Self::Message)> {
+		self.message_by_name(MessageSide::Request, name)
+	}
+
+	fn event_by_name(&self, name: &str) -> Option<(u16, &Self::Message)> {
+		self.message_by_name(MessageSide::Event, name)
+	}
+}
+
+/// A description of a request or event, implemented by the static `MessageDesc` and the runtime
+/// `registry::OwnedMessageDesc`.
+pub trait MessageSignature {
+	type Argument: ArgumentSignature;
+
+	fn name(&self) -> &str;
+	fn args(&self) -> &[Self::Argument];
+}
+
+impl InterfaceSignature for DynInterface {
+	type Message = MessageDesc;
+
+	fn name(&self) -> &str {
+		self.name
+	}
+
+	fn version(&self) -> u32 {
+		self.version
+	}
+
+	fn messages(&self, side: MessageSide) -> &[MessageDesc] {
+		DynInterface::messages(self, side)
+	}
+}
+
+impl MessageSignature for MessageDesc {
+	type Argument = ArgumentDesc;
+
+	fn name(&self) -> &str {
+		self.name
+	}
+
+	fn args(&self) -> &[ArgumentDesc] {
+		self.args
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageSide {
+	Request,
+	Event,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -135,3 +247,44 @@ pub enum IntoArgsError {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
 #[error("Got an invalid enum value")]
 pub struct InvalidEnumValue;
+
+/// An enum argument of a request. Clients can send values the protocol doesn't define, which are kept as is so the
+/// handler can answer them with the interface's own error, like `wl_shm.error.invalid_format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WEnum<T> {
+	Value(T),
+	Unknown(u32),
+}
+
+impl<T> WEnum<T> {
+	pub fn into_result(self) -> Result<T, InvalidEnumValue> {
+		match self {
+			WEnum::Value(value) => Ok(value),
+			WEnum::Unknown(_) => Err(InvalidEnumValue),
+		}
+	}
+}
+
+impl<T> From<T> for WEnum<T> {
+	fn from(value: T) -> Self {
+		WEnum::Value(value)
+	}
+}
+
+impl<T: Into<u32>> From<WEnum<T>> for u32 {
+	fn from(value: WEnum<T>) -> u32 {
+		match value {
+			WEnum::Value(value) => value.into(),
+			WEnum::Unknown(value) => value,
+		}
+	}
+}
+
+impl<T: Into<i32>> From<WEnum<T>> for i32 {
+	fn from(value: WEnum<T>) -> i32 {
+		match value {
+			WEnum::Value(value) => value.into(),
+			WEnum::Unknown(value) => value as i32,
+		}
+	}
+}
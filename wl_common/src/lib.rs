@@ -1,2 +1,6 @@
 pub mod wire;
-pub mod interface;
\ No newline at end of file
+pub mod interface;
+pub mod debug;
+pub mod capture;
+pub mod registry;
+pub mod socket;
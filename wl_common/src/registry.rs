@@ -0,0 +1,192 @@
+use std::{
+	collections::{HashMap},
+	iter::{FromIterator},
+};
+
+use crate::{
+	interface::{DynInterface, MessageDesc, MessageSide, InterfaceSignature, MessageSignature},
+	wire::{ArgumentDesc, ArgumentType, ArgumentSignature},
+};
+
+/// An argument description that owns its data, for protocols loaded at runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedArgumentDesc {
+	pub name: String,
+	pub arg_type: ArgumentType,
+	pub interface: Option<String>,
+	pub allow_null: bool,
+}
+
+impl ArgumentSignature for OwnedArgumentDesc {
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn arg_type(&self) -> ArgumentType {
+		self.arg_type
+	}
+
+	fn interface(&self) -> Option<&str> {
+		self.interface.as_deref()
+	}
+}
+
+impl From<&ArgumentDesc> for OwnedArgumentDesc {
+	fn from(desc: &ArgumentDesc) -> Self {
+		Self {
+			name: desc.name.to_owned(),
+			arg_type: desc.arg_type,
+			interface: desc.interface.map(str::to_owned),
+			allow_null: desc.allow_null,
+		}
+	}
+}
+
+/// A request or event description that owns its data, for protocols loaded at runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedMessageDesc {
+	pub name: String,
+	/// The interface version this message was introduced in.
+	pub since: u32,
+	pub destructor: bool,
+	pub args: Vec<OwnedArgumentDesc>,
+}
+
+impl OwnedMessageDesc {
+	pub fn arg_by_name(&self, name: &str) -> Option<(usize, &OwnedArgumentDesc)> {
+		self.args.iter().enumerate().find(|(_, arg)| arg.name == name)
+	}
+}
+
+impl MessageSignature for OwnedMessageDesc {
+	type Argument = OwnedArgumentDesc;
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn args(&self) -> &[OwnedArgumentDesc] {
+		&self.args
+	}
+}
+
+impl From<&MessageDesc> for OwnedMessageDesc {
+	fn from(desc: &MessageDesc) -> Self {
+		Self {
+			name: desc.name.to_owned(),
+			since: desc.since,
+			destructor: desc.destructor,
+			args: desc.args.iter().map(OwnedArgumentDesc::from).collect(),
+		}
+	}
+}
+
+/// An interface description that owns its data, the runtime counterpart of `DynInterface`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedInterface {
+	pub name: String,
+	pub version: u32,
+	pub requests: Vec<OwnedMessageDesc>,
+	pub events: Vec<OwnedMessageDesc>,
+}
+
+impl InterfaceSignature for OwnedInterface {
+	type Message = OwnedMessageDesc;
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn version(&self) -> u32 {
+		self.version
+	}
+
+	fn messages(&self, side: MessageSide) -> &[OwnedMessageDesc] {
+		match side {
+			MessageSide::Request => &self.requests,
+			MessageSide::Event => &self.events,
+		}
+	}
+}
+
+impl From<DynInterface> for OwnedInterface {
+	fn from(interface: DynInterface) -> Self {
+		Self {
+			name: interface.name.to_owned(),
+			version: interface.version,
+			requests: interface.requests.iter().map(OwnedMessageDesc::from).collect(),
+			events: interface.events.iter().map(OwnedMessageDesc::from).collect(),
+		}
+	}
+}
+
+/// A set of interfaces looked up by name, built at runtime. `wl_scanner` converts parsed protocol XML into one, and
+/// interfaces compiled into the program can be added with `OwnedInterface::from(I::as_dyn())`.
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceRegistry {
+	interfaces: Vec<OwnedInterface>,
+	by_name: HashMap<String, usize>,
+}
+
+impl InterfaceRegistry {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Adds an interface, replacing any interface with the same name.
+	pub fn add(&mut self, interface: OwnedInterface) {
+		match self.by_name.get(&interface.name) {
+			Some(&index) => self.interfaces[index] = interface,
+			None => {
+				self.by_name.insert(interface.name.clone(), self.interfaces.len());
+				self.interfaces.push(interface);
+			},
+		}
+	}
+
+	pub fn get(&self, name: &str) -> Option<&OwnedInterface> {
+		self.by_name.get(name).map(|&index| &self.interfaces[index])
+	}
+
+	pub fn contains(&self, name: &str) -> bool {
+		self.by_name.contains_key(name)
+	}
+
+	/// Iterates over the interfaces in the order they were first added.
+	pub fn iter(&self) -> impl Iterator<Item=&OwnedInterface> {
+		self.interfaces.iter()
+	}
+
+	pub fn len(&self) -> usize {
+		self.interfaces.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.interfaces.is_empty()
+	}
+}
+
+impl Extend<OwnedInterface> for InterfaceRegistry {
+	fn extend<T: IntoIterator<Item=OwnedInterface>>(&mut self, iter: T) {
+		for interface in iter {
+			self.add(interface);
+		}
+	}
+}
+
+impl FromIterator<OwnedInterface> for InterfaceRegistry {
+	fn from_iter<T: IntoIterator<Item=OwnedInterface>>(iter: T) -> Self {
+		let mut registry = Self::new();
+		registry.extend(iter);
+		registry
+	}
+}
+
+impl IntoIterator for InterfaceRegistry {
+	type Item = OwnedInterface;
+	type IntoIter = std::vec::IntoIter<OwnedInterface>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.interfaces.into_iter()
+	}
+}
@@ -0,0 +1,161 @@
+//! Blocking helpers for tools that talk wayland over a unix socket as a client, or on behalf of one.
+
+use std::{
+	os::unix::{net::{UnixStream}, io::{RawFd, AsRawFd}},
+	collections::{VecDeque},
+	path::{PathBuf},
+	env,
+};
+
+use nix::{
+	errno::Errno,
+	sys::{socket, uio::{IoVec}},
+};
+
+use crate::{
+	wire::{RawMessage, MessageHeader},
+};
+
+/// The most fds libwayland sends along with one chunk of data.
+pub const MAX_FDS: usize = 28;
+
+/// The name of the compositor socket to connect to: `name` if given, otherwise `$WAYLAND_DISPLAY` or `wayland-0`.
+pub fn display_name(name: Option<String>) -> String {
+	name.or_else(|| env::var("WAYLAND_DISPLAY").ok())
+		.unwrap_or_else(|| String::from("wayland-0"))
+}
+
+/// Resolves a socket name relative to `$XDG_RUNTIME_DIR`, unless it's an absolute path. Returns `None` if it's
+/// relative and `XDG_RUNTIME_DIR` isn't set.
+pub fn socket_path(name: &str) -> Option<PathBuf> {
+	let path = PathBuf::from(name);
+	if path.is_absolute() {
+		return Some(path);
+	}
+	env::var_os("XDG_RUNTIME_DIR").map(|runtime_dir| PathBuf::from(runtime_dir).join(path))
+}
+
+/// Receives whatever is available on `stream`, appending the data to `data` and the fds to `fds`. Returns `false` if
+/// the other side hung up.
+pub fn recv(stream: &UnixStream, data: &mut Vec<u8>, fds: &mut VecDeque<RawFd>) -> nix::Result<bool> {
+	let mut buf = [0u8; 4096];
+	let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_FDS]);
+	let flags = socket::MsgFlags::MSG_CMSG_CLOEXEC;
+	loop {
+		match socket::recvmsg(stream.as_raw_fd(), &[IoVec::from_mut_slice(&mut buf)], Some(&mut cmsg_buf), flags) {
+			Ok(recv) => {
+				let mut received_fds = false;
+				for cmsg in recv.cmsgs() {
+					if let socket::ControlMessageOwned::ScmRights(received) = cmsg {
+						fds.extend(received);
+						received_fds = true;
+					}
+				}
+				data.extend_from_slice(&buf[..recv.bytes]);
+				return Ok(recv.bytes > 0 || received_fds);
+			},
+			Err(nix::Error::Sys(Errno::EINTR)) => continue,
+			Err(nix::Error::Sys(Errno::ECONNRESET)) => return Ok(false),
+			Err(e) => return Err(e),
+		}
+	}
+}
+
+/// Data and fds received from one side of a connection, taken off the front a message at a time once they're whole.
+#[derive(Debug, Default)]
+pub struct MessageBuffer {
+	data: Vec<u8>,
+	fds: VecDeque<RawFd>,
+}
+
+impl MessageBuffer {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Receives whatever is available on `stream`. Returns `false` if the other side hung up.
+	pub fn recv(&mut self, stream: &UnixStream) -> nix::Result<bool> {
+		recv(stream, &mut self.data, &mut self.fds)
+	}
+
+	/// Appends data and fds that were received some other way.
+	pub fn extend<I: IntoIterator<Item=RawFd>>(&mut self, data: &[u8], fds: I) {
+		self.data.extend_from_slice(data);
+		self.fds.extend(fds);
+	}
+
+	/// The header of the next message, if all of the message has been received.
+	pub fn peek(&self) -> Option<MessageHeader> {
+		if self.data.len() < 8 {
+			return None;
+		}
+		let header = MessageHeader::from_bytes(&self.data[..8]).unwrap();
+		if self.data.len() < (header.msg_size as usize).max(8) {
+			return None;
+		}
+		Some(header)
+	}
+
+	/// Takes the next message if it's whole, along with the next `fd_count` fds. The fd count has to come from the
+	/// message's description, since fds aren't delimited on the wire. Fds that haven't arrived are left out.
+	pub fn take(&mut self, fd_count: usize) -> Option<RawMessage> {
+		let header = self.peek()?;
+		let size = (header.msg_size as usize).max(8);
+		Some(RawMessage {
+			header,
+			data: self.data.drain(..size).skip(8).collect(),
+			fds: (0..fd_count).filter_map(|_| self.fds.pop_front()).collect(),
+		})
+	}
+}
+
+/// Sends all of `data`, with `fds` attached to the first chunk.
+pub fn send(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> nix::Result<()> {
+	let cmsgs = [socket::ControlMessage::ScmRights(fds)];
+	let mut sent = 0;
+	while sent < data.len() {
+		let cmsgs: &[_] = if sent == 0 { &cmsgs } else { &[] };
+		match socket::sendmsg(stream.as_raw_fd(), &[IoVec::from_slice(&data[sent..])], cmsgs, socket::MsgFlags::empty(), None) {
+			Ok(bytes) => sent += bytes,
+			Err(nix::Error::Sys(Errno::EINTR)) => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(())
+}
+
+/// Sends a whole message along with its fds.
+pub fn send_message(stream: &UnixStream, raw: &RawMessage) -> nix::Result<()> {
+	let mut data = Vec::with_capacity(raw.header.msg_size as usize);
+	data.extend_from_slice(&raw.header.to_bytes());
+	data.extend_from_slice(&raw.data);
+	send(stream, &data, &raw.fds)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn messages_are_taken_once_whole() {
+		let header = MessageHeader {
+			sender: 3,
+			opcode: 1,
+			msg_size: 12,
+		};
+		let mut buffer = MessageBuffer::new();
+		buffer.extend(&header.to_bytes(), vec![5, 6]);
+		assert!(buffer.peek().is_none());
+		assert!(buffer.take(1).is_none());
+
+		buffer.extend(&[1, 0, 0, 0, 2, 0], None);
+		assert_eq!(buffer.peek(), Some(header));
+		let raw = buffer.take(1).unwrap();
+		assert_eq!(raw.header, header);
+		assert_eq!(raw.data, vec![1, 0, 0, 0]);
+		assert_eq!(raw.fds, vec![5]);
+		assert!(buffer.peek().is_none());
+		assert_eq!(buffer.data, vec![2, 0]);
+		assert_eq!(buffer.fds, vec![6]);
+	}
+}
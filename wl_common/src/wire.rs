@@ -48,6 +48,15 @@ impl MessageHeader {
 			msg_size,
 		})
 	}
+
+	pub fn to_bytes(&self) -> [u8; 8] {
+		let mut bytes = [0u8; 8];
+		let mut cursor = std::io::Cursor::new(&mut bytes[..]);
+		cursor.write_u32::<NativeEndian>(self.sender).unwrap();
+		cursor.write_u16::<NativeEndian>(self.opcode).unwrap();
+		cursor.write_u16::<NativeEndian>(self.msg_size).unwrap();
+		bytes
+	}
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -136,7 +145,12 @@ impl<'a, 'b> RawMessageReader<'a, 'b> {
 	}
 
 	pub fn next_new_id_anonymous(&mut self) -> Result<(u32, InterfaceTitle), ParseDynError> {
-		let name = String::from_utf8(self.next_string()?.unwrap()).unwrap(); // TODO treat non-utf8 properly, i.e., use CString instead
+		let mut name = self.next_string()?.ok_or(ParseDynError::NullInterfaceName)?;
+		// The name is sent with its nul terminator, which isn't part of the interface name
+		if name.last() == Some(&0) {
+			name.pop();
+		}
+		let name = String::from_utf8(name).map_err(|_| ParseDynError::InvalidInterfaceName)?;
 		let version = self.next_uint()?;
 		let id = self.next_uint()?;
 		Ok((id, InterfaceTitle::new(name, version)))
@@ -183,7 +197,7 @@ impl DynMessage {
 		}
 	}
 
-	pub fn from_raw(args_desc: &[ArgumentDesc], reader: RawMessageReader) -> Result<Self, ParseDynError> {
+	pub fn from_raw<A: ArgumentSignature>(args_desc: &[A], reader: RawMessageReader) -> Result<Self, ParseDynError> {
 		Ok(Self {
 			sender: reader.header.sender,
 			opcode: reader.header.opcode,
@@ -242,6 +256,7 @@ impl DynMessage {
 					if let Some(interface) = interface {
 						let c_name = std::ffi::CString::new(interface.name.as_bytes()).unwrap();
 						write_array(&mut buf, c_name.as_bytes_with_nul())?;
+						buf.write_u32::<NativeEndian>(interface.version).unwrap();
 					}
 					buf.write_u32::<NativeEndian>(v).unwrap();
 				}
@@ -252,10 +267,10 @@ impl DynMessage {
 		Ok((buf, fds))
 	}
 
-	pub fn parse_dyn_args(args_desc: &[ArgumentDesc], mut reader: RawMessageReader) -> Result<Vec<DynArgument>, ParseDynError> {
+	pub fn parse_dyn_args<A: ArgumentSignature>(args_desc: &[A], mut reader: RawMessageReader) -> Result<Vec<DynArgument>, ParseDynError> {
 		let mut args = Vec::new();
 		for arg_desc in args_desc {
-			match arg_desc.arg_type {
+			match arg_desc.arg_type() {
 			    ArgumentType::Int => args.push(DynArgument::Int(reader.next_int()?)),
 			    ArgumentType::Uint => args.push(DynArgument::Uint(reader.next_uint()?)),
 			    ArgumentType::Fixed => args.push(DynArgument::Fixed(reader.next_fixed()?)),
@@ -265,7 +280,7 @@ impl DynMessage {
 					args.push(DynArgument::Object(next_object))
 				},
 			    ArgumentType::NewId => {
-					if arg_desc.interface.is_some() {
+					if arg_desc.interface().is_some() {
 						let id = reader.next_new_id()?;
 						args.push(DynArgument::NewId(id, None));
 					} else {
@@ -289,6 +304,10 @@ pub enum ParseDynError {
 	InsufficientFds,
 	#[error("The message referenced an object id that does not exist")]
 	ObjectDoesntExist,
+	#[error("The interface of a new object was null")]
+	NullInterfaceName,
+	#[error("The interface of a new object was not valid UTF-8")]
+	InvalidInterfaceName,
 }
 
 #[derive(Debug, Error)]
@@ -309,7 +328,7 @@ pub enum ArgumentError {
 	IncorrectArguments,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum DynArgument {
 	Int(i32),
 	Uint(u32),
@@ -423,7 +442,93 @@ impl ArgumentType {
 }
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ArgumentDesc {
+	pub name: &'static str,
 	pub arg_type: ArgumentType,
 	pub interface: Option<&'static str>,
 	pub allow_null: bool,
 }
+
+/// A description of a message argument, implemented by the static `ArgumentDesc` and the runtime
+/// `registry::OwnedArgumentDesc` so that messages can be parsed with either.
+pub trait ArgumentSignature {
+	fn name(&self) -> &str;
+	fn arg_type(&self) -> ArgumentType;
+	/// The interface of an object or new_id argument, or `None` if it can be any interface.
+	fn interface(&self) -> Option<&str>;
+}
+
+impl ArgumentSignature for ArgumentDesc {
+	fn name(&self) -> &str {
+		self.name
+	}
+
+	fn arg_type(&self) -> ArgumentType {
+		self.arg_type
+	}
+
+	fn interface(&self) -> Option<&str> {
+		self.interface
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn untyped_new_ids_are_read_without_their_nul() {
+		// wl_registry.bind(1, "wl_compositor", 4, 3) as libwayland sends it
+		let mut data = Vec::new();
+		data.write_u32::<NativeEndian>(1).unwrap();
+		data.write_u32::<NativeEndian>(14).unwrap();
+		data.extend_from_slice(b"wl_compositor\0\0\0");
+		data.write_u32::<NativeEndian>(4).unwrap();
+		data.write_u32::<NativeEndian>(3).unwrap();
+		let raw = RawMessage::from_data_without_header(MessageHeader { sender: 2, opcode: 0, msg_size: 40 }, data, Vec::new());
+
+		let mut reader = RawMessageReader::new(&raw);
+		assert_eq!(reader.next_uint().unwrap(), 1);
+		let (id, title) = reader.next_new_id_anonymous().unwrap();
+		assert_eq!(id, 3);
+		assert_eq!(title, InterfaceTitle::new("wl_compositor", 4));
+	}
+
+	#[test]
+	fn untyped_new_ids_need_a_valid_interface_name() {
+		// wl_registry.bind(1, NULL, 1, 3)
+		let mut data = Vec::new();
+		data.write_u32::<NativeEndian>(1).unwrap();
+		data.write_u32::<NativeEndian>(0).unwrap();
+		data.write_u32::<NativeEndian>(1).unwrap();
+		data.write_u32::<NativeEndian>(3).unwrap();
+		let raw = RawMessage::from_data_without_header(MessageHeader { sender: 2, opcode: 0, msg_size: 24 }, data, Vec::new());
+		let mut reader = RawMessageReader::new(&raw);
+		reader.next_uint().unwrap();
+		assert!(matches!(reader.next_new_id_anonymous(), Err(ParseDynError::NullInterfaceName)));
+
+		// wl_registry.bind(1, "\xff", 1, 3)
+		let mut data = Vec::new();
+		data.write_u32::<NativeEndian>(1).unwrap();
+		data.write_u32::<NativeEndian>(2).unwrap();
+		data.extend_from_slice(&[0xff, 0, 0, 0]);
+		data.write_u32::<NativeEndian>(1).unwrap();
+		data.write_u32::<NativeEndian>(3).unwrap();
+		let raw = RawMessage::from_data_without_header(MessageHeader { sender: 2, opcode: 0, msg_size: 28 }, data, Vec::new());
+		let mut reader = RawMessageReader::new(&raw);
+		reader.next_uint().unwrap();
+		assert!(matches!(reader.next_new_id_anonymous(), Err(ParseDynError::InvalidInterfaceName)));
+	}
+
+	#[test]
+	fn untyped_new_ids_round_trip() {
+		let message = DynMessage::new(2, 0, vec![DynArgument::Uint(1), DynArgument::NewId(3, Some(InterfaceTitle::new("wl_compositor", 4)))]);
+		let raw = message.into_raw().unwrap();
+		assert_eq!(raw.header.msg_size, 40);
+
+		let mut reader = RawMessageReader::new(&raw);
+		assert_eq!(reader.next_uint().unwrap(), 1);
+		let (id, title) = reader.next_new_id_anonymous().unwrap();
+		assert_eq!(id, 3);
+		assert_eq!(title, InterfaceTitle::new("wl_compositor", 4));
+	}
+}
@@ -0,0 +1,131 @@
+use std::{
+	os::unix::{net::{UnixStream}},
+	path::{Path},
+};
+
+use nix::{
+	unistd,
+};
+
+use wl_common::{
+	wire::{RawMessageReader, DynMessage, DynArgument, ArgumentType},
+	interface::{Interface, DynInterface, Message},
+	socket::{MessageBuffer, send_message},
+};
+use wl_server::{
+	client::{ClientMap},
+	resource::{NewResource, Untyped},
+	shadow::{ShadowObjects},
+	protocol::{
+		wl_display::{self, WlDisplay, WlDisplayRequest, WlDisplayEvent},
+		wl_callback::{WlCallback},
+	},
+};
+
+use crate::{
+	InfoError,
+};
+
+/// An event received from the compositor.
+#[derive(Debug, Clone)]
+pub struct Event {
+	pub sender: u32,
+	pub opcode: u16,
+	pub args: Vec<DynArgument>,
+}
+
+/// A blocking client connection. Requests and events are the types generated for the server, which are the same on
+/// both sides of the wire.
+pub struct Connection {
+	stream: UnixStream,
+	objects: ShadowObjects,
+	buffer: MessageBuffer,
+}
+
+impl Connection {
+	pub fn connect(path: &Path) -> Result<Self, InfoError> {
+		let stream = UnixStream::connect(path).map_err(|e| InfoError::Connect(path.to_owned(), e))?;
+
+		Ok(Self {
+			stream,
+			objects: ShadowObjects::new(),
+			buffer: MessageBuffer::new(),
+		})
+	}
+
+	/// Allocates an id for a new object with the interface `I`, returning it along with the id.
+	pub fn new_id<I: Interface>(&mut self) -> (NewResource<I>, u32) {
+		let new_resource = self.objects.new_id();
+		let id = self.objects.id(&new_resource).unwrap();
+		(new_resource, id)
+	}
+
+	/// Allocates an id for binding `version` of `interface`, returning it along with the id.
+	pub fn new_id_untyped(&mut self, interface: DynInterface, version: u32) -> (NewResource<Untyped>, u32) {
+		let new_resource = self.objects.new_id_untyped(interface, version);
+		let id = self.objects.id(&new_resource).unwrap();
+		(new_resource, id)
+	}
+
+	/// Sends `request` to `object`.
+	pub fn send<R: Message<ClientMap=ClientMap>>(&mut self, object: u32, request: R) -> Result<(), InfoError> {
+		let (opcode, args) = self.objects.request_args(&request)?;
+		let raw = DynMessage::new(object, opcode, args).into_raw()?;
+		send_message(&self.stream, &raw).map_err(InfoError::Send)
+	}
+
+	/// Converts an event sent by an object with the interface `I`.
+	pub fn event<I: Interface>(&self, event: Event) -> Result<I::Event, InfoError> where I::Event: Message<ClientMap=ClientMap> {
+		Ok(self.objects.event::<I>(event.opcode, event.args)?)
+	}
+
+	/// Sends a `wl_display.sync` and returns every event received before its callback is done.
+	pub fn roundtrip(&mut self) -> Result<Vec<Event>, InfoError> {
+		let (callback, callback_id) = self.new_id::<WlCallback>();
+		self.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback }))?;
+
+		let mut events = Vec::new();
+		loop {
+			let event = self.next_event()?;
+			if event.sender == callback_id {
+				return Ok(events);
+			}
+			if event.sender == 1 {
+				match self.event::<WlDisplay>(event)? {
+					WlDisplayEvent::Error(error) => {
+						let object = self.objects.object_id(&error.object_id).unwrap_or(0);
+						let message = String::from_utf8_lossy(error.message.strip_suffix(&[0]).unwrap_or(&error.message)).into_owned();
+						return Err(InfoError::Protocol(object, error.code, message));
+					},
+					WlDisplayEvent::DeleteId(delete_id) => self.objects.remove(delete_id.id),
+				}
+				continue;
+			}
+			events.push(event);
+		}
+	}
+
+	fn next_event(&mut self) -> Result<Event, InfoError> {
+		loop {
+			if let Some(header) = self.buffer.peek() {
+				let interface = self.objects.interface(header.sender).ok_or(InfoError::UnknownObject(header.sender))?;
+				let desc = interface.event(header.opcode).ok_or(InfoError::UnknownEvent(interface.name, header.opcode))?;
+				let fd_count = desc.args.iter().filter(|arg| arg.arg_type == ArgumentType::Fd).count();
+				let raw = self.buffer.take(fd_count).unwrap();
+				let args = DynMessage::parse_dyn_args(desc.args, RawMessageReader::new(&raw))?;
+				// Nothing here needs the fds the compositor sends, like the keymap
+				for &fd in &raw.fds {
+					let _ = unistd::close(fd);
+				}
+				return Ok(Event {
+					sender: header.sender,
+					opcode: header.opcode,
+					args,
+				});
+			}
+			if !self.buffer.recv(&self.stream).map_err(InfoError::Recv)? {
+				return Err(InfoError::Disconnected);
+			}
+		}
+	}
+}
@@ -0,0 +1,210 @@
+//! Lists the globals of a running compositor, like `wayland-info`.
+//!
+//! ```text
+//! wl-info [--socket NAME]
+//! ```
+//!
+//! `wl_output`, `wl_seat` and `wl_shm` globals are bound and what they report is printed under them. Messages are
+//! sent and decoded as the request and event types generated for `wl_server` and `wl_protocols`, so this also checks
+//! those against a real compositor. Globals that the generated code has an older version of are marked.
+
+use std::{
+	path::{PathBuf},
+	io,
+	env,
+};
+
+use thiserror::{Error};
+
+use wl_common::{
+	wire::{DynArgument, SerializeRawError, ParseDynError},
+	interface::{Interface, DynInterface, IntoArgsError, FromArgsError},
+	socket::{display_name, socket_path},
+};
+use wl_server::{
+	protocol::*,
+};
+use wl_protocols::{
+	xdg_shell::{XdgWmBase},
+};
+
+mod connection;
+
+use crate::{
+	connection::{Connection, Event},
+};
+
+/// A global advertised by the compositor.
+#[derive(Debug, Clone)]
+struct Global {
+	name: u32,
+	interface: String,
+	version: u32,
+	/// The id it was bound to, if it's one of the globals that get inspected.
+	bound: Option<u32>,
+}
+
+/// The interfaces the workspace has generated code for that can be advertised as globals.
+fn generated_globals() -> Vec<DynInterface> {
+	vec![
+		WlCompositor::as_dyn(),
+		WlSubcompositor::as_dyn(),
+		WlDataDeviceManager::as_dyn(),
+		WlShell::as_dyn(),
+		WlShm::as_dyn(),
+		WlSeat::as_dyn(),
+		WlOutput::as_dyn(),
+		XdgWmBase::as_dyn(),
+	]
+}
+
+fn run(socket: Option<String>) -> Result<(), InfoError> {
+	let path = socket_path(&display_name(socket)).ok_or(InfoError::NoRuntimeDir)?;
+	let mut connection = Connection::connect(&path)?;
+
+	let (registry, registry_id) = connection.new_id::<WlRegistry>();
+	connection.send(1, WlDisplayRequest::GetRegistry(wl_display::GetRegistryRequest { registry }))?;
+	let mut globals = Vec::new();
+	for event in connection.roundtrip()? {
+		if event.sender == registry_id {
+			if let WlRegistryEvent::Global(global) = connection.event::<WlRegistry>(event)? {
+				globals.push(Global {
+					name: global.name,
+					interface: string(&global.interface),
+					version: global.version,
+					bound: None,
+				});
+			}
+		}
+	}
+
+	// Everything that gets inspected is bound at once, and sends what it has to say before the next roundtrip is done
+	let inspected = [WlOutput::as_dyn(), WlSeat::as_dyn(), WlShm::as_dyn()];
+	for global in &mut globals {
+		if let Some(interface) = inspected.iter().find(|interface| interface.name == global.interface) {
+			let version = global.version.min(interface.version);
+			let (id, bound) = connection.new_id_untyped(*interface, version);
+			connection.send(registry_id, WlRegistryRequest::Bind(wl_registry::BindRequest { name: global.name, id }))?;
+			global.bound = Some(bound);
+		}
+	}
+	let events = connection.roundtrip()?;
+
+	let generated = generated_globals();
+	for global in &globals {
+		print!("interface: '{}', version: {}, name: {}", global.interface, global.version, global.name);
+		match generated.iter().find(|interface| interface.name == global.interface) {
+			Some(interface) if interface.version < global.version => println!(" (generated code has version {})", interface.version),
+			_ => println!(),
+		}
+
+		if let Some(id) = global.bound {
+			let events = events.iter().filter(|event| event.sender == id).cloned().collect::<Vec<_>>();
+			match global.interface.as_str() {
+				"wl_output" => print_output(&connection, events)?,
+				"wl_seat" => print_seat(&connection, events)?,
+				"wl_shm" => print_shm(&connection, events)?,
+				_ => {},
+			}
+		}
+	}
+
+	Ok(())
+}
+
+fn print_output(connection: &Connection, events: Vec<Event>) -> Result<(), InfoError> {
+	for event in events {
+		match connection.event::<WlOutput>(event)? {
+			WlOutputEvent::Geometry(geometry) => {
+				println!("\tx: {}, y: {},", geometry.x, geometry.y);
+				println!("\tphysical_width: {} mm, physical_height: {} mm,", geometry.physical_width, geometry.physical_height);
+				println!("\tmake: '{}', model: '{}',", string(&geometry.make), string(&geometry.model));
+				println!("\tsubpixel_orientation: {:?}, output_transform: {:?},", geometry.subpixel, geometry.transform);
+			},
+			WlOutputEvent::Mode(mode) => {
+				println!("\tmode:");
+				println!("\t\twidth: {} px, height: {} px, refresh: {:.3} Hz,", mode.width, mode.height, mode.refresh as f64 / 1000.0);
+				println!("\t\tflags: {:?}", mode.flags);
+			},
+			WlOutputEvent::Scale(scale) => println!("\tscale: {}", scale.factor),
+			WlOutputEvent::Done => {},
+		}
+	}
+	Ok(())
+}
+
+fn print_seat(connection: &Connection, events: Vec<Event>) -> Result<(), InfoError> {
+	for event in events {
+		match connection.event::<WlSeat>(event)? {
+			WlSeatEvent::Name(name) => println!("\tname: {}", string(&name.name)),
+			WlSeatEvent::Capabilities(capabilities) => println!("\tcapabilities: {:?}", capabilities.capabilities),
+		}
+	}
+	Ok(())
+}
+
+fn print_shm(connection: &Connection, events: Vec<Event>) -> Result<(), InfoError> {
+	let mut formats = Vec::new();
+	for event in events {
+		let raw_format = match event.args.first() {
+			Some(&DynArgument::Uint(format)) => format,
+			_ => 0,
+		};
+		match connection.event::<WlShm>(event) {
+			Ok(WlShmEvent::Format(format)) => formats.push(format!("{:?}", format.format)),
+			// Formats the generated code doesn't know are shown as their fourcc code
+			Err(InfoError::Arguments(FromArgsError::InvalidEnumValue(_))) => formats.push(format!("0x{:08x}", raw_format)),
+			Err(e) => return Err(e),
+		}
+	}
+	println!("\tformats ({}): {}", formats.len(), formats.join(", "));
+	Ok(())
+}
+
+// Converts a string argument for printing, without its nul terminator
+fn string(string: &[u8]) -> String {
+	String::from_utf8_lossy(string.strip_suffix(&[0]).unwrap_or(string)).into_owned()
+}
+
+#[derive(Debug, Error)]
+pub enum InfoError {
+	#[error("{0}\nusage: wl-info [--socket NAME]")]
+	Usage(String),
+	#[error("XDG_RUNTIME_DIR is not set")]
+	NoRuntimeDir,
+	#[error("Failed to connect to {0}\n\t{1}")]
+	Connect(PathBuf, #[source] io::Error),
+	#[error("Failed to send message on socket\n\t{0}")]
+	Send(#[source] nix::Error),
+	#[error("Failed to read socket\n\t{0}")]
+	Recv(#[source] nix::Error),
+	#[error("The compositor closed the connection")]
+	Disconnected,
+	#[error("Failed to serialize request\n\t{0}")]
+	Serialize(#[from] SerializeRawError),
+	#[error("Failed to parse event\n\t{0}")]
+	Parse(#[from] ParseDynError),
+	#[error("Failed to convert request to arguments\n\t{0}")]
+	Request(#[from] IntoArgsError),
+	#[error("Got unexpected event arguments\n\t{0}")]
+	Arguments(#[from] FromArgsError),
+	#[error("Got a message for unknown object {0}")]
+	UnknownObject(u32),
+	#[error("Got unknown event {1} from a {0}")]
+	UnknownEvent(&'static str, u16),
+	#[error("The compositor sent error {1} on object {0}: {2}")]
+	Protocol(u32, u32, String),
+}
+
+fn main() {
+	let mut args = env::args().skip(1);
+	let result = match (args.next().as_deref(), args.next(), args.next()) {
+		(None, _, _) => run(None),
+		(Some("--socket"), Some(socket), None) => run(Some(socket)),
+		(Some(arg), _, _) => Err(InfoError::Usage(format!("unexpected argument {}", arg))),
+	};
+	if let Err(e) = result {
+		eprintln!("{}", e);
+		std::process::exit(1);
+	}
+}
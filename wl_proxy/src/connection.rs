@@ -0,0 +1,151 @@
+use std::{
+	os::unix::{net::{UnixStream}},
+	collections::{HashMap, VecDeque},
+};
+
+use nix::{
+	unistd,
+};
+
+use wl_common::{
+	wire::{RawMessage, RawMessageReader, DynMessage, DynArgument, ArgumentType},
+	interface::{MessageSide, InterfaceSignature},
+	registry::{InterfaceRegistry, OwnedInterface},
+	socket::{MessageBuffer, recv, send},
+};
+
+use crate::{
+	ProxyError,
+};
+
+/// A client connected to the proxy along with the proxy's own connection to the compositor.
+#[derive(Debug)]
+pub struct Connection {
+	pub id: u32,
+	pub client: UnixStream,
+	pub compositor: UnixStream,
+	/// The interface name of every object the proxy knows about.
+	objects: HashMap<u32, String>,
+	requests: MessageBuffer,
+	events: MessageBuffer,
+}
+
+/// A message that was forwarded in either direction, decoded as far as the loaded protocols allow.
+#[derive(Debug)]
+pub struct Decoded {
+	pub side: MessageSide,
+	pub raw: RawMessage,
+	/// The interface name of the sender and the parsed message, if the sender and message are known.
+	pub message: Option<(String, DynMessage)>,
+}
+
+impl Connection {
+	pub fn new(id: u32, client: UnixStream, compositor: UnixStream) -> Self {
+		let mut objects = HashMap::new();
+		objects.insert(1, String::from("wl_display"));
+
+		Self {
+			id,
+			client,
+			compositor,
+			objects,
+			requests: MessageBuffer::new(),
+			events: MessageBuffer::new(),
+		}
+	}
+
+	/// Forwards whatever the given side has sent to the other side, returning the messages that are now complete, or
+	/// `None` if the side hung up.
+	pub fn forward(&mut self, side: MessageSide, protocols: &InterfaceRegistry) -> Result<Option<Vec<Decoded>>, ProxyError> {
+		let (from, to) = match side {
+			MessageSide::Request => (&self.client, &self.compositor),
+			MessageSide::Event => (&self.compositor, &self.client),
+		};
+
+		let mut data = Vec::new();
+		let mut fds = VecDeque::new();
+		if !recv(from, &mut data, &mut fds).map_err(ProxyError::Recv)? {
+			return Ok(None);
+		}
+		let result = send(to, &data, fds.make_contiguous());
+		// The fds now belong to the other side. Their numbers are still useful for printing.
+		for &fd in &fds {
+			let _ = unistd::close(fd);
+		}
+		result.map_err(ProxyError::Send)?;
+
+		let buffer = match side {
+			MessageSide::Request => &mut self.requests,
+			MessageSide::Event => &mut self.events,
+		};
+		buffer.extend(&data, fds);
+
+		let mut decoded = Vec::new();
+		while let Some(message) = self.decode_next(side, protocols) {
+			decoded.push(message);
+		}
+		Ok(Some(decoded))
+	}
+
+	pub fn object_interface(&self, id: u32) -> Option<&str> {
+		self.objects.get(&id).map(String::as_str)
+	}
+
+	fn decode_next(&mut self, side: MessageSide, protocols: &InterfaceRegistry) -> Option<Decoded> {
+		let buffer = match side {
+			MessageSide::Request => &mut self.requests,
+			MessageSide::Event => &mut self.events,
+		};
+		let header = buffer.peek()?;
+		let interface = self.objects.get(&header.sender).and_then(|name| protocols.get(name));
+		let desc = interface.and_then(|interface| interface.message(side, header.opcode));
+		let fd_count = desc.map(|desc| desc.args.iter().filter(|arg| arg.arg_type == ArgumentType::Fd).count()).unwrap_or(0);
+		let raw = buffer.take(fd_count)?;
+
+		let message = match (interface, desc) {
+			(Some(interface), Some(desc)) => DynMessage::from_raw(&desc.args, RawMessageReader::new(&raw))
+				.ok()
+				.map(|message| (interface, message)),
+			_ => None,
+		};
+		if let Some((interface, ref message)) = message {
+			track_objects(&mut self.objects, interface, side, message, protocols);
+		}
+		let message = message.map(|(interface, message)| (interface.name.clone(), message));
+
+		Some(Decoded {
+			side,
+			raw,
+			message,
+		})
+	}
+}
+
+/// Keeps the map of object ids to interfaces up to date, so later messages can be decoded.
+fn track_objects(objects: &mut HashMap<u32, String>, interface: &OwnedInterface, side: MessageSide, message: &DynMessage, protocols: &InterfaceRegistry) {
+	let desc = match interface.message(side, message.opcode) {
+		Some(desc) => desc,
+		None => return,
+	};
+
+	if interface.name == "wl_display" && side == MessageSide::Event && desc.name == "delete_id" {
+		if let Some(DynArgument::Uint(id)) = message.arguments.first() {
+			objects.remove(id);
+		}
+		return;
+	}
+
+	for (arg_desc, arg) in desc.args.iter().zip(&message.arguments) {
+		if let DynArgument::NewId(id, ref title) = *arg {
+			let name = arg_desc.interface.as_deref().or_else(|| title.as_ref().map(|title| title.name.as_ref()));
+			match name.filter(|name| protocols.contains(name)) {
+				Some(name) => {
+					objects.insert(id, name.to_owned());
+				},
+				None => {
+					objects.remove(&id);
+				},
+			}
+		}
+	}
+}
@@ -0,0 +1,259 @@
+//! A proxy that sits between wayland clients and a compositor and prints every message going through it.
+//!
+//! ```text
+//! wl-proxy [--socket NAME] [--compositor NAME] [--protocol FILE]... [--interface NAME]... [--client ID]... [--record FILE]
+//! ```
+//!
+//! Clients connect to `--socket` (`wayland-proxy` by default) and are forwarded to `--compositor` (`$WAYLAND_DISPLAY`
+//! or `wayland-0` by default). Names that aren't absolute paths are relative to `$XDG_RUNTIME_DIR`. The core protocol
+//! is always loaded, and extensions can be added with `--protocol`. Only messages from the given interfaces or
+//! clients are printed if any are given, but everything is recorded.
+
+use std::{
+	os::unix::{net::{UnixListener, UnixStream}, io::{AsRawFd}},
+	path::{PathBuf},
+	fs::{self, File},
+	io::{self, BufWriter},
+	time::{SystemTime},
+	env,
+};
+
+use nix::{
+	poll,
+	errno::Errno,
+};
+use thiserror::{Error};
+
+use wl_common::{
+	interface::{MessageSide},
+	registry::{InterfaceRegistry},
+	capture::{CaptureWriter, CaptureRecord, CaptureError},
+	debug::{DebugMessage},
+	socket::{display_name, socket_path},
+};
+use wl_scanner::{
+	scanner::{LoadProtocolError, load_protocols},
+};
+
+mod connection;
+
+use crate::{
+	connection::{Connection, Decoded},
+};
+
+#[derive(Debug, Default)]
+struct Options {
+	socket: Option<String>,
+	compositor: Option<String>,
+	protocols: Vec<PathBuf>,
+	interfaces: Vec<String>,
+	clients: Vec<u32>,
+	record: Option<PathBuf>,
+}
+
+impl Options {
+	fn parse<I: Iterator<Item=String>>(mut args: I) -> Result<Self, ProxyError> {
+		let mut options = Options::default();
+		while let Some(arg) = args.next() {
+			let mut value = || args.next().ok_or_else(|| ProxyError::Usage(format!("missing value for {}", arg)));
+			match arg.as_str() {
+				"--socket" => options.socket = Some(value()?),
+				"--compositor" => options.compositor = Some(value()?),
+				"--protocol" => options.protocols.push(value()?.into()),
+				"--interface" => options.interfaces.push(value()?),
+				"--client" => {
+					let client = value()?;
+					options.clients.push(client.parse().map_err(|_| ProxyError::Usage(format!("invalid client id {}", client)))?);
+				},
+				"--record" => options.record = Some(value()?.into()),
+				_ => return Err(ProxyError::Usage(format!("unknown argument {}", arg))),
+			}
+		}
+		Ok(options)
+	}
+
+	fn shows(&self, client: u32, interface: Option<&str>) -> bool {
+		(self.clients.is_empty() || self.clients.contains(&client))
+			&& (self.interfaces.is_empty() || interface.map(|interface| self.interfaces.iter().any(|i| i == interface)).unwrap_or(false))
+	}
+}
+
+struct Proxy {
+	options: Options,
+	protocols: InterfaceRegistry,
+	listener: UnixListener,
+	socket_path: PathBuf,
+	compositor_path: PathBuf,
+	connections: Vec<Connection>,
+	recorder: Option<CaptureWriter<BufWriter<File>>>,
+	next_id: u32,
+}
+
+impl Proxy {
+	fn new(options: Options) -> Result<Self, ProxyError> {
+		let protocols = load_protocols(&options.protocols)?;
+
+		let socket_path = runtime_path(options.socket.as_deref().unwrap_or("wayland-proxy"))?;
+		let compositor_path = runtime_path(&display_name(options.compositor.clone()))?;
+		let listener = UnixListener::bind(&socket_path).map_err(ProxyError::Bind)?;
+
+		let recorder = match options.record {
+			Some(ref path) => Some(CaptureWriter::new(BufWriter::new(File::create(path).map_err(CaptureError::from)?))?),
+			None => None,
+		};
+
+		Ok(Self {
+			options,
+			protocols,
+			listener,
+			socket_path,
+			compositor_path,
+			connections: Vec::new(),
+			recorder,
+			next_id: 1,
+		})
+	}
+
+	fn run(&mut self) -> Result<(), ProxyError> {
+		eprintln!("Listening on {}, forwarding to {}", self.socket_path.display(), self.compositor_path.display());
+		loop {
+			let mut pollfds = vec![poll::PollFd::new(self.listener.as_raw_fd(), poll::PollFlags::POLLIN)];
+			for connection in &self.connections {
+				pollfds.push(poll::PollFd::new(connection.client.as_raw_fd(), poll::PollFlags::POLLIN));
+				pollfds.push(poll::PollFd::new(connection.compositor.as_raw_fd(), poll::PollFlags::POLLIN));
+			}
+			match poll::poll(&mut pollfds, -1) {
+				Ok(_) => {},
+				Err(nix::Error::Sys(Errno::EINTR)) => continue,
+				Err(e) => return Err(ProxyError::Poll(e)),
+			}
+			let ready = |pollfd: &poll::PollFd| pollfd.revents().map(|revents| !revents.is_empty()).unwrap_or(false);
+
+			let mut hung_up = Vec::new();
+			for (i, connection) in self.connections.iter_mut().enumerate() {
+				for (side, pollfd) in [(MessageSide::Request, &pollfds[1 + i * 2]), (MessageSide::Event, &pollfds[2 + i * 2])].iter() {
+					if !ready(pollfd) {
+						continue;
+					}
+					match connection.forward(*side, &self.protocols) {
+						Ok(Some(decoded)) => {
+							for message in decoded {
+								show(&self.options, &self.protocols, connection, &message);
+								if let Some(ref mut recorder) = self.recorder {
+									record(recorder, connection.id, &message)?;
+								}
+							}
+						},
+						Ok(None) => {
+							hung_up.push(connection.id);
+							break;
+						},
+						Err(e) => {
+							eprintln!("Client {}: {}", connection.id, e);
+							hung_up.push(connection.id);
+							break;
+						},
+					}
+				}
+			}
+			for id in hung_up {
+				eprintln!("Client {} disconnected", id);
+				self.connections.retain(|connection| connection.id != id);
+			}
+
+			if ready(&pollfds[0]) {
+				self.accept()?;
+			}
+		}
+	}
+
+	fn accept(&mut self) -> Result<(), ProxyError> {
+		let (client, _addr) = self.listener.accept().map_err(ProxyError::Accept)?;
+		let compositor = match UnixStream::connect(&self.compositor_path) {
+			Ok(compositor) => compositor,
+			Err(e) => {
+				eprintln!("Failed to connect to {}: {}", self.compositor_path.display(), e);
+				return Ok(());
+			},
+		};
+		let id = self.next_id;
+		self.next_id += 1;
+		eprintln!("Client {} connected", id);
+		self.connections.push(Connection::new(id, client, compositor));
+		Ok(())
+	}
+}
+
+impl Drop for Proxy {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.socket_path);
+	}
+}
+
+fn show(options: &Options, protocols: &InterfaceRegistry, connection: &Connection, decoded: &Decoded) {
+	let interface = decoded.message.as_ref().map(|(interface, _)| interface.as_str());
+	if !options.shows(connection.id, interface) {
+		return;
+	}
+	let message = decoded.message.as_ref().and_then(|(interface, message)| protocols.get(interface).map(|interface| (interface, message)));
+	match message {
+		Some((interface, message)) => {
+			let debug = DebugMessage::new(interface, message, decoded.side)
+				.outgoing(decoded.side == MessageSide::Request)
+				.object_interface(|id| connection.object_interface(id));
+			println!("{{{}}} {}", connection.id, debug);
+		},
+		None => {
+			let arrow = if decoded.side == MessageSide::Request { " -> " } else { "" };
+			let header = decoded.raw.header;
+			println!("{{{}}} {}[unknown]@{}.opcode {} ({} bytes)", connection.id, arrow, header.sender, header.opcode, header.msg_size);
+		},
+	}
+}
+
+fn record(recorder: &mut CaptureWriter<BufWriter<File>>, client: u32, decoded: &Decoded) -> Result<(), ProxyError> {
+	recorder.write_record(&CaptureRecord {
+		timestamp: SystemTime::now(),
+		client,
+		direction: decoded.side,
+		header: decoded.raw.header,
+		payload: decoded.raw.data.clone(),
+		fds: vec![None; decoded.raw.fds.len()],
+	})?;
+	recorder.flush()?;
+	Ok(())
+}
+
+fn runtime_path(name: &str) -> Result<PathBuf, ProxyError> {
+	socket_path(name).ok_or(ProxyError::NoRuntimeDir)
+}
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+	#[error("{0}\nusage: wl-proxy [--socket NAME] [--compositor NAME] [--protocol FILE]... [--interface NAME]... [--client ID]... [--record FILE]")]
+	Usage(String),
+	#[error("XDG_RUNTIME_DIR is not set")]
+	NoRuntimeDir,
+	#[error(transparent)]
+	Protocol(#[from] LoadProtocolError),
+	#[error("Failed to bind socket\n\t{0}")]
+	Bind(#[source] io::Error),
+	#[error("Failed to accept connection from client\n\t{0}")]
+	Accept(#[source] io::Error),
+	#[error("Failed to poll connections\n\t{0}")]
+	Poll(#[source] nix::Error),
+	#[error("Failed to read socket\n\t{0}")]
+	Recv(#[source] nix::Error),
+	#[error("Failed to send message on socket\n\t{0}")]
+	Send(#[source] nix::Error),
+	#[error("Failed to record message\n\t{0}")]
+	Record(#[from] CaptureError),
+}
+
+fn main() {
+	let result = Options::parse(env::args().skip(1)).and_then(Proxy::new).and_then(|mut proxy| proxy.run());
+	if let Err(e) = result {
+		eprintln!("{}", e);
+		std::process::exit(1);
+	}
+}
@@ -134,16 +134,21 @@ fn generate_enum_definition(enum_desc: &EnumDesc) -> TokenStream {
 	}
 }
 
-fn generate_argument_type(argument: &ArgumentDesc) -> TokenStream {
+fn generate_argument_type(argument: &ArgumentDesc, side: MessageSide) -> TokenStream {
 	match argument.arg_type {
 	    ArgumentType::Int | ArgumentType::Uint => {
 			if let Some((ref ns, ref enum_type)) = argument.enum_type {
 				let enum_type = Ident::new(&snake_to_camel(enum_type), Span::call_site());
-				if let Some(ns) = ns {
+				let enum_type = if let Some(ns) = ns {
 					let ns = Ident::new(ns, Span::call_site());
 					quote!(super::#ns::#enum_type)
 				} else {
 					quote!(#enum_type)
+				};
+				// Requests come from clients, which can send values the protocol doesn't define
+				match side {
+					MessageSide::Request => quote!(WEnum<#enum_type>),
+					MessageSide::Event => enum_type,
 				}
 			} else if argument.arg_type == ArgumentType::Int {
 				quote!(i32)
@@ -192,7 +197,7 @@ fn generate_message_struct_definition(message: &MessageDesc, side: MessageSide)
 	let struct_name = format_ident!("{}{}", snake_to_camel(&message.name), side.as_str());
 	let struct_fields = message.arguments.iter().map(|argument| {
 		let argument_name = Ident::new(&argument.name, Span::call_site());
-		let argument_type = generate_argument_type(argument);
+		let argument_type = generate_argument_type(argument, side);
 		quote!(pub #argument_name: #argument_type)
 	});
 	quote! {
@@ -260,8 +265,8 @@ fn generate_interface_impl(interface: &InterfaceDesc) -> TokenStream {
 
 			const NAME: &'static str = #snake_name;
 			const VERSION: u32 = #version;
-			const REQUESTS: &'static [&'static [ArgumentDesc]] = #requests_array;
-			const EVENTS: &'static [&'static [ArgumentDesc]] = #events_array;
+			const REQUESTS: &'static [MessageDesc] = #requests_array;
+			const EVENTS: &'static [MessageDesc] = #events_array;
 
 			fn new() -> Self {
 				Self
@@ -305,7 +310,7 @@ fn generate_interface(interface: &InterfaceDesc) -> TokenStream {
 			use std::borrow::Cow;
 			use byteorder::{ByteOrder, NativeEndian, ReadBytesExt, WriteBytesExt};
 			use wl_common::{
-				interface::{Interface, InterfaceTitle, DynInterface, Message, InvalidEnumValue, FromArgsError, IntoArgsError},
+				interface::{Interface, InterfaceTitle, DynInterface, Message, MessageDesc, InvalidEnumValue, WEnum, FromArgsError, IntoArgsError},
 				wire::{ArgumentDesc, ArgumentType, DynArgument, DynArgumentReader, Fixed},
 			};
 
@@ -348,9 +353,11 @@ fn generate_wire_arg_desc(arg: &ArgumentDesc) -> TokenStream {
 		quote!(None)
 	};
 	let allow_null = arg.allow_null;
+	let name = &arg.name;
 
 	quote! {
 		ArgumentDesc {
+			name: #name,
 			arg_type: #arg_type,
 			interface: #interface,
 			allow_null: #allow_null,
@@ -359,22 +366,29 @@ fn generate_wire_arg_desc(arg: &ArgumentDesc) -> TokenStream {
 }
 
 fn generate_arg_arrays(interface: &InterfaceDesc, side: MessageSide) -> TokenStream {
-	let mut requests_iter = interface.requests.iter().map(|request| &request.message);
-	let mut events_iter = interface.events.iter().map(|event| &event.message);
-	let messages_iter: &mut dyn Iterator<Item=&MessageDesc> = match side {
+	let mut requests_iter = interface.requests.iter().map(|request| (&request.message, request.destructor));
+	let mut events_iter = interface.events.iter().map(|event| (&event.message, false));
+	let messages_iter: &mut dyn Iterator<Item=(&MessageDesc, bool)> = match side {
 		MessageSide::Request => &mut requests_iter,
 		MessageSide::Event => &mut events_iter,
 	};
-	let arg_arrays_iter = messages_iter.map(|message| {
+	let message_descs_iter = messages_iter.map(|(message, destructor)| {
 		let arg_array_iter = message.arguments.iter().map(|argument| {
 			generate_wire_arg_desc(argument)
 		});
+		let name = &message.name;
+		let since = Literal::u32_unsuffixed(message.since.unwrap_or(1).try_into().unwrap());
 		quote! {
-			#(#arg_array_iter,)*
+			MessageDesc {
+				name: #name,
+				since: #since,
+				destructor: #destructor,
+				args: &[#(#arg_array_iter,)*],
+			}
 		}
 	});
 	quote! {
-		&[#(&[#arg_arrays_iter],)*]
+		&[#(#message_descs_iter,)*]
 	}
 }
 
@@ -470,9 +484,10 @@ fn generate_message_parser(interface: &InterfaceDesc, message: &MessageDesc, sid
 						let #val = client_map.add_new_id(#val);
 					}
 				} else {
+					let title = format_ident!("title{}", i);
 					quote! {
-						let #val = reader.next_new_id()?.0;
-						let #val = client_map.add_new_id_untyped(#val);
+						let (#val, #title) = reader.next_new_id()?;
+						let #val = client_map.add_new_id_untyped(#val, #title);
 					}
 				}
 			},
@@ -481,11 +496,21 @@ fn generate_message_parser(interface: &InterfaceDesc, message: &MessageDesc, sid
 		};
 		let enum_cast = if let Some((ref ns , ref enum_type)) = arg.enum_type {
 			let enum_type = Ident::new(&sanitize_enum_variant_name(&snake_to_camel(enum_type)), Span::call_site());
-			if let Some(ref ns) = ns {
+			let enum_type = if let Some(ref ns) = ns {
 				let ns = Ident::new(ns, Span::call_site());
-				quote!(let #val = super::#ns::#enum_type::try_from(#val)?;)
+				quote!(super::#ns::#enum_type)
 			} else {
-				quote!(let #val = #enum_type::try_from(#val)?;)
+				quote!(#enum_type)
+			};
+			let raw = if arg.arg_type == ArgumentType::Int { quote!(#val as u32) } else { quote!(#val) };
+			match side {
+				MessageSide::Request => quote! {
+					let #val = match #enum_type::try_from(#val) {
+						Ok(value) => WEnum::Value(value),
+						Err(_) => WEnum::Unknown(#raw),
+					};
+				},
+				MessageSide::Event => quote!(let #val = #enum_type::try_from(#val)?;),
 			}
 		} else {
 			quote!()
@@ -16,10 +16,7 @@ pub enum GenerationError {
 }
 
 pub fn generate_api(protocol: &str) -> Result<String, GenerationError> {
-	let mut reader = quick_xml::Reader::from_str(protocol);
-	reader.trim_text(true);
-	let mut buf = Vec::new();
-	let desc = scanner::parse_protocol(&mut reader, &mut buf)?;
+	let desc = scanner::parse_protocol_str(protocol)?;
 	let api = generator::generate_api(&desc);
 	Ok(api)
 }
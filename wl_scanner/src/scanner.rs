@@ -1,10 +1,18 @@
+use std::{
+	path::{PathBuf},
+	fs, io,
+};
+
 use quick_xml::{
 	Reader,
 	events::{Event},
 };
 use thiserror::Error;
 
-use wl_common::wire::ArgumentType;
+use wl_common::{
+	wire::ArgumentType,
+	registry::{InterfaceRegistry, OwnedInterface, OwnedMessageDesc, OwnedArgumentDesc},
+};
 
 #[derive(Debug)]
 pub struct ProtocolDesc {
@@ -73,6 +81,43 @@ pub struct EntryDesc {
 	pub summary: String,
 }
 
+impl From<&ProtocolDesc> for InterfaceRegistry {
+	fn from(protocol: &ProtocolDesc) -> Self {
+		protocol.interfaces.iter().map(OwnedInterface::from).collect()
+	}
+}
+
+impl From<&InterfaceDesc> for OwnedInterface {
+	fn from(interface: &InterfaceDesc) -> Self {
+		Self {
+			name: interface.name.clone(),
+			version: interface.version as u32,
+			requests: interface.requests.iter().map(|request| owned_message(&request.message, request.destructor)).collect(),
+			events: interface.events.iter().map(|event| owned_message(&event.message, false)).collect(),
+		}
+	}
+}
+
+fn owned_message(message: &MessageDesc, destructor: bool) -> OwnedMessageDesc {
+	OwnedMessageDesc {
+		name: message.name.clone(),
+		since: message.since.unwrap_or(1) as u32,
+		destructor,
+		args: message.arguments.iter().map(OwnedArgumentDesc::from).collect(),
+	}
+}
+
+impl From<&ArgumentDesc> for OwnedArgumentDesc {
+	fn from(arg: &ArgumentDesc) -> Self {
+		Self {
+			name: arg.name.clone(),
+			arg_type: arg.arg_type,
+			interface: arg.interface.clone(),
+			allow_null: arg.allow_null,
+		}
+	}
+}
+
 #[derive(Debug, Error)]
 pub enum ProtocolParseError {
 	#[error("Failed to parse protocol XML description")]
@@ -83,6 +128,38 @@ pub enum ProtocolParseError {
 	Utf8Error(#[from] std::str::Utf8Error),
 }
 
+/// Parses a whole protocol XML document.
+pub fn parse_protocol_str(protocol: &str) -> Result<ProtocolDesc, ProtocolParseError> {
+	let mut reader = Reader::from_str(protocol);
+	reader.trim_text(true);
+	let mut buf = Vec::new();
+	parse_protocol(&mut reader, &mut buf)
+}
+
+/// The core protocol, which every connection starts out speaking.
+pub static CORE_PROTOCOL: &str = include_str!("../wayland.xml");
+
+/// Loads the core protocol and the protocol XML files at `paths` into one registry. Interfaces in later files replace
+/// interfaces with the same name.
+pub fn load_protocols(paths: &[PathBuf]) -> Result<InterfaceRegistry, LoadProtocolError> {
+	let core = parse_protocol_str(CORE_PROTOCOL).map_err(|e| LoadProtocolError::Parse(PathBuf::from("wayland.xml"), e))?;
+	let mut registry = InterfaceRegistry::from(&core);
+	for path in paths {
+		let xml = fs::read_to_string(path).map_err(|e| LoadProtocolError::Read(path.clone(), e))?;
+		let protocol = parse_protocol_str(&xml).map_err(|e| LoadProtocolError::Parse(path.clone(), e))?;
+		registry.extend(InterfaceRegistry::from(&protocol));
+	}
+	Ok(registry)
+}
+
+#[derive(Debug, Error)]
+pub enum LoadProtocolError {
+	#[error("Failed to read protocol {0}\n\t{1}")]
+	Read(PathBuf, #[source] io::Error),
+	#[error("Failed to parse protocol {0}\n\t{1}")]
+	Parse(PathBuf, #[source] ProtocolParseError),
+}
+
 pub fn parse_protocol(reader: &mut Reader<&[u8]>, buf: &mut Vec<u8>) -> Result<ProtocolDesc, ProtocolParseError> {
 	let mut protocol = None;
 
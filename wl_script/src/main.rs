@@ -0,0 +1,438 @@
+//! Runs a scripted protocol conversation against a wayland server and reports where the server's events differ from
+//! what the script expects.
+//!
+//! ```text
+//! wl-script [--socket NAME] [--protocol FILE]... [--timeout MS] [--verbose] SCRIPT
+//! ```
+//!
+//! Scripts are written like `WAYLAND_DEBUG` output from the client's point of view. `->` lines are requests to send
+//! and `<-` lines are events that must arrive next, in order. `*` matches any argument of an event.
+//!
+//! ```text
+//! -> wl_display@1.get_registry(new id wl_registry@2)
+//! <- wl_registry@2.global(*, "wl_compositor", *)
+//! -> wl_registry@2.bind(1, "wl_compositor", 4, new id [unknown]@3)
+//! -> wl_display@1.sync(new id wl_callback@4)
+//! <- wl_callback@4.done(*)
+//! ```
+//!
+//! The socket is `$WAYLAND_DISPLAY` or `wayland-0` by default, relative to `$XDG_RUNTIME_DIR` unless it is an absolute
+//! path. The core protocol is always loaded, and extensions can be added with `--protocol`. Exits with 1 if any
+//! event didn't match.
+
+use std::{
+	os::unix::{net::{UnixStream}, io::{AsRawFd}},
+	collections::{HashMap},
+	path::{PathBuf},
+	fs::{self, File},
+	convert::{TryFrom},
+	io,
+	time::{Duration, Instant},
+	env,
+};
+
+use nix::{
+	errno::Errno,
+	poll,
+	unistd,
+};
+use thiserror::{Error};
+
+use wl_common::{
+	wire::{RawMessage, RawMessageReader, DynMessage, DynArgument, ArgumentType, Fixed, SerializeRawError},
+	interface::{MessageSide, InterfaceTitle, InterfaceSignature},
+	registry::{InterfaceRegistry, OwnedMessageDesc},
+	debug::{DebugMessage},
+	socket::{MessageBuffer, display_name, socket_path, send_message},
+};
+use wl_scanner::{
+	scanner::{LoadProtocolError, load_protocols},
+};
+
+mod script;
+
+use crate::{
+	script::{Line, Pattern, ParseError, parse_script},
+};
+
+#[derive(Debug, Default)]
+struct Options {
+	socket: Option<String>,
+	protocols: Vec<PathBuf>,
+	timeout: Option<u64>,
+	verbose: bool,
+	script: Option<PathBuf>,
+}
+
+impl Options {
+	fn parse<I: Iterator<Item=String>>(mut args: I) -> Result<Self, ScriptError> {
+		let mut options = Options::default();
+		while let Some(arg) = args.next() {
+			let mut value = || args.next().ok_or_else(|| ScriptError::Usage(format!("missing value for {}", arg)));
+			match arg.as_str() {
+				"--socket" => options.socket = Some(value()?),
+				"--protocol" => options.protocols.push(value()?.into()),
+				"--timeout" => {
+					let timeout = value()?;
+					options.timeout = Some(timeout.parse().map_err(|_| ScriptError::Usage(format!("invalid timeout {}", timeout)))?);
+				},
+				"--verbose" => options.verbose = true,
+				_ if arg.starts_with("--") => return Err(ScriptError::Usage(format!("unknown argument {}", arg))),
+				_ if options.script.is_none() => options.script = Some(arg.into()),
+				_ => return Err(ScriptError::Usage(format!("unexpected argument {}", arg))),
+			}
+		}
+		if options.script.is_none() {
+			return Err(ScriptError::Usage(String::from("missing script")));
+		}
+		Ok(options)
+	}
+}
+
+/// A connection to the server being tested, along with what's needed to decode its events.
+struct Runner {
+	protocols: InterfaceRegistry,
+	stream: UnixStream,
+	/// The interface name of every object the script created or received.
+	objects: HashMap<u32, String>,
+	buffer: MessageBuffer,
+	timeout: Duration,
+	verbose: bool,
+}
+
+impl Runner {
+	fn new(protocols: InterfaceRegistry, stream: UnixStream, timeout: Duration, verbose: bool) -> Self {
+		let mut objects = HashMap::new();
+		objects.insert(1, String::from("wl_display"));
+
+		Self {
+			protocols,
+			stream,
+			objects,
+			buffer: MessageBuffer::new(),
+			timeout,
+			verbose,
+		}
+	}
+
+	/// Runs every line of the script, returning the number of lines that didn't match.
+	fn run(&mut self, lines: &[Line]) -> Result<usize, ScriptError> {
+		let mut mismatches = 0;
+		for line in lines {
+			match line.side {
+				MessageSide::Request => self.send(line)?,
+				MessageSide::Event => if let Err(reason) = self.expect(line)? {
+					println!("line {}: expected {}\n\t{}", line.number, line, reason);
+					mismatches += 1;
+				},
+			}
+		}
+		Ok(mismatches)
+	}
+
+	fn send(&mut self, line: &Line) -> Result<(), ScriptError> {
+		let interface = self.protocols.get(&line.interface)
+			.ok_or_else(|| ScriptError::Line(line.number, format!("unknown interface {}", line.interface)))?;
+		let (opcode, desc) = interface.request_by_name(&line.message)
+			.ok_or_else(|| ScriptError::Line(line.number, format!("{} has no request {}", line.interface, line.message)))?;
+
+		// The files are kept open until the message is sent
+		let mut files = Vec::new();
+		let mut created = Vec::new();
+		let mut args = Vec::new();
+		let mut patterns = line.args.iter();
+		let mut next = |what: &str| patterns.next().ok_or_else(|| ScriptError::Line(line.number, format!("missing {} argument", what)));
+		for arg_desc in &desc.args {
+			let invalid = |pattern: &Pattern| ScriptError::Line(line.number, format!("invalid value for {}: {}", arg_desc.name, pattern));
+			let out_of_range = |pattern: &Pattern| ScriptError::Line(line.number, format!("{} is out of range for {}", pattern, arg_desc.name));
+			if arg_desc.arg_type == ArgumentType::NewId && arg_desc.interface.is_none() {
+				// Untyped new_ids are written as the interface name, the version and the new id
+				let interface = match next("interface")? {
+					Pattern::String(name) => String::from_utf8_lossy(name).into_owned(),
+					pattern => return Err(invalid(pattern)),
+				};
+				let version = match *next("version")? {
+					ref pattern @ Pattern::Int(version) => u32::try_from(version).map_err(|_| out_of_range(pattern))?,
+					ref pattern => return Err(invalid(pattern)),
+				};
+				let id = match next(&arg_desc.name)? {
+					Pattern::NewId(_, Some(id)) => *id,
+					pattern => return Err(invalid(pattern)),
+				};
+				created.push((id, interface.clone()));
+				args.push(DynArgument::NewId(id, Some(InterfaceTitle::new(interface, version))));
+				continue;
+			}
+
+			let pattern = next(&arg_desc.name)?;
+			let arg = match (arg_desc.arg_type, pattern) {
+				(ArgumentType::Int, &Pattern::Int(v)) => DynArgument::Int(i32::try_from(v).map_err(|_| out_of_range(pattern))?),
+				(ArgumentType::Uint, &Pattern::Int(v)) => DynArgument::Uint(u32::try_from(v).map_err(|_| out_of_range(pattern))?),
+				(ArgumentType::Fixed, &Pattern::Int(v)) => {
+					let fixed = i32::try_from(v).ok().and_then(|v| v.checked_mul(256)).ok_or_else(|| out_of_range(pattern))?;
+					DynArgument::Fixed(Fixed(fixed as u32))
+				},
+				(ArgumentType::Fixed, &Pattern::Fixed(v)) => {
+					let fixed = v * 256.0;
+					if !(i32::MIN as f64..=i32::MAX as f64).contains(&fixed) {
+						return Err(out_of_range(pattern));
+					}
+					DynArgument::Fixed(Fixed(fixed as i32 as u32))
+				},
+				(ArgumentType::String, Pattern::String(v)) => {
+					let mut v = v.clone();
+					v.push(0);
+					DynArgument::String(Some(v))
+				},
+				(ArgumentType::String, Pattern::Nil) => DynArgument::String(None),
+				(ArgumentType::Object, &Pattern::Object(id)) => DynArgument::Object(Some(id)),
+				(ArgumentType::Object, Pattern::Nil) => DynArgument::Object(None),
+				(ArgumentType::NewId, &Pattern::NewId(_, Some(id))) => {
+					created.push((id, arg_desc.interface.clone().unwrap()));
+					DynArgument::NewId(id, None)
+				},
+				(ArgumentType::Array, &Pattern::Array(len)) => DynArgument::Array(vec![0u8; len]),
+				(ArgumentType::Fd, Pattern::Fd) => {
+					let file = File::open("/dev/null").map_err(ScriptError::OpenFd)?;
+					args.push(DynArgument::Fd(file.as_raw_fd()));
+					files.push(file);
+					continue;
+				},
+				_ => return Err(invalid(pattern)),
+			};
+			args.push(arg);
+		}
+		if patterns.next().is_some() {
+			return Err(ScriptError::Line(line.number, String::from("too many arguments")));
+		}
+
+		let message = DynMessage::new(line.object, opcode, args);
+		if self.verbose {
+			let debug = DebugMessage::new(interface, &message, MessageSide::Request)
+				.without_timestamp()
+				.object_interface(|id| self.objects.get(&id).map(String::as_str));
+			println!("-> {}", debug);
+		}
+		let raw = message.into_raw()?;
+		send_message(&self.stream, &raw).map_err(ScriptError::Send)?;
+		drop(files);
+
+		for (id, interface) in created {
+			self.objects.insert(id, interface);
+		}
+		Ok(())
+	}
+
+	/// Waits for the next event and checks it against the line, returning why it didn't match if it didn't.
+	fn expect(&mut self, line: &Line) -> Result<Result<(), String>, ScriptError> {
+		let (raw, interface_name) = match self.next_event()? {
+			Some(event) => event,
+			None => return Ok(Err(format!("no event arrived within {}ms", self.timeout.as_millis()))),
+		};
+		// Scripts only ever match fds as `fd`, so they're closed before anything can return early
+		for &fd in &raw.fds {
+			let _ = unistd::close(fd);
+		}
+		let protocols = &self.protocols;
+		let interface = match interface_name.as_ref().and_then(|name| protocols.get(name)) {
+			Some(interface) => interface,
+			None => return Ok(Err(format!("got an event from unknown object {}", raw.header.sender))),
+		};
+		let desc = match interface.event(raw.header.opcode) {
+			Some(desc) => desc,
+			None => return Ok(Err(format!("got unknown event {} from {}@{}", raw.header.opcode, interface.name, raw.header.sender))),
+		};
+		let message = match DynMessage::from_raw(&desc.args, RawMessageReader::new(&raw)) {
+			Ok(message) => message,
+			Err(e) => return Ok(Err(format!("got a malformed {}.{} event: {}", interface.name, desc.name, e))),
+		};
+
+		let debug = DebugMessage::new(interface, &message, MessageSide::Event)
+			.without_timestamp()
+			.object_interface(|id| self.objects.get(&id).map(String::as_str))
+			.to_string();
+		if self.verbose {
+			println!("<- {}", debug);
+		}
+		let result = if interface.name != line.interface || raw.header.sender != line.object || desc.name != line.message {
+			Err(format!("got {}", debug))
+		} else {
+			match_args(&line.args, desc, &message.arguments).map_err(|reason| format!("got {}\n\t{}", debug, reason))
+		};
+		track_objects(&mut self.objects, &interface.name, desc, &message);
+		Ok(result)
+	}
+
+	// Reads until a whole event is buffered, returning it with the interface name of its sender
+	fn next_event(&mut self) -> Result<Option<(RawMessage, Option<String>)>, ScriptError> {
+		let deadline = Instant::now() + self.timeout;
+		loop {
+			if let Some(header) = self.buffer.peek() {
+				let interface = self.objects.get(&header.sender).cloned();
+				let desc = interface.as_ref()
+					.and_then(|name| self.protocols.get(name))
+					.and_then(|interface| interface.event(header.opcode));
+				let fd_count = desc.map(|desc| desc.args.iter().filter(|arg| arg.arg_type == ArgumentType::Fd).count()).unwrap_or(0);
+				let raw = self.buffer.take(fd_count).unwrap();
+				return Ok(Some((raw, interface)));
+			}
+
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			let mut pollfds = [poll::PollFd::new(self.stream.as_raw_fd(), poll::PollFlags::POLLIN)];
+			match poll::poll(&mut pollfds, remaining.as_millis() as i32) {
+				Ok(0) => return Ok(None),
+				Ok(_) => {},
+				Err(nix::Error::Sys(Errno::EINTR)) => continue,
+				Err(e) => return Err(ScriptError::Poll(e)),
+			}
+			if !self.buffer.recv(&self.stream).map_err(ScriptError::Recv)? {
+				return Err(ScriptError::Disconnected);
+			}
+		}
+	}
+}
+
+/// Checks event arguments against the patterns of a line. Untyped new_ids are matched against three patterns, like
+/// they are printed.
+fn match_args(patterns: &[Pattern], desc: &OwnedMessageDesc, args: &[DynArgument]) -> Result<(), String> {
+	let mut expanded = Vec::new();
+	for (arg_desc, arg) in desc.args.iter().zip(args) {
+		if let DynArgument::NewId(id, Some(ref title)) = *arg {
+			let mut name = title.name.as_bytes().to_vec();
+			name.push(0);
+			expanded.push((arg_desc, DynArgument::String(Some(name))));
+			expanded.push((arg_desc, DynArgument::Uint(title.version)));
+			expanded.push((arg_desc, DynArgument::NewId(id, None)));
+		} else {
+			expanded.push((arg_desc, arg.clone()));
+		}
+	}
+	if patterns.len() != expanded.len() {
+		return Err(format!("expected {} arguments, got {}", patterns.len(), expanded.len()));
+	}
+
+	for (i, (pattern, (arg_desc, arg))) in patterns.iter().zip(&expanded).enumerate() {
+		let matches = match (pattern, arg) {
+			(Pattern::Any, _) => true,
+			(Pattern::Nil, DynArgument::String(None)) | (Pattern::Nil, DynArgument::Object(None)) | (Pattern::Nil, DynArgument::NewId(0, None)) => true,
+			(&Pattern::Int(expected), &DynArgument::Int(v)) => expected == v as i64,
+			(&Pattern::Int(expected), &DynArgument::Uint(v)) => expected == v as i64,
+			(&Pattern::Int(expected), &DynArgument::Fixed(v)) => expected as f64 == fixed_to_f64(v),
+			(&Pattern::Fixed(expected), &DynArgument::Fixed(v)) => (expected - fixed_to_f64(v)).abs() < 1.0 / 256.0,
+			(Pattern::String(expected), DynArgument::String(Some(v))) => expected.as_slice() == v.strip_suffix(&[0]).unwrap_or(v),
+			(&Pattern::Object(expected), &DynArgument::Object(Some(id))) => expected == id,
+			(Pattern::NewId(name, expected), &DynArgument::NewId(id, None)) => {
+				let interface_matches = arg_desc.interface.as_ref().map(|interface| interface == name || name == "[unknown]").unwrap_or(true);
+				interface_matches && expected.map(|expected| expected == id).unwrap_or(true)
+			},
+			(&Pattern::Array(len), DynArgument::Array(v)) => len == v.len(),
+			(Pattern::Fd, DynArgument::Fd(_)) => true,
+			_ => false,
+		};
+		if !matches {
+			return Err(format!("argument {} ({}) doesn't match {}", i + 1, arg_desc.name, pattern));
+		}
+	}
+	Ok(())
+}
+
+fn fixed_to_f64(v: Fixed) -> f64 {
+	v.0 as i32 as f64 / 256.0
+}
+
+/// Keeps the map of object ids to interfaces up to date with the objects created and deleted by events.
+fn track_objects(objects: &mut HashMap<u32, String>, interface: &str, desc: &OwnedMessageDesc, message: &DynMessage) {
+	if interface == "wl_display" && desc.name == "delete_id" {
+		if let Some(&DynArgument::Uint(id)) = message.arguments.first() {
+			objects.remove(&id);
+		}
+		return;
+	}
+	for (arg_desc, arg) in desc.args.iter().zip(&message.arguments) {
+		if let (&DynArgument::NewId(id, _), Some(interface)) = (arg, &arg_desc.interface) {
+			objects.insert(id, interface.clone());
+		}
+	}
+}
+
+fn run(options: Options) -> Result<usize, ScriptError> {
+	let script_path = options.script.unwrap();
+	let script = fs::read_to_string(&script_path).map_err(|e| ScriptError::ReadScript(script_path.clone(), e))?;
+	let lines = parse_script(&script)?;
+	let protocols = load_protocols(&options.protocols)?;
+
+	let path = socket_path(&display_name(options.socket.clone())).ok_or(ScriptError::NoRuntimeDir)?;
+	let stream = UnixStream::connect(&path).map_err(|e| ScriptError::Connect(path, e))?;
+
+	let timeout = Duration::from_millis(options.timeout.unwrap_or(1000));
+	let mut runner = Runner::new(protocols, stream, timeout, options.verbose);
+	runner.run(&lines)
+}
+
+#[derive(Debug, Error)]
+pub enum ScriptError {
+	#[error("{0}\nusage: wl-script [--socket NAME] [--protocol FILE]... [--timeout MS] [--verbose] SCRIPT")]
+	Usage(String),
+	#[error("XDG_RUNTIME_DIR is not set")]
+	NoRuntimeDir,
+	#[error("Failed to read script {0}\n\t{1}")]
+	ReadScript(PathBuf, #[source] io::Error),
+	#[error("Failed to parse script\n\t{0}")]
+	Parse(#[from] ParseError),
+	#[error(transparent)]
+	Protocol(#[from] LoadProtocolError),
+	#[error("Failed to connect to {0}\n\t{1}")]
+	Connect(PathBuf, #[source] io::Error),
+	#[error("line {0}: {1}")]
+	Line(usize, String),
+	#[error("Failed to open a file to send\n\t{0}")]
+	OpenFd(#[source] io::Error),
+	#[error("Failed to serialize request\n\t{0}")]
+	Serialize(#[from] SerializeRawError),
+	#[error("Failed to poll socket\n\t{0}")]
+	Poll(#[source] nix::Error),
+	#[error("Failed to read socket\n\t{0}")]
+	Recv(#[source] nix::Error),
+	#[error("Failed to send message on socket\n\t{0}")]
+	Send(#[source] nix::Error),
+	#[error("The server closed the connection")]
+	Disconnected,
+}
+
+fn main() {
+	let result = Options::parse(env::args().skip(1)).and_then(run);
+	match result {
+		Ok(0) => {},
+		Ok(mismatches) => {
+			println!("{} event(s) didn't match", mismatches);
+			std::process::exit(1);
+		},
+		Err(e) => {
+			eprintln!("{}", e);
+			std::process::exit(2);
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn send_error(text: &str) -> String {
+		let (stream, _server) = UnixStream::pair().unwrap();
+		let mut runner = Runner::new(load_protocols(&[]).unwrap(), stream, Duration::from_millis(0), false);
+		let lines = parse_script(text).unwrap();
+		match runner.send(&lines[0]) {
+			Err(ScriptError::Line(1, message)) => message,
+			result => panic!("Expected a line error, got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn integers_out_of_range_are_errors() {
+		assert_eq!(send_error("-> wl_surface@3.set_buffer_scale(2147483648)"), "2147483648 is out of range for scale");
+		assert_eq!(send_error("-> wl_shm_pool@4.resize(-2147483649)"), "-2147483649 is out of range for size");
+		assert_eq!(send_error("-> wl_registry@2.bind(-1, \"wl_compositor\", 4, new id [unknown]@3)"), "-1 is out of range for name");
+		assert_eq!(send_error("-> wl_registry@2.bind(1, \"wl_compositor\", -1, new id [unknown]@3)"), "-1 is out of range for id");
+	}
+}
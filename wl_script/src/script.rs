@@ -0,0 +1,323 @@
+use std::{
+	iter::{Peekable},
+	str::{Chars},
+	fmt,
+};
+
+use thiserror::{Error};
+
+use wl_common::{
+	interface::{MessageSide},
+};
+
+/// One message of a script, either a request to send or an event to expect.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+	pub number: usize,
+	pub text: String,
+	pub side: MessageSide,
+	pub interface: String,
+	pub object: u32,
+	pub message: String,
+	pub args: Vec<Pattern>,
+}
+
+impl fmt::Display for Line {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.text)
+	}
+}
+
+/// An argument as written in a script. Arguments are written the way `WAYLAND_DEBUG` prints them, so an untyped
+/// new_id such as the one in `wl_registry.bind` takes up three patterns: the interface name, the version and the
+/// `new id`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+	/// `*`, which matches any value. Only allowed in events.
+	Any,
+	/// `nil`, a null string, object or new_id.
+	Nil,
+	Int(i64),
+	Fixed(f64),
+	String(Vec<u8>),
+	/// `interface@id`. The interface name is only there for readability.
+	Object(u32),
+	/// `new id interface@id`, where the id can be `*` in events.
+	NewId(String, Option<u32>),
+	/// `array[len]`. Arrays sent are filled with zeroes.
+	Array(usize),
+	/// `fd N`. The number is ignored, sent fds refer to `/dev/null`.
+	Fd,
+}
+
+impl fmt::Display for Pattern {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Pattern::Any => write!(f, "*"),
+			Pattern::Nil => write!(f, "nil"),
+			Pattern::Int(v) => write!(f, "{}", v),
+			Pattern::Fixed(v) => write!(f, "{:.6}", v),
+			Pattern::String(ref v) => write!(f, "{:?}", String::from_utf8_lossy(v)),
+			Pattern::Object(id) => write!(f, "@{}", id),
+			Pattern::NewId(ref interface, Some(id)) => write!(f, "new id {}@{}", interface, id),
+			Pattern::NewId(ref interface, None) => write!(f, "new id {}@*", interface),
+			Pattern::Array(len) => write!(f, "array[{}]", len),
+			Pattern::Fd => write!(f, "fd"),
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+	pub line: usize,
+	pub message: String,
+}
+
+/// Parses a script. Lines starting with `->` are requests and lines starting with `<-` are events, anything else
+/// after optional whitespace must be a `#` comment or empty. A `[timestamp]` before the arrow is skipped so lines can
+/// be copied from `WAYLAND_DEBUG` output.
+pub fn parse_script(text: &str) -> Result<Vec<Line>, ParseError> {
+	let mut lines = Vec::new();
+	for (i, text) in text.lines().enumerate() {
+		let number = i + 1;
+		let trimmed = text.trim();
+		if trimmed.is_empty() || trimmed.starts_with('#') {
+			continue;
+		}
+		let line = parse_line(number, trimmed).map_err(|message| ParseError { line: number, message })?;
+		lines.push(line);
+	}
+	Ok(lines)
+}
+
+fn parse_line(number: usize, text: &str) -> Result<Line, String> {
+	let mut rest = text;
+	if rest.starts_with('[') {
+		let end = rest.find(']').ok_or_else(|| String::from("unterminated timestamp"))?;
+		rest = rest[end + 1..].trim_start();
+	}
+	let side = if rest.starts_with("->") {
+		MessageSide::Request
+	} else if rest.starts_with("<-") {
+		MessageSide::Event
+	} else {
+		return Err(String::from("expected -> or <-"));
+	};
+	let rest = rest[2..].trim_start();
+
+	let open = rest.find('(').ok_or_else(|| String::from("expected ( after the message name"))?;
+	if !rest.ends_with(')') {
+		return Err(String::from("expected ) at the end of the line"));
+	}
+	let (target, message) = split_target(&rest[..open])?;
+	let (interface, object) = split_object(target)?;
+	let object = object.ok_or_else(|| String::from("the sender can't be a wildcard"))?;
+
+	let mut chars = rest[open + 1..rest.len() - 1].chars().peekable();
+	let mut args = Vec::new();
+	loop {
+		skip_whitespace(&mut chars);
+		if chars.peek().is_none() {
+			break;
+		}
+		if !args.is_empty() {
+			if chars.next() != Some(',') {
+				return Err(String::from("expected , between arguments"));
+			}
+			skip_whitespace(&mut chars);
+		}
+		let pattern = parse_pattern(&mut chars)?;
+		if pattern == Pattern::Any && side == MessageSide::Request {
+			return Err(String::from("wildcards can only be used in events"));
+		}
+		args.push(pattern);
+	}
+
+	Ok(Line {
+		number,
+		text: text.to_owned(),
+		side,
+		interface: interface.to_owned(),
+		object,
+		message: message.to_owned(),
+		args,
+	})
+}
+
+// Splits `interface@id.message` into `interface@id` and `message`
+fn split_target(text: &str) -> Result<(&str, &str), String> {
+	let dot = text.rfind('.').ok_or_else(|| String::from("expected interface@id.message"))?;
+	let message = text[dot + 1..].trim();
+	if message.is_empty() {
+		return Err(String::from("missing message name"));
+	}
+	Ok((text[..dot].trim(), message))
+}
+
+// Splits `interface@id` into its parts, with `None` for an id of `*`
+fn split_object(text: &str) -> Result<(&str, Option<u32>), String> {
+	let at = text.rfind('@').ok_or_else(|| format!("expected interface@id, got {}", text))?;
+	let (interface, id) = (&text[..at], &text[at + 1..]);
+	if interface.is_empty() {
+		return Err(format!("missing interface in {}", text));
+	}
+	match id {
+		"*" => Ok((interface, None)),
+		"nil" => Ok((interface, Some(0))),
+		_ => id.parse().map(|id| (interface, Some(id))).map_err(|_| format!("invalid object id {}", id)),
+	}
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+	while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+		chars.next();
+	}
+}
+
+// Takes characters up to the next top level comma
+fn take_word(chars: &mut Peekable<Chars>) -> String {
+	let mut word = String::new();
+	while let Some(&c) = chars.peek() {
+		if c == ',' {
+			break;
+		}
+		word.push(c);
+		chars.next();
+	}
+	word.trim_end().to_owned()
+}
+
+fn parse_pattern(chars: &mut Peekable<Chars>) -> Result<Pattern, String> {
+	if chars.peek() == Some(&'"') {
+		chars.next();
+		return parse_string(chars).map(Pattern::String);
+	}
+
+	let word = take_word(chars);
+	if word == "*" {
+		return Ok(Pattern::Any);
+	}
+	if word == "nil" {
+		return Ok(Pattern::Nil);
+	}
+	if let Some(object) = word.strip_prefix("new id ") {
+		let (interface, id) = split_object(object.trim())?;
+		return Ok(Pattern::NewId(interface.to_owned(), id));
+	}
+	if word.starts_with("fd ") {
+		return Ok(Pattern::Fd);
+	}
+	if let Some(len) = word.strip_prefix("array[").and_then(|rest| rest.strip_suffix(']')) {
+		return len.parse().map(Pattern::Array).map_err(|_| format!("invalid array length {}", len));
+	}
+	if word.contains('@') {
+		let (_, id) = split_object(&word)?;
+		return id.map(Pattern::Object).ok_or_else(|| format!("object ids can't be wildcards, use * instead of {}", word));
+	}
+	if word.contains('.') {
+		return word.parse().map(Pattern::Fixed).map_err(|_| format!("invalid number {}", word));
+	}
+	word.parse().map(Pattern::Int).map_err(|_| format!("invalid argument {}", word))
+}
+
+// Parses the rest of a string after the opening quote, handling the escapes \" \\ and \n
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<Vec<u8>, String> {
+	let mut string = String::new();
+	loop {
+		match chars.next() {
+			Some('"') => break,
+			Some('\\') => match chars.next() {
+				Some('n') => string.push('\n'),
+				Some(c @ '"') | Some(c @ '\\') => string.push(c),
+				Some(c) => return Err(format!("unknown escape \\{}", c)),
+				None => return Err(String::from("unterminated string")),
+			},
+			Some(c) => string.push(c),
+			None => return Err(String::from("unterminated string")),
+		}
+	}
+	skip_whitespace(chars);
+	Ok(string.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(text: &str) -> Line {
+		let mut lines = parse_script(text).unwrap();
+		assert_eq!(lines.len(), 1);
+		lines.remove(0)
+	}
+
+	fn error(text: &str) -> String {
+		parse_script(text).unwrap_err().message
+	}
+
+	#[test]
+	fn arrows_give_the_side() {
+		let request = parse("-> wl_display@1.sync(new id wl_callback@2)");
+		assert_eq!((request.side, request.interface.as_str(), request.object, request.message.as_str()), (MessageSide::Request, "wl_display", 1, "sync"));
+		assert_eq!(request.args, vec![Pattern::NewId(String::from("wl_callback"), Some(2))]);
+
+		let event = parse("<- wl_callback@2.done(1234)");
+		assert_eq!((event.side, event.object), (MessageSide::Event, 2));
+		assert_eq!(event.args, vec![Pattern::Int(1234)]);
+	}
+
+	#[test]
+	fn timestamps_comments_and_blank_lines_are_skipped() {
+		let lines = parse_script("# a comment\n\n  [1234567.890]  -> wl_display@1.get_registry(new id wl_registry@2)\n").unwrap();
+		assert_eq!(lines.len(), 1);
+		assert_eq!(lines[0].number, 3);
+		assert_eq!(lines[0].message, "get_registry");
+	}
+
+	#[test]
+	fn arguments_are_parsed_like_wayland_debug_prints_them() {
+		let line = parse("-> wl_registry@2.bind(1, \"wl_compositor\", 4, new id [unknown]@3)");
+		assert_eq!(line.args, vec![
+			Pattern::Int(1),
+			Pattern::String(b"wl_compositor".to_vec()),
+			Pattern::Int(4),
+			Pattern::NewId(String::from("[unknown]"), Some(3)),
+		]);
+
+		let line = parse("-> wl_surface@5.attach(wl_buffer@7, -1, 2.5)");
+		assert_eq!(line.args, vec![Pattern::Object(7), Pattern::Int(-1), Pattern::Fixed(2.5)]);
+
+		let line = parse("<- wl_keyboard@9.keymap(1, fd 12, 48, array[8], nil, wl_surface@nil)");
+		assert_eq!(line.args, vec![Pattern::Int(1), Pattern::Fd, Pattern::Int(48), Pattern::Array(8), Pattern::Nil, Pattern::Object(0)]);
+	}
+
+	#[test]
+	fn strings_can_contain_escapes_and_commas() {
+		let line = parse(r#"-> xdg_toplevel@8.set_title("a \"quoted\", \\ line\n")"#);
+		assert_eq!(line.args, vec![Pattern::String(b"a \"quoted\", \\ line\n".to_vec())]);
+	}
+
+	#[test]
+	fn events_can_use_wildcards() {
+		let line = parse("<- wl_data_device@4.data_offer(new id wl_data_offer@*, *)");
+		assert_eq!(line.args, vec![Pattern::NewId(String::from("wl_data_offer"), None), Pattern::Any]);
+	}
+
+	#[test]
+	fn malformed_lines_are_errors() {
+		assert_eq!(parse_script("\n-> wl_display@1.sync(").unwrap_err().line, 2);
+		assert_eq!(error("wl_display@1.sync()"), "expected -> or <-");
+		assert_eq!(error("[123 -> wl_display@1.sync()"), "unterminated timestamp");
+		assert_eq!(error("-> wl_display@1.sync"), "expected ( after the message name");
+		assert_eq!(error("-> wl_display@1.sync("), "expected ) at the end of the line");
+		assert_eq!(error("-> wl_display@1.(1)"), "missing message name");
+		assert_eq!(error("<- wl_callback@*.done(1)"), "the sender can't be a wildcard");
+		assert_eq!(error("-> wl_display@x.sync()"), "invalid object id x");
+		assert_eq!(error("-> wl_callback@2.done(*)"), "wildcards can only be used in events");
+		assert_eq!(error("-> wl_surface@5.attach(1 2)"), "invalid argument 1 2");
+		assert_eq!(error(r#"-> xdg_toplevel@8.set_title("a\tb")"#), "unknown escape \\t");
+		assert_eq!(error(r#"-> xdg_toplevel@8.set_title("abc)"#), "unterminated string");
+		assert_eq!(error(r#"-> xdg_toplevel@8.set_title("a" "b")"#), "expected , between arguments");
+		assert_eq!(error("<- wl_surface@5.enter(wl_output@*)"), "object ids can't be wildcards, use * instead of wl_output@*");
+	}
+}
@@ -1,5 +1,3 @@
-static PROTOCOL: &str = include_str!("wayland.xml");
-
 use std::{
 	env,
 	fs,
@@ -7,7 +5,7 @@ use std::{
 };
 
 fn main() {
-	let api = wl_scanner::generate_api(PROTOCOL).expect("Failed to generate Rust API");
+	let api = wl_scanner::generate_api(wl_scanner::scanner::CORE_PROTOCOL).expect("Failed to generate Rust API");
 	let formatted_api = wl_scanner::format_rustfmt_external(&api).expect("Failed to format Rust API");
 	let out_dir = env::var("OUT_DIR").expect("OUT_DIR not specified");
 	let mut out_path = path::PathBuf::from(out_dir);
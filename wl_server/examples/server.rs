@@ -1,8 +1,25 @@
+use std::{
+	sync::{OnceLock},
+};
+
+use nix::{
+	sys::{signal::{self, Signal, SigAction, SigHandler, SaFlags, SigSet}},
+};
+
 use wl_server::{
-	Server, Resource, NewResource,
+	Server, NewResource, BindContext, StopHandle,
+	shm::{Shm},
 	protocol::*,
 };
 
+static STOP: OnceLock<StopHandle> = OnceLock::new();
+
+extern "C" fn stop_server(_signal: i32) {
+	if let Some(stop) = STOP.get() {
+		stop.stop();
+	}
+}
+
 fn main() {
 	setup_logging();
 
@@ -10,7 +27,8 @@ fn main() {
 
 	let state = State::new();
 	let mut server = Server::new(state).unwrap();
-	server.register_global::<WlCompositor, _>(|new_resource: NewResource<WlCompositor>| {
+	// TODO: these required closure argument type annotations can be mitigated by adding a `register_fn` function
+	server.register_global::<WlCompositor, _>(|_bind: BindContext, new_resource: NewResource<WlCompositor>| {
 		new_resource.register_fn(
 			(),
 			|_state, compositor, request| {
@@ -22,28 +40,17 @@ fn main() {
 			}
 		);
 	});
-	// TODO: these required closure argument type annotations can be mitigated by adding a `register_fn` function
-	server.register_global::<WlShm, _>(|new_resource: NewResource<WlShm>| {
-		new_resource.register_fn(
-			ShmData { },
-			|_state, shm: Resource<WlShm>, request| {
-				let _shm_data = shm.get_data::<ShmData>().unwrap();
-				match request {
-					WlShmRequest::CreatePool(create_pool) => {
-						dbg!(create_pool);
-					},
-				}
-			},
-			|_, _| {
-				log::info!("Shm destroyed");
-			}
-		);
-	});
-	server.run(|_this| ClientState::new()).unwrap();
-}
+	server.register_global(Shm::default());
 
-pub struct ShmData {
+	// Ctrl-C shuts the server down, which removes the socket and runs the destructors above
+	let _ = STOP.set(server.stop_handle());
+	let action = SigAction::new(SigHandler::Handler(stop_server), SaFlags::empty(), SigSet::empty());
+	for &signal in &[Signal::SIGINT, Signal::SIGTERM] {
+		unsafe { signal::sigaction(signal, &action) }.expect("Failed to set signal handler");
+	}
 
+	server.run(|_this| ClientState::new()).unwrap();
+	log::info!("Server stopped");
 }
 
 pub struct State {
@@ -0,0 +1,202 @@
+//! Generational slot arenas that clients and objects are stored in. A `Key` names a slot and the generation of the
+//! value that was put in it, so a key outlives its value safely: once the value is removed, looking the key up fails
+//! with a `DanglingError` even if the slot was reused.
+
+use std::{
+	rc::{Rc},
+	marker::{PhantomData},
+	hash::{Hash, Hasher},
+	fmt,
+};
+
+use thiserror::{Error};
+
+/// A copyable reference to a value in an `Arena<T>`.
+pub struct Key<T> {
+	index: u32,
+	generation: u32,
+	_value: PhantomData<fn() -> T>,
+}
+
+impl<T> Key<T> {
+	/// A key that never resolves to a value.
+	pub(crate) fn dangling() -> Self {
+		Self {
+			index: u32::MAX,
+			generation: 0,
+			_value: PhantomData,
+		}
+	}
+}
+
+impl<T> Clone for Key<T> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+
+impl<T> Copy for Key<T> {}
+
+impl<T> PartialEq for Key<T> {
+	fn eq(&self, other: &Self) -> bool {
+		self.index == other.index && self.generation == other.generation
+	}
+}
+
+impl<T> Eq for Key<T> {}
+
+impl<T> Hash for Key<T> {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.index.hash(state);
+		self.generation.hash(state);
+	}
+}
+
+impl<T> fmt::Debug for Key<T> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Key({}v{})", self.index, self.generation)
+	}
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+	generation: u32,
+	// When the current value was inserted, for iterating in insertion order
+	inserted: u64,
+	value: Option<Rc<T>>,
+}
+
+/// Values are kept in `Rc`s so one can be used while the arena changes, for example while a request handler creates
+/// and destroys objects.
+#[derive(Debug)]
+pub(crate) struct Arena<T> {
+	slots: Vec<Slot<T>>,
+	free: Vec<u32>,
+	inserted: u64,
+	len: usize,
+}
+
+impl<T> Arena<T> {
+	pub(crate) fn new() -> Self {
+		Self {
+			slots: Vec::new(),
+			free: Vec::new(),
+			inserted: 0,
+			len: 0,
+		}
+	}
+
+	/// Inserts a value built from its own key, for values that need to know it. The slot is only taken once the
+	/// value is built, so nothing changes if building it panics.
+	pub(crate) fn insert_with<F: FnOnce(Key<T>) -> T>(&mut self, value: F) -> Key<T> {
+		let (index, generation) = match self.free.last() {
+			Some(&index) => (index, self.slots[index as usize].generation.wrapping_add(1)),
+			None => (self.slots.len() as u32, 0),
+		};
+		let key = Key {
+			index,
+			generation,
+			_value: PhantomData,
+		};
+		let value = Some(Rc::new(value(key)));
+
+		self.inserted += 1;
+		let slot = Slot {
+			generation,
+			inserted: self.inserted,
+			value,
+		};
+		if self.free.last() == Some(&index) {
+			self.free.pop();
+			self.slots[index as usize] = slot;
+		} else {
+			self.slots.push(slot);
+		}
+		self.len += 1;
+		key
+	}
+
+	pub(crate) fn insert(&mut self, value: T) -> Key<T> {
+		self.insert_with(|_| value)
+	}
+
+	pub(crate) fn get(&self, key: Key<T>) -> Option<Rc<T>> {
+		self.slots.get(key.index as usize)
+			.filter(|slot| slot.generation == key.generation)
+			.and_then(|slot| slot.value.clone())
+	}
+
+	/// Removes a value. The value itself lives on until every `Rc` to it returned by `get` is dropped.
+	pub(crate) fn remove(&mut self, key: Key<T>) -> Option<Rc<T>> {
+		let slot = self.slots.get_mut(key.index as usize).filter(|slot| slot.generation == key.generation)?;
+		let value = slot.value.take()?;
+		self.free.push(key.index);
+		self.len -= 1;
+		Some(value)
+	}
+
+	pub(crate) fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Returns every value with its key, in the order they were inserted.
+	pub(crate) fn entries(&self) -> Vec<(Key<T>, Rc<T>)> {
+		let mut entries = self.slots.iter().enumerate().filter_map(|(index, slot)| {
+			let value = slot.value.clone()?;
+			Some((slot.inserted, Key { index: index as u32, generation: slot.generation, _value: PhantomData }, value))
+		}).collect::<Vec<_>>();
+		entries.sort_by_key(|&(inserted, _, _)| inserted);
+		entries.into_iter().map(|(_, key, value)| (key, value)).collect()
+	}
+}
+
+/// Returned when a client or object is used after it's gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum DanglingError {
+	#[error("The client was disconnected")]
+	Client,
+	#[error("The object was destroyed")]
+	Object,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn removed_keys_stay_dangling_after_reuse() {
+		let mut arena = Arena::new();
+		let first = arena.insert("first");
+		assert_eq!(arena.remove(first).as_deref(), Some(&"first"));
+		let second = arena.insert("second");
+		assert!(arena.get(first).is_none());
+		assert!(arena.remove(first).is_none());
+		assert_eq!(arena.get(second).as_deref(), Some(&"second"));
+		assert_eq!(arena.len(), 1);
+	}
+
+	#[test]
+	fn entries_are_in_insertion_order() {
+		let mut arena = Arena::new();
+		let a = arena.insert('a');
+		arena.insert('b');
+		arena.remove(a);
+		arena.insert('c');
+		let values = arena.entries().into_iter().map(|(_, value)| *value).collect::<Vec<_>>();
+		assert_eq!(values, vec!['b', 'c']);
+	}
+
+	#[test]
+	fn panicking_insert_with_leaves_the_arena_unchanged() {
+		let mut arena = Arena::new();
+		let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+			arena.insert_with(|_| -> (u32, u32) { panic!("failed to build the value") });
+		}));
+		assert!(result.is_err());
+		assert_eq!(arena.len(), 0);
+
+		let key = arena.insert_with(|key| (key.index, key.generation));
+		assert_eq!(arena.get(key).as_deref(), Some(&(key.index, key.generation)));
+		assert_eq!(arena.slots.len(), 1);
+	}
+}
@@ -0,0 +1,267 @@
+//! Running a server on a tokio runtime, with the `tokio` feature. The listener, the clients' sockets and the
+//! `ServerHandle` wakeup are registered with the runtime's reactor and the server is dispatched when one of them is
+//! ready.
+//!
+//! The server isn't `Send`, so `Server::run_async` has to run on the thread that owns the server, for example with
+//! `LocalSet::run_until` or `Runtime::block_on` on a current thread runtime.
+//!
+//! ```ignore
+//! let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build()?;
+//! let local = tokio::task::LocalSet::new();
+//! local.block_on(&runtime, server.run_async(|_client| ClientState::new()))?;
+//! ```
+
+use std::{
+	os::unix::{io::{RawFd, OwnedFd, BorrowedFd}},
+	collections::{HashMap},
+	future::{Future},
+	pin::{Pin},
+	task::{Context, Poll},
+	io,
+};
+
+use nix::{
+	sys::{stat},
+};
+use tokio::{
+	io::{unix::{AsyncFd}},
+};
+
+use crate::{
+	server::{Server, ServerError},
+	client::{ClientHandle},
+};
+
+impl Server {
+	/// Dispatches the server whenever a client sent something, a client is connecting, events queued for a client
+	/// can be sent or a `ServerHandle` queued work. Like `Server::run`, this shuts the server down and returns once
+	/// it's stopped, and otherwise only returns if a socket can't be registered with the reactor.
+	pub async fn run_async<S: 'static, F: FnMut(ClientHandle) -> S>(&mut self, mut client_state_creator: F) -> Result<(), ServerError> {
+		let mut sources = Sources::new(self).map_err(ServerError::Reactor)?;
+		while !self.stop_requested() {
+			sources.update(self).map_err(ServerError::Reactor)?;
+			sources.ready().await.map_err(ServerError::Reactor)?;
+			if self.stop_requested() {
+				break;
+			}
+			if let Err(e) = self.dispatch(&mut client_state_creator) {
+				log::error!("{}", e);
+			}
+		}
+		// The registrations are dropped first, as they're duplicates of the sockets that are about to be closed
+		drop(sources);
+		self.shutdown();
+		Ok(())
+	}
+}
+
+/// Everything the server waits on, registered with the reactor.
+struct Sources {
+	listener: Option<AsyncFd<OwnedFd>>,
+	wake: AsyncFd<OwnedFd>,
+	// The debug socket can be replaced while the server runs, so it's told apart by its inode instead of its fd
+	debug_socket: Option<(u64, AsyncFd<OwnedFd>)>,
+	// Debug socket connections that wait for the rest of their snapshot. They're rare and short-lived, so they're
+	// registered anew on every update
+	debug_connections: Vec<AsyncFd<OwnedFd>>,
+	clients: HashMap<u32, ClientSource>,
+}
+
+struct ClientSource {
+	fd: AsyncFd<OwnedFd>,
+	wants_write: bool,
+}
+
+/// Registers a duplicate of `fd`. The registration then stays valid however long the server keeps the original open,
+/// and deregistering it can never affect a newer file that was given the same fd number.
+fn register(fd: RawFd) -> io::Result<AsyncFd<OwnedFd>> {
+	// SAFETY: The server keeps `fd` open while it's being duplicated
+	let fd = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+	// SAFETY: The registration owns the duplicate, so it stays open for as long as it's registered
+	Ok(unsafe { AsyncFd::register(fd) }?)
+}
+
+impl Sources {
+	fn new(server: &Server) -> io::Result<Self> {
+		Ok(Self {
+			listener: server.listener_fd().map(register).transpose()?,
+			wake: register(server.wake_fd())?,
+			debug_socket: None,
+			debug_connections: Vec::new(),
+			clients: HashMap::new(),
+		})
+	}
+
+	/// Registers clients that connected and forgets clients that are gone since the last dispatch.
+	fn update(&mut self, server: &Server) -> io::Result<()> {
+		let clients = server.clients().filter_map(|client| {
+			let client = client.get().ok()?;
+			let net = client.net.borrow();
+			Some((client.id(), net.fd(), net.has_queued_events()))
+		}).collect::<Vec<_>>();
+
+		self.clients.retain(|id, _| clients.iter().any(|&(client, _, _)| client == *id));
+		for (id, fd, wants_write) in clients {
+			let source = match self.clients.get_mut(&id) {
+				Some(source) => source,
+				None => self.clients.entry(id).or_insert(ClientSource {
+					fd: register(fd)?,
+					wants_write: false,
+				}),
+			};
+			source.wants_write = wants_write;
+		}
+
+		let debug_socket = server.debug_socket_fd().and_then(|fd| stat::fstat(fd).ok().map(|stat| (fd, stat.st_ino)));
+		match (debug_socket, &self.debug_socket) {
+			(Some((_, inode)), Some((registered, _))) if inode == *registered => {},
+			(Some((fd, inode)), _) => {
+				self.debug_socket = Some((inode, register(fd)?));
+			},
+			(None, _) => self.debug_socket = None,
+		}
+		self.debug_connections = server.debug_connection_fds().into_iter().map(register).collect::<io::Result<_>>()?;
+
+		Ok(())
+	}
+
+	fn ready(&self) -> Ready<'_> {
+		Ready {
+			sources: self,
+		}
+	}
+}
+
+/// Resolves once any source is ready. Every ready source has its readiness cleared, as the following dispatch
+/// handles all of them.
+struct Ready<'a> {
+	sources: &'a Sources,
+}
+
+impl<'a> Future for Ready<'a> {
+	type Output = io::Result<()>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		let sources = self.sources;
+		let mut readable = vec![&sources.wake];
+		readable.extend(sources.listener.iter());
+		readable.extend(sources.debug_socket.iter().map(|(_, fd)| fd));
+		readable.extend(sources.clients.values().map(|source| &source.fd));
+		let writable = sources.clients.values()
+			.filter(|source| source.wants_write)
+			.map(|source| &source.fd)
+			.chain(sources.debug_connections.iter());
+
+		// Every source is polled, even after one is ready, so all their readiness is cleared at once
+		let mut ready = false;
+		for fd in readable {
+			if let Poll::Ready(guard) = fd.poll_read_ready(cx) {
+				guard?.clear_ready();
+				ready = true;
+			}
+		}
+		for fd in writable {
+			if let Poll::Ready(guard) = fd.poll_write_ready(cx) {
+				guard?.clear_ready();
+				ready = true;
+			}
+		}
+
+		if ready {
+			Poll::Ready(Ok(()))
+		} else {
+			Poll::Pending
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		os::unix::{net::{UnixStream}},
+		io::{Read, Write},
+		thread,
+		time::{Duration},
+		env,
+		process,
+	};
+
+	use tokio::{
+		sync::{oneshot},
+		task::{LocalSet},
+	};
+
+	use crate::{
+		Server,
+	};
+
+	#[test]
+	fn requests_are_dispatched_on_readiness() {
+		let mut server = Server::new_without_socket(());
+		let (mut ours, theirs) = UnixStream::pair().unwrap();
+		server.add_client(theirs, |_| ());
+
+		let (done_sender, done_receiver) = oneshot::channel();
+		let client = thread::spawn(move || {
+			// wl_display@1.sync(new id wl_callback@2)
+			let mut sync = Vec::new();
+			sync.extend_from_slice(&1u32.to_ne_bytes());
+			sync.extend_from_slice(&0u16.to_ne_bytes());
+			sync.extend_from_slice(&12u16.to_ne_bytes());
+			sync.extend_from_slice(&2u32.to_ne_bytes());
+			ours.write_all(&sync).unwrap();
+
+			ours.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+			let mut done = [0u8; 12];
+			ours.read_exact(&mut done).unwrap();
+			let _ = done_sender.send(());
+			done
+		});
+
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+		LocalSet::new().block_on(&runtime, async {
+			tokio::select! {
+				result = server.run_async(|_| ()) => panic!("Server stopped: {:?}", result),
+				_ = done_receiver => {},
+			}
+		});
+
+		let done = client.join().unwrap();
+		assert_eq!(u32::from_ne_bytes([done[0], done[1], done[2], done[3]]), 2);
+		assert_eq!(u16::from_ne_bytes([done[4], done[5]]), 0);
+	}
+
+	#[test]
+	fn clients_connecting_at_once_are_all_accepted() {
+		let path = env::temp_dir().join(format!("wl-test-{}-async-accept", process::id()));
+		let mut server = Server::bind(&path, ()).unwrap();
+		// Both are waiting before the listener is registered, so it's only reported readable once. Neither sends
+		// anything, so nothing else makes the server dispatch again.
+		let _streams = [UnixStream::connect(&path).unwrap(), UnixStream::connect(&path).unwrap()];
+
+		// Stops the server if it doesn't accept both in time
+		let stop = server.stop_handle();
+		thread::spawn(move || {
+			thread::sleep(Duration::from_secs(5));
+			stop.stop();
+		});
+
+		let mut accepted = 0;
+		let (done_sender, done_receiver) = oneshot::channel();
+		let mut done_sender = Some(done_sender);
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+		LocalSet::new().block_on(&runtime, async {
+			tokio::select! {
+				result = server.run_async(|_| {
+					accepted += 1;
+					if accepted == 2 {
+						let _ = done_sender.take().unwrap().send(());
+					}
+				}) => result.unwrap(),
+				_ = done_receiver => {},
+			}
+		});
+
+		assert_eq!(accepted, 2);
+	}
+}
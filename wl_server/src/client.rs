@@ -1,6 +1,7 @@
 use std::{
 	ffi::{CString},
-	cell::{RefCell},
+	cell::{Cell, RefCell},
+	rc::{Rc, Weak},
 	fmt,
 };
 
@@ -9,15 +10,21 @@ use loaner::{
 };
 
 use wl_common::{
-	interface::{Interface, Message, IntoArgsError, InterfaceTitle}, wire::{DynMessage},
+	interface::{Interface, DynInterface, Message, MessageSide, IntoArgsError, InterfaceTitle}, wire::{DynMessage},
+	debug::{DebugMessage},
 };
 
 use crate::{
 	server::{State, SendEventError},
-	net::{NetClient, NetError},
+	net::{NetClient, NetError, Credentials, SessionRecorder, ClientRecorder},
 	resource::{Resource, Untyped, NewResource},
-	object::{Object, ObjectMap, ObjectImplementation},
-	global::{GlobalManager},
+	object::{Object, ObjectMap, ObjectKey, ObjectImplementation},
+	arena::{Arena, Key, DanglingError},
+	global::{self, GlobalManager},
+	serial::{SerialManager, SerialRecord},
+	middleware::{MiddlewareChain, MiddlewareAction},
+	limits::{ClientLimits, ClientLimit},
+	remote::{RemoteResources, RemoteResource},
 	protocol::*,
 };
 
@@ -25,7 +32,13 @@ use crate::{
 pub struct ClientManager {
 	pub(crate) this: Option<Handle<RefCell<ClientManager>>>,
 	pub(crate) global_manager: Option<Handle<RefCell<GlobalManager>>>,
-	pub(crate) clients: Vec<Owner<Client>>,
+	pub(crate) serial_manager: Option<Handle<RefCell<SerialManager>>>,
+	pub(crate) middleware: Option<Handle<RefCell<MiddlewareChain>>>,
+	pub(crate) clients: Rc<RefCell<Arena<Client>>>,
+	pub(crate) default_limits: ClientLimits,
+	pub(crate) default_tracing: bool,
+	pub(crate) default_recorder: Option<Rc<RefCell<SessionRecorder>>>,
+	pub(crate) remote_resources: RemoteResources,
 	next_id: u32,
 }
 
@@ -34,7 +47,13 @@ impl ClientManager {
 		Self {
 			this: None,
 			global_manager: None,
-			clients: Vec::new(),
+			serial_manager: None,
+			middleware: None,
+			clients: Rc::new(RefCell::new(Arena::new())),
+			default_limits: ClientLimits::default(),
+			default_tracing: crate::server::request_debug() || crate::server::event_debug(),
+			default_recorder: None,
+			remote_resources: RemoteResources::default(),
 			next_id: 1,
 		}
 	}
@@ -55,76 +74,210 @@ impl ClientManager {
 		self.global_manager.clone().expect("Global manager not set")
 	}
 
-	pub fn create_client<S: 'static>(&mut self, net: NetClient, state: S) -> Handle<Client> {
+	pub(crate) fn set_serial_manager(&mut self, serial_manager: Handle<RefCell<SerialManager>>) {
+		self.serial_manager = Some(serial_manager);
+	}
+
+	pub(crate) fn serial_manager(&self) -> Handle<RefCell<SerialManager>> {
+		self.serial_manager.clone().expect("Serial manager not set")
+	}
+
+	pub(crate) fn set_middleware(&mut self, middleware: Handle<RefCell<MiddlewareChain>>) {
+		self.middleware = Some(middleware);
+	}
+
+	pub(crate) fn middleware(&self) -> Handle<RefCell<MiddlewareChain>> {
+		self.middleware.clone().expect("Middleware chain not set")
+	}
+
+	pub fn create_client<S: 'static>(&mut self, mut net: NetClient, state: S) -> ClientHandle {
+		net.limits = self.default_limits;
 		let id = self.next_id;
 		self.next_id = self.next_id.wrapping_add(1);
-		let client = Client::new(id, self.this(), self.global_manager(), net, state);
-		let handle = client.handle();
-		self.clients.push(client);
+		net.recorder = self.default_recorder.clone().map(|recorder| ClientRecorder {
+			client: id,
+			recorder,
+		});
+		// The client is built with its own key so it can refer to itself
+		let key = self.clients.borrow_mut().insert_with(|key| {
+			let client = Client::new(id, self.handle(key), self, net, state);
+			client.tracing.set(self.default_tracing);
+			client
+		});
+		let handle = self.handle(key);
+
+		// Resources only resolve once their client is in the arena
+		let client = handle.get().expect("Client was just added");
+		let display = client.find_by_id::<WlDisplay>(1).expect("Client has no display");
+		display.set_implementation(WlDisplayImplementation);
+		*client.display.borrow_mut() = Some(display);
+
 		handle
 	}
 
-	pub fn remove_client(&mut self, handle: Handle<Client>) -> Option<Owner<Client>> {
-		self.clients.iter().position(|owner| owner.handle().is(&handle)).map(|position| self.clients.remove(position))
+	pub fn remove_client(&mut self, handle: &ClientHandle) -> Option<Rc<Client>> {
+		if let (Ok(client), Some(serial_manager)) = (handle.get(), self.serial_manager.as_ref().and_then(|serial_manager| serial_manager.get())) {
+			serial_manager.borrow_mut().remove_client(client.id());
+		}
+		// Bound first so the client is dropped after the arena is released
+		let client = self.clients.borrow_mut().remove(handle.key);
+		client
+	}
+
+	pub(crate) fn handle(&self, key: ClientKey) -> ClientHandle {
+		ClientHandle {
+			clients: Rc::downgrade(&self.clients),
+			key,
+		}
+	}
+
+	/// Returns handles to every client, in the order they connected.
+	pub(crate) fn clients(&self) -> impl Iterator<Item=ClientHandle> + '_ {
+		self.clients.borrow().entries().into_iter().map(move |(key, _)| self.handle(key))
+	}
+
+	/// Returns every client, in the order they connected.
+	pub(crate) fn live_clients(&self) -> Vec<Rc<Client>> {
+		self.clients.borrow().entries().into_iter().map(|(_, client)| client).collect()
 	}
 
 	pub fn flush_clients(&self) -> Result<bool, NetError> {
 		let mut flushed = true;
-		for client in &self.clients {
+		for client in self.live_clients() {
 			flushed = flushed && client.net.borrow_mut().flush()?;
 		}
 		Ok(flushed)
 	}
 }
 
+pub type ClientKey = Key<Client>;
+
+/// A reference to a client that can be kept after the client disconnects. Getting the client then fails with
+/// `DanglingError::Client`.
+#[derive(Clone)]
+pub struct ClientHandle {
+	clients: Weak<RefCell<Arena<Client>>>,
+	key: ClientKey,
+}
+
+impl ClientHandle {
+	pub fn get(&self) -> Result<Rc<Client>, DanglingError> {
+		let clients = self.clients.upgrade().ok_or(DanglingError::Client)?;
+		let client = clients.borrow().get(self.key).ok_or(DanglingError::Client);
+		client
+	}
+
+	pub fn key(&self) -> ClientKey {
+		self.key
+	}
+
+	pub fn is(&self, other: &ClientHandle) -> bool {
+		self.key == other.key && self.clients.ptr_eq(&other.clients)
+	}
+
+	/// Looks up one of the client's objects.
+	pub(crate) fn object(&self, key: ObjectKey) -> Result<Rc<Object>, DanglingError> {
+		let client = self.get()?;
+		let object = client.objects.borrow().get(key).ok_or(DanglingError::Object);
+		object
+	}
+}
+
+impl fmt::Debug for ClientHandle {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_tuple("ClientHandle")
+			.field(&self.key)
+			.finish()
+	}
+}
+
 // TODO: allow the user to associate dynamic data with a client as they do with objects
 #[derive(Debug)]
 pub struct Client {
-	this: RefCell<Option<Handle<Client>>>, // TODO: ensure necessary
+	this: ClientHandle,
 	id: u32,
 	client_manager: Handle<RefCell<ClientManager>>,
 	global_manager: Handle<RefCell<GlobalManager>>,
+	serial_manager: Handle<RefCell<SerialManager>>,
+	middleware: Handle<RefCell<MiddlewareChain>>,
 	
 	pub(crate) net: RefCell<NetClient>,
-	pub(crate) objects: Owner<RefCell<ObjectMap>>, // TODO: remove from Owner,
+	pub(crate) objects: RefCell<ObjectMap>,
 	pub(crate) state: RefCell<State>,
 
 	pub(crate) display: RefCell<Option<Resource<WlDisplay>>>,
 	pub(crate) registry: RefCell<Option<Resource<WlRegistry>>>,
+	pub(crate) error_posted: Cell<bool>,
+	pub(crate) requests_this_dispatch: Cell<usize>,
+	pub(crate) span: tracing::Span,
+	pub(crate) tracing: Cell<bool>,
 }
 
 impl Client {
-	pub(crate) fn new<S: 'static>(id: u32, client_manager: Handle<RefCell<ClientManager>>, global_manager: Handle<RefCell<GlobalManager>>, net: NetClient, state: S) -> Owner<Self> {
+	pub(crate) fn new<S: 'static>(id: u32, this: ClientHandle, client_manager: &ClientManager, net: NetClient, state: S) -> Self {
 		let mut objects = ObjectMap::new();
-		objects.add(Owner::new(Object::new::<WlDisplay, _>(1)));
-		let objects = Owner::new(RefCell::new(objects));
+		objects.add(Object::new::<WlDisplay, _>(1));
+		let objects = RefCell::new(objects);
 		let state = RefCell::new(State::new(Owner::new(state)));
 
-		let partial = Owner::new(Self {
-			this: RefCell::new(None),
+		let span = tracing::info_span!("client", id, pid = tracing::field::Empty);
+		if let Some(credentials) = net.credentials() {
+			span.record("pid", credentials.pid);
+		}
+
+		Self {
+			this,
 			id,
-			client_manager,
-			global_manager,
+			client_manager: client_manager.this(),
+			global_manager: client_manager.global_manager(),
+			serial_manager: client_manager.serial_manager(),
+			middleware: client_manager.middleware(),
 			net: RefCell::new(net),
 			objects,
 			state,
 			display: RefCell::new(None),
 			registry: RefCell::new(None),
-		});
-		let handle = partial.handle();
-		*partial.this.borrow_mut() = Some(handle.clone());
-
-		let display = partial.find_by_id::<WlDisplay>(1).unwrap();
-		display.set_implementation(WlDisplayImplementation);
-		*partial.display.borrow_mut() = Some(display);
-
-		partial
+			error_posted: Cell::new(false),
+			requests_this_dispatch: Cell::new(0),
+			span,
+			tracing: Cell::new(false),
+		}
 	}
 
 	pub fn id(&self) -> u32 {
 		self.id
 	}
 
+	pub fn credentials(&self) -> Option<Credentials> {
+		self.net.borrow().credentials()
+	}
+
+	/// The span that all of this client's request spans and events are recorded under.
+	pub fn span(&self) -> &tracing::Span {
+		&self.span
+	}
+
+	pub fn tracing_enabled(&self) -> bool {
+		self.tracing.get()
+	}
+
+	/// Turns tracing of this client's requests and events on or off.
+	pub fn set_tracing(&self, enabled: bool) {
+		self.tracing.set(enabled);
+	}
+
+	/// Records this client's traffic to its own capture, replacing any recorder set by `Server::record_session`.
+	pub fn record_session(&self, recorder: SessionRecorder) {
+		self.net.borrow_mut().recorder = Some(ClientRecorder {
+			client: self.id,
+			recorder: Rc::new(RefCell::new(recorder)),
+		});
+	}
+
+	pub fn stop_recording(&self) {
+		self.net.borrow_mut().recorder = None;
+	}
+
 	pub fn set_state<S: 'static>(&self, state: S) {
 		*self.state.borrow_mut() = State::new(Owner::new(state));
 	}
@@ -133,8 +286,31 @@ impl Client {
 		self.state.borrow().get::<Owner<S>>().custom_ref()
 	}
 
-	fn handle(&self) -> Handle<Client> {
-		self.this.borrow().clone().expect("Handle not set")
+	pub fn handle(&self) -> ClientHandle {
+		self.this.clone()
+	}
+
+	/// Returns a new serial and records that it was sent to this client on `resource`.
+	pub fn next_serial<I>(&self, resource: &Resource<I>) -> u32 {
+		let serial_manager = self.serial_manager.get().expect("Serial manager destroyed");
+		let mut serial_manager = serial_manager.borrow_mut();
+		match resource.object() {
+			Ok(object) => serial_manager.next_serial_for(self.id, object.interface.get().name, object.id),
+			Err(_) => serial_manager.next_serial(),
+		}
+	}
+
+	/// Checks whether `serial` was previously sent to this client, returning the object it was sent on.
+	pub fn validate_serial(&self, serial: u32) -> Option<SerialRecord> {
+		let serial_manager = self.serial_manager.get().expect("Serial manager destroyed");
+		let serial_manager = serial_manager.borrow();
+		serial_manager.find(self.id, serial)
+	}
+
+	pub(crate) fn remote_resource<I>(&self, resource: &Resource<I>) -> Option<RemoteResource<I>> {
+		let client_manager = self.client_manager.get()?;
+		let remote = client_manager.borrow_mut().remote_resources.add(resource);
+		Some(remote)
 	}
 
 	pub(crate) fn advertise_current_globals(&self) {
@@ -168,36 +344,131 @@ impl Client {
 	}
 
 	// TODO: change all of the client_map-specific function signatures to look like this
-	pub fn try_send_event<I: Interface>(&self, object: Handle<Object>, event: I::Event) -> Result<(), SendEventError> where I::Event: Message<ClientMap=ClientMap> + fmt::Debug {
-		let resource = Resource::<I>::new(self.this.borrow().clone().unwrap(), object.clone());
+	pub fn try_send_event<I: Interface>(&self, key: ObjectKey, event: I::Event) -> Result<(), SendEventError> where I::Event: Message<ClientMap=ClientMap> + fmt::Debug {
+		let resource = Resource::<I>::new(self.handle(), key);
 		if crate::server::event_debug() {
 			log::debug!(" -> {:?} {:?}", resource, event);
 		}
 
-		let object = object.get().ok_or(SendEventError::SenderMissing)?;
+		let object = self.objects.borrow().get(key).ok_or(SendEventError::SenderMissing)?;
 
 		let client_map = self.client_map();
-		let (opcode, args) = event.into_args(client_map)?;
+		let (opcode, mut args) = event.into_args(client_map)?;
+
+		// Events sent from inside a middleware skip the chain instead of re-entering it
+		let sender = Resource::new_untyped(self.handle(), key);
+		let action = self.middleware.get()
+			.and_then(|middleware| middleware.try_borrow_mut().ok().map(|mut middleware| middleware.event(self, &sender, opcode, &mut args)))
+			.unwrap_or(MiddlewareAction::Pass);
+		let discarded = action != MiddlewareAction::Pass;
+
+		if self.tracing_enabled() {
+			let interface = object.interface.get();
+			tracing::debug!(
+				parent: &self.span,
+				object = object.id,
+				interface = interface.name,
+				opcode,
+				message = interface.event(opcode).map(|event| event.name).unwrap_or("<unknown>"),
+				discarded = action != MiddlewareAction::Pass,
+				"event {:?}", event,
+			);
+		}
+
 		let dyn_msg = DynMessage::new(object.id, opcode, args);
+		if crate::server::wayland_debug() {
+			let interface = object.interface.get();
+			eprintln!("{}", DebugMessage::new(&interface, &dyn_msg, MessageSide::Event)
+				.outgoing(true)
+				.discarded(discarded)
+				.object_interface(|id| self.object_interface_name(id)));
+		}
+
+		match action {
+			MiddlewareAction::Pass => {},
+			MiddlewareAction::Drop => return Ok(()),
+			MiddlewareAction::Error { code, message } => {
+				self.post_error(sender, code, &message);
+				return Ok(());
+			},
+		}
+
 		let raw = dyn_msg.into_raw()?;
 
 		if crate::server::raw_event_debug() {
 			log::debug!(" -> client: {}, sender: {}, opcode: {}, len: {}\n\tcontents: {:?}", self.id(), raw.header.sender, raw.header.opcode, raw.header.msg_size, raw.data);
 		}
 
-		self.net.borrow_mut().try_send_message(raw)?;
+		let result = self.net.borrow_mut().try_send_message(raw);
+		if let Err(NetError::LimitExceeded(limit)) = result {
+			self.post_limit_error(limit);
+		}
+		result?;
 
 		Ok(())
 	}
 
-	pub(crate) fn remove_object(&self, object: Ref<Object>) -> Option<Owner<Object>> {
-		let owner = self.objects.borrow_mut().remove(object.handle());
+	pub fn limits(&self) -> ClientLimits {
+		self.net.borrow().limits
+	}
+
+	pub fn set_limits(&self, limits: ClientLimits) {
+		self.net.borrow_mut().limits = limits;
+	}
+
+	pub(crate) fn post_limit_error(&self, limit: ClientLimit) {
+		let display = self.display.borrow().clone().expect("Client display not set");
+		self.post_error(display.to_untyped(), limit.error_code().into(), &limit.to_string());
+	}
+
+	/// Sends a `wl_display.error` event about `object` to this client. The client is disconnected once its
+	/// outgoing events have been flushed, and no further requests from it are dispatched.
+	pub fn post_error(&self, object: Resource<Untyped>, code: u32, message: &str) {
+		if self.error_posted.replace(true) {
+			return;
+		}
+		self.net.borrow_mut().closing = true;
+
+		log::warn!("Posting error {} on {:?} to client {}: {}", code, object, self.id, message);
+		let display = self.display.borrow().clone().expect("Client display not set");
+		display.send_event(WlDisplayEvent::Error(wl_display::ErrorEvent {
+			object_id: object,
+			code,
+			message: CString::new(message.replace('\0', "")).unwrap().into_bytes_with_nul(),
+		}));
+	}
+
+	/// Sends a `wl_display.error` event about the connection itself, like a request to an object that doesn't exist.
+	pub(crate) fn post_display_error(&self, code: wl_display::Error, message: &str) {
+		let display = self.display.borrow().clone().expect("Client display not set");
+		self.post_error(display.to_untyped(), code.into(), message);
+	}
+
+	pub fn error_posted(&self) -> bool {
+		self.error_posted.get()
+	}
+
+	pub(crate) fn remove_object(&self, key: ObjectKey) -> Option<Rc<Object>> {
+		let object = self.objects.borrow_mut().remove(key)?;
 		let display = self.display.borrow().clone().expect("Client display not set");
 		let delete_id_event = wl_display::DeleteIdEvent {
 			id: object.id,
 		};
 		display.send_event(WlDisplayEvent::DeleteId(delete_id_event));
-		owner
+		Some(object)
+	}
+
+	/// Returns every live resource of this client with the interface `I`, in creation order.
+	pub fn resources<I: Interface>(&self) -> impl Iterator<Item=Resource<I>> {
+		self.resources_untyped().filter_map(|resource| resource.downcast::<I>())
+	}
+
+	pub fn resources_untyped(&self) -> impl Iterator<Item=Resource<Untyped>> {
+		// Collected up front so the object map isn't borrowed while the caller uses the resources
+		let resources = self.objects.borrow().entries().into_iter().map(|(key, _)| {
+			Resource::new_untyped(self.handle(), key)
+		}).collect::<Vec<_>>();
+		resources.into_iter()
 	}
 
 	pub fn find<I: Interface, F: Fn(Resource<I>) -> bool>(&self, f: F) -> Option<Resource<I>> {
@@ -208,29 +479,40 @@ impl Client {
 	}
 
 	pub fn find_untyped<F: Fn(Resource<Untyped>) -> bool>(&self, f: F) -> Option<Resource<Untyped>> {
-		self.objects.borrow().find(|object| {
-			let resource = Resource::new_untyped(self.handle(), object.handle());
-			f(resource)
-		}).map(|object| {
-			Resource::new_untyped(self.handle(), object.handle())
-		})
+		self.resources_untyped().find(|resource| f(resource.clone()))
 	}
 
 	pub fn find_by_id<I: Interface>(&self, id: u32) -> Option<Resource<I>> {
-		self.find(|resource| {
-			resource.object().get().unwrap().id == id
-		})
+		self.find_by_id_untyped(id)?.downcast()
 	}
 
 	pub fn find_by_id_untyped(&self, id: u32) -> Option<Resource<Untyped>> {
-		self.find_untyped(|resource| {
-			resource.object().get().unwrap().id == id
-		})
+		let key = self.objects.borrow().key_of(id)?;
+		Some(Resource::new_untyped(self.handle(), key))
+	}
+
+	pub(crate) fn object_interface_name(&self, id: u32) -> Option<&'static str> {
+		self.object_interface(id).map(|interface| interface.name)
+	}
+
+	pub(crate) fn object_interface(&self, id: u32) -> Option<DynInterface> {
+		let objects = self.objects.borrow();
+		objects.key_of(id).and_then(|key| objects.get(key)).map(|object| object.interface.get())
 	}
 
 	pub(crate) fn client_map(&self) -> ClientMap {
 		ClientMap {
 			handle: self.handle(),
+			version: None,
+		}
+	}
+
+	/// A client map for parsing a request sent to an object with the given version. Objects created by the request
+	/// get the same version.
+	pub(crate) fn client_map_for(&self, version: u32) -> ClientMap {
+		ClientMap {
+			handle: self.handle(),
+			version: Some(version),
 		}
 	}
 }
@@ -238,47 +520,53 @@ impl Client {
 // TODO: rename this to something that more clearly means "a reference to a client's map of objects"
 // Right now the name seems like it means "a map of clients"
 pub struct ClientMap {
-	handle: Handle<Client>,
+	handle: ClientHandle,
+	// The version of the object a request was sent to, which new_ids in the request inherit
+	version: Option<u32>,
 }
 
-// TODO: review possibilites of the handle being null
 impl ClientMap {
 	pub fn try_get_object<I: Interface>(&self, id: u32) -> Option<Resource<I>> {
-		let client = self.handle.get().expect("Client was destroyed");
-		client.find_by_id(id)
+		self.handle.get().ok()?.find_by_id(id)
 	}
 
 	pub fn try_get_object_untyped(&self, id: u32) -> Option<Resource<Untyped>> {
-		let client = self.handle.get().expect("Client was destroyed");
-		client.find_by_id_untyped(id)
+		self.handle.get().ok()?.find_by_id_untyped(id)
 	}
 
 	pub fn try_get_id<I>(&self, resource: Resource<I>) -> Result<u32, IntoArgsError> {
-		let untyped = resource.to_untyped();
-		untyped.object().get().map(|object| object.id).ok_or(IntoArgsError::ResourceDoesntExist)
+		resource.object().map(|object| object.id).map_err(|_| IntoArgsError::ResourceDoesntExist)
 	}
 
 	pub fn add_new_id<I, R>(&self, id: u32) -> NewResource<I> where R: Message<ClientMap=ClientMap> + fmt::Debug, I: Interface<Request=R> + fmt::Debug + 'static {
-		let client = self.handle.get().expect("Client was destroyed");
 		let object = Object::new::<I, R>(id);
-		let object_owner = Owner::new(object);
-		let object_handle = object_owner.handle();
-		client.objects.borrow_mut().add(object_owner);
-		NewResource::new(self.handle.clone(), object_handle)
+		if let Some(version) = self.version {
+			object.version.set(version);
+		}
+		self.add_object(object)
+	}
+
+	pub fn add_new_id_untyped(&self, id: u32, title: Option<InterfaceTitle>) -> NewResource<Untyped> {
+		self.add_object(Object::new_untyped(id, title))
 	}
 
-	// TODO: accept InterfaceTitle?
-	pub fn add_new_id_untyped(&self, id: u32) -> NewResource<Untyped> {
-		let client = self.handle.get().expect("Client was destroyed");
-		let object = Object::new_untyped(id);
-		let object_owner = Owner::new(object);
-		let object_handle = object_owner.handle();
-		client.objects.borrow_mut().add(object_owner);
-		NewResource::new(self.handle.clone(), object_handle)
+	// A client that's gone, or has too many objects already, gets a resource that never resolves
+	fn add_object<I>(&self, object: Object) -> NewResource<I> {
+		let key = match self.handle.get() {
+			Ok(client) if client.objects.borrow().len() >= client.limits().max_objects => {
+				client.post_limit_error(ClientLimit::Objects);
+				// It never had a destructor to run
+				object.dispatcher.borrow_mut().take();
+				Key::dangling()
+			},
+			Ok(client) => client.objects.borrow_mut().add(object),
+			Err(_) => Key::dangling(),
+		};
+		NewResource::new(self.handle.clone(), key)
 	}
 
 	pub fn try_get_new_id<I>(&self, new_resource: &NewResource<I>) -> Result<(u32, InterfaceTitle), IntoArgsError> {
-		new_resource.object.get().map(|object| (object.id, object.interface.get().title())).ok_or(IntoArgsError::ResourceDoesntExist)
+		new_resource.object().map(|object| (object.id, object.interface.get().title())).map_err(|_| IntoArgsError::ResourceDoesntExist)
 	}
 }
 
@@ -289,16 +577,21 @@ impl ObjectImplementation<WlDisplay> for WlDisplayImplementation {
         match request {
 			WlDisplayRequest::Sync(sync) => {
 				let callback = sync.callback.register_fn((), |_, _, _| { }, |_, _| { });
+				// Clients never send callback data back, so it doesn't take up room in the serial history
+				let serial = this.client().get().ok()
+					.and_then(|client| client.serial_manager.get())
+					.map(|serial_manager| serial_manager.borrow_mut().next_serial())
+					.unwrap_or(0);
 				callback.send_event(WlCallbackEvent::Done(wl_callback::DoneEvent {
-					callback_data: 1, // TODO!: serial
+					callback_data: serial,
 				}));
 			},
 			WlDisplayRequest::GetRegistry(get_registry) => {
 				let registry = get_registry.registry.register((), WlRegistryImplementation);
-				let client = this.client();
-				let client = client.get().unwrap();
-				*client.registry.borrow_mut() = Some(registry.clone());
-				client.advertise_current_globals();
+				if let Ok(client) = this.client().get() {
+					*client.registry.borrow_mut() = Some(registry.clone());
+					client.advertise_current_globals();
+				}
 			},
 		}
 	}
@@ -311,14 +604,14 @@ impl ObjectImplementation<WlDisplay> for WlDisplayImplementation {
 pub struct WlRegistryImplementation;
 
 impl ObjectImplementation<WlRegistry> for WlRegistryImplementation {
-    fn handle(&mut self, _state: &mut State, this: Resource<WlRegistry>, request: WlRegistryRequest) {
+    fn handle(&mut self, state: &mut State, this: Resource<WlRegistry>, request: WlRegistryRequest) {
         match request {
 			WlRegistryRequest::Bind(bind) => {
-				let client = this.client();
-				let client = client.get().unwrap();
-				let global_manager = client.global_manager.get().unwrap();
-				let global_manager = global_manager.borrow();
-				global_manager.bind_global(bind.name, bind.id);
+				let global_manager = match this.client().get() {
+					Ok(client) => client.global_manager.clone(),
+					Err(_) => return,
+				};
+				global::bind_global(global_manager, state, this.to_untyped(), bind.name, bind.id);
 			}
 		}
 	}
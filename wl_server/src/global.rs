@@ -1,18 +1,22 @@
 use std::{
 	fmt,
 	cell::{RefCell},
+	rc::{Rc},
 };
 
 use loaner::{Owner, Handle};
 use thiserror::{Error};
 
 use wl_common::{
-	interface::{Interface, DynInterface},
+	interface::{Interface, DynInterface, InterfaceTitle, ANONYMOUS_NAME},
 };
 
 use crate::{
-	resource::{NewResource, Untyped},
-	client::{ClientManager},
+	server::{State},
+	resource::{Resource, NewResource, Untyped},
+	client::{Client, ClientManager, ClientHandle},
+	arena::{DanglingError},
+	protocol::{wl_display},
 };
 
 #[derive(Debug)]
@@ -43,7 +47,7 @@ impl GlobalManager {
 		let global = Global::new(name, global_implementation);
 		let client_manager = self.client_manager.get().expect("Client manager destroyed");
 		let client_manager = client_manager.borrow_mut();
-		for client in &client_manager.clients {
+		for client in client_manager.live_clients() {
 			client.advertise_global::<I>(name);
 		}
 		let owner = Owner::new(global);
@@ -52,22 +56,104 @@ impl GlobalManager {
 		handle
 	}
 
-	pub(crate) fn bind_global(&self, name: u32, this: NewResource<Untyped>) {
-		if let Some(global) = self.globals.iter().find(|global| global.name == name) {
-			this.object.get().unwrap().interface.set(global.interface);
-			match global.dispatcher.borrow_mut().dispatch(this) {
-				Ok(_) => {},
-				Err(e) => {
-					log::error!("Failed to bind global: {}", e);
-				}
-			}
-		} else {
-			log::error!("Attempted to bind global that doesn't exist");
+	pub(crate) fn globals(&self) -> impl Iterator<Item=Handle<Global>> + '_ {
+		self.globals.iter().map(|owner| owner.handle())
+	}
+
+	pub(crate) fn find(&self, name: u32) -> Option<Handle<Global>> {
+		self.globals.iter().find(|global| global.name == name).map(|owner| owner.handle())
+	}
+}
+
+// This takes a handle instead of `&self` so that the global manager isn't borrowed while the bind handler runs,
+// which lets handlers register new globals.
+pub(crate) fn bind_global(global_manager: Handle<RefCell<GlobalManager>>, state: &mut State, registry: Resource<Untyped>, name: u32, this: NewResource<Untyped>) {
+	let object = match this.object() {
+		Ok(object) => object,
+		Err(_) => return,
+	};
+	let requested = object.requested.clone().unwrap_or_else(|| InterfaceTitle::new(ANONYMOUS_NAME, 0));
+
+	let global_handle = global_manager.get().and_then(|global_manager| global_manager.borrow().find(name));
+	let global = match global_handle.as_ref().and_then(|global| global.get()) {
+		Some(global) => global,
+		None => {
+			registry.post_error(wl_display::Error::InvalidObject, &format!("invalid global {} ({})", requested.name, name));
+			return;
 		}
+	};
+
+	// These checks and messages follow libwayland's registry_bind
+	if requested.name != global.interface.name {
+		registry.post_error(wl_display::Error::InvalidObject, &format!("invalid interface for global {}: have {}, wanted {}", name, requested.name, global.interface.name));
+		return;
+	}
+	if requested.version == 0 {
+		registry.post_error(wl_display::Error::InvalidObject, &format!("invalid version for global {} ({}): 0 is not a valid version", requested.name, name));
+		return;
+	}
+	if requested.version > global.interface.version {
+		registry.post_error(wl_display::Error::InvalidObject, &format!("invalid version for global {} ({}): have {}, wanted {}", requested.name, name, global.interface.version, requested.version));
+		return;
 	}
 
-	pub(crate) fn globals(&self) -> impl Iterator<Item=Handle<Global>> + '_ {
-		self.globals.iter().map(|owner| owner.handle())
+	object.interface.set(global.interface);
+	let version = object.version.get();
+	// The resource is forgotten again when it's destroyed, so that `bound` doesn't grow for as long as the global lives
+	let bound_global = global_handle.clone().unwrap();
+	object.destroy_listeners.borrow_mut().add(Box::new(move |_, resource: Resource<Untyped>| {
+		if let Some(global) = bound_global.get() {
+			global.bound.borrow_mut().retain(|bound| !bound.is(&resource));
+		}
+	}));
+	drop(object);
+	global.bound.borrow_mut().push(Resource::new_untyped(this.client.clone(), this.object));
+
+	let context = BindContext {
+		state,
+		client: this.client.clone(),
+		version,
+		global_manager,
+	};
+	let result = global.dispatcher.borrow_mut().dispatch(context, this);
+	match result {
+		Ok(_) => {},
+		Err(e) => {
+			log::error!("Failed to bind global: {}", e);
+		}
+	}
+}
+
+/// Everything a global implementation gets to know about a client binding it.
+pub struct BindContext<'a> {
+	pub state: &'a mut State,
+	pub client: ClientHandle,
+	/// The version the client asked for in `wl_registry.bind`.
+	pub version: u32,
+	global_manager: Handle<RefCell<GlobalManager>>,
+}
+
+impl<'a> BindContext<'a> {
+	pub fn client(&self) -> Result<Rc<Client>, DanglingError> {
+		self.client.get()
+	}
+
+	/// Registers a new global from inside a bind handler. The global is advertised to every client, including the
+	/// one currently binding.
+	pub fn register_global<I: Interface + 'static, Impl: GlobalImplementation<I> + 'static>(&mut self, global_implementation: Impl) -> Option<Handle<Global>> {
+		let global_manager = self.global_manager.get()?;
+		let handle = global_manager.borrow_mut().add_global(global_implementation);
+		Some(handle)
+	}
+}
+
+impl<'a> fmt::Debug for BindContext<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("BindContext")
+			.field("state", &self.state)
+			.field("client", &self.client)
+			.field("version", &self.version)
+			.finish()
 	}
 }
 
@@ -77,6 +163,7 @@ pub struct Global {
 	// I don't think this field is even necessary because there are no message schemas
 	pub(crate) interface: DynInterface,
 	pub(crate) dispatcher: RefCell<GlobalDispatcher>,
+	pub(crate) bound: RefCell<Vec<Resource<Untyped>>>,
 }
 
 impl Global {
@@ -85,8 +172,18 @@ impl Global {
 			name,
 			interface: I::as_dyn(),
 			dispatcher: RefCell::new(GlobalDispatcher::new(global_implementation)),
+			bound: RefCell::new(Vec::new()),
 		}
 	}
+
+	pub fn name(&self) -> u32 {
+		self.name
+	}
+
+	/// Returns every live resource that a client created by binding this global.
+	pub fn bound_resources(&self) -> impl Iterator<Item=Resource<Untyped>> {
+		self.bound.borrow().clone().into_iter()
+	}
 }
 
 pub(crate) struct GlobalDispatcher {
@@ -103,8 +200,8 @@ impl GlobalDispatcher {
 		}
 	}
 
-	pub fn dispatch(&mut self, this: NewResource<Untyped>) -> Result<(), GlobalDispatchError> {
-		self.implementation.dispatch(this)
+	pub fn dispatch(&mut self, context: BindContext, this: NewResource<Untyped>) -> Result<(), GlobalDispatchError> {
+		self.implementation.dispatch(context, this)
 	}
 }
 
@@ -117,17 +214,17 @@ impl fmt::Debug for GlobalDispatcher {
 }
 
 pub trait GlobalImplementation<I: Interface> {
-	fn handle(&mut self, this: NewResource<I>);
+	fn handle(&mut self, context: BindContext, this: NewResource<I>);
 }
 
-impl<I: Interface, F: FnMut(NewResource<I>)> GlobalImplementation<I> for F {
-    fn handle(&mut self, this: NewResource<I>) {
-        (self)(this)
+impl<I: Interface, F: FnMut(BindContext, NewResource<I>)> GlobalImplementation<I> for F {
+    fn handle(&mut self, context: BindContext, this: NewResource<I>) {
+        (self)(context, this)
     }
 }
 
 pub trait RawGlobalImplementation {
-	fn dispatch(&mut self, this: NewResource<Untyped>) -> Result<(), GlobalDispatchError>;
+	fn dispatch(&mut self, context: BindContext, this: NewResource<Untyped>) -> Result<(), GlobalDispatchError>;
 }
 
 pub struct RawGlobalImplementationConcrete<I: Interface> {
@@ -136,9 +233,9 @@ pub struct RawGlobalImplementationConcrete<I: Interface> {
 }
 
 impl<I: Interface> RawGlobalImplementation for RawGlobalImplementationConcrete<I> {
-    fn dispatch(&mut self, this: NewResource<Untyped>) -> Result<(), GlobalDispatchError> {
+    fn dispatch(&mut self, context: BindContext, this: NewResource<Untyped>) -> Result<(), GlobalDispatchError> {
 		let typed = this.downcast::<I>().ok_or(GlobalDispatchError::TypeMismatch)?;
-		self.typed_implementation.handle(typed);
+		self.typed_implementation.handle(context, typed);
 		Ok(())
     }
 }
@@ -147,4 +244,29 @@ impl<I: Interface> RawGlobalImplementation for RawGlobalImplementationConcrete<I
 pub enum GlobalDispatchError {
 	#[error("Attempted to dispatch a request to an object with the wrong type")]
 	TypeMismatch,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use crate::{
+		Server, BindContext, NewResource,
+		protocol::*,
+		testing::{TestClient},
+	};
+
+	#[test]
+	fn destroyed_resources_are_no_longer_bound() {
+		let mut server = Server::new_without_socket(());
+		let global = server.register_global::<WlCompositor, _>(|_: BindContext, new_resource: NewResource<WlCompositor>| {
+			new_resource.register_fn((), |_, _, _| {}, |_, _| {});
+		});
+		for _ in 0..2 {
+			let mut client = TestClient::connect(&mut server, ());
+			client.bind_global::<WlCompositor>(&mut server, 1).unwrap();
+			assert_eq!(global.get().unwrap().bound.borrow().len(), 1);
+
+			drop(client);
+			server.dispatch(|_| ()).unwrap();
+			assert_eq!(global.get().unwrap().bound.borrow().len(), 0);
+		}
+	}
+}
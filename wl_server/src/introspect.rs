@@ -0,0 +1,337 @@
+//! Structured snapshots of a running server, for inspecting a compositor without attaching a debugger. Take one with
+//! `Server::snapshot`, or serve them as JSON with `Server::open_debug_socket`.
+
+use std::{
+	os::unix::{net::{UnixListener, UnixStream}, io::{RawFd, AsRawFd}},
+	path::{Path, PathBuf},
+	io::{self, Write},
+	time::{Duration, Instant},
+	cell::{RefCell},
+	rc::{Rc},
+	fs,
+	fmt,
+};
+
+use serde::{Serialize};
+
+use crate::{
+	client::{Client},
+	global::{Global},
+	net::{self, Credentials},
+};
+
+/// How long a debug socket connection may take to read a snapshot before it's dropped.
+const DEBUG_SOCKET_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How many debug socket connections may be waiting for the rest of their snapshot at once.
+const MAX_DEBUG_CONNECTIONS: usize = 16;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerSnapshot {
+	pub clients: Vec<ClientSnapshot>,
+	pub globals: Vec<GlobalSnapshot>,
+}
+
+impl ServerSnapshot {
+	pub fn to_json(&self) -> String {
+		serde_json::to_string_pretty(self).expect("Failed to serialize server snapshot")
+	}
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientSnapshot {
+	pub id: u32,
+	pub credentials: Option<Credentials>,
+	/// Whether the client was sent a protocol error and is about to be disconnected.
+	pub error_posted: bool,
+	pub queues: QueueSizes,
+	pub objects: Vec<ObjectSnapshot>,
+}
+
+/// What's buffered for a client: requests that were read but not handled yet, and events that couldn't be sent yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct QueueSizes {
+	pub incoming_bytes: usize,
+	pub incoming_fds: usize,
+	pub outgoing_bytes: usize,
+	pub outgoing_fds: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ObjectSnapshot {
+	pub id: u32,
+	pub interface: &'static str,
+	pub version: u32,
+	/// The type name of the data the object was registered with.
+	pub data_type: &'static str,
+	pub pending_destroy: bool,
+	pub parent: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GlobalSnapshot {
+	pub name: u32,
+	pub interface: &'static str,
+	pub version: u32,
+	pub bound: Vec<BoundResource>,
+}
+
+/// A resource created by binding a global.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BoundResource {
+	pub client: u32,
+	pub object: u32,
+}
+
+pub(crate) fn snapshot_client(client: &Client) -> ClientSnapshot {
+	let objects = client.objects.borrow();
+	let objects = objects.entries().into_iter().map(|(_, object)| {
+		ObjectSnapshot {
+			id: object.id,
+			interface: object.interface.get().name,
+			version: object.version.get(),
+			data_type: object.data_type.get(),
+			pending_destroy: object.destroy.get(),
+			parent: object.parent.get().and_then(|parent| objects.get(parent)).map(|parent| parent.id),
+		}
+	}).collect();
+
+	let net = client.net.borrow();
+	ClientSnapshot {
+		id: client.id(),
+		credentials: net.credentials(),
+		error_posted: client.error_posted(),
+		queues: net.queue_sizes(),
+		objects,
+	}
+}
+
+pub(crate) fn snapshot_global(global: &Global) -> GlobalSnapshot {
+	let bound = global.bound_resources().filter_map(|resource| {
+		let client = resource.client().get().ok()?.id();
+		let object = resource.object().ok()?.id;
+		Some(BoundResource {
+			client,
+			object,
+		})
+	}).collect();
+
+	GlobalSnapshot {
+		name: global.name,
+		interface: global.interface.name,
+		version: global.interface.version,
+		bound,
+	}
+}
+
+impl fmt::Display for ServerSnapshot {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for client in &self.clients {
+			write!(f, "Client {}", client.id)?;
+			if let Some(credentials) = client.credentials {
+				write!(f, " (pid {}, uid {})", credentials.pid, credentials.uid)?;
+			}
+			writeln!(f, ":")?;
+			for object in &client.objects {
+				write!(f, "\t{}@{} v{} {}", object.interface, object.id, object.version, object.data_type)?;
+				if object.pending_destroy {
+					write!(f, " (pending destroy)")?;
+				}
+				writeln!(f)?;
+			}
+		}
+		writeln!(f, "Globals:")?;
+		for global in &self.globals {
+			write!(f, "\t{} {} v{}", global.name, global.interface, global.version)?;
+			for bound in &global.bound {
+				write!(f, " {}:{}", bound.client, bound.object)?;
+			}
+			writeln!(f)?;
+		}
+		Ok(())
+	}
+}
+
+/// A socket that writes a JSON snapshot to every connection and closes it, so `socat - UNIX-CONNECT:<path>` prints
+/// the server's state. It's served during `Server::dispatch`, and connections that can't take the whole snapshot at
+/// once are finished in later dispatches.
+#[derive(Debug)]
+pub(crate) struct DebugSocket {
+	listener: UnixListener,
+	path: PathBuf,
+	connections: RefCell<Vec<DebugConnection>>,
+}
+
+#[derive(Debug)]
+struct DebugConnection {
+	stream: UnixStream,
+	json: Rc<[u8]>,
+	written: usize,
+	accepted: Instant,
+}
+
+impl DebugConnection {
+	/// Writes as much of the snapshot as the connection takes. Returns `true` once it's done with the connection.
+	fn write(&mut self) -> bool {
+		while self.written < self.json.len() {
+			if self.accepted.elapsed() > DEBUG_SOCKET_WRITE_TIMEOUT {
+				log::warn!("Debug socket connection didn't read its snapshot in time");
+				return true;
+			}
+			match self.stream.write(&self.json[self.written..]) {
+				Ok(0) => return true,
+				Ok(bytes) => self.written += bytes,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+				Err(e) => {
+					log::warn!("Failed to write to debug socket connection: {}", e);
+					return true;
+				},
+			}
+		}
+		true
+	}
+}
+
+impl DebugSocket {
+	pub(crate) fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+		let path = path.as_ref().to_owned();
+		let listener = net::bind_listener(&path)?;
+		Ok(Self {
+			listener,
+			path,
+			connections: RefCell::new(Vec::new()),
+		})
+	}
+
+	pub(crate) fn fd(&self) -> RawFd {
+		self.listener.as_raw_fd()
+	}
+
+	/// The connections that are still waiting to be written the rest of their snapshot.
+	pub(crate) fn pending_fds(&self) -> Vec<RawFd> {
+		self.connections.borrow().iter().map(|connection| connection.stream.as_raw_fd()).collect()
+	}
+
+	/// Answers every pending connection and continues writing to the ones that were answered before. The snapshot is
+	/// only taken if someone connected.
+	pub(crate) fn serve<F: FnOnce() -> ServerSnapshot>(&self, snapshot: F) {
+		let mut connections = self.connections.borrow_mut();
+		let mut snapshot = Some(snapshot);
+		let mut json: Option<Rc<[u8]>> = None;
+		loop {
+			let stream = match self.listener.accept() {
+				Ok((stream, _addr)) => stream,
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+				Err(e) => {
+					log::warn!("Failed to accept debug socket connection: {}", e);
+					break;
+				},
+			};
+			if connections.len() >= MAX_DEBUG_CONNECTIONS {
+				log::warn!("Too many debug socket connections, dropping one");
+				continue;
+			}
+			if let Err(e) = stream.set_nonblocking(true) {
+				log::warn!("Failed to set debug socket connection as non-blocking: {}", e);
+				continue;
+			}
+			let json = json.get_or_insert_with(|| {
+				let mut json = snapshot.take().unwrap()().to_json().into_bytes();
+				json.push(b'\n');
+				json.into()
+			});
+			connections.push(DebugConnection {
+				stream,
+				json: Rc::clone(json),
+				written: 0,
+				accepted: Instant::now(),
+			});
+		}
+
+		let mut i = 0;
+		while i < connections.len() {
+			if connections[i].write() {
+				connections.swap_remove(i);
+			} else {
+				i += 1;
+			}
+		}
+	}
+}
+
+impl Drop for DebugSocket {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		os::unix::{net::{UnixListener, UnixStream}},
+		path::{PathBuf},
+		io::{Read},
+		env,
+		process,
+	};
+
+	use crate::{
+		Server, BindContext, NewResource,
+		protocol::*,
+		testing::{TestClient},
+	};
+
+	struct CompositorData;
+
+	#[test]
+	fn snapshot_lists_objects_and_bound_globals() {
+		let mut server = Server::new_without_socket(());
+		server.register_global::<WlCompositor, _>(|_context: BindContext, new_resource: NewResource<WlCompositor>| {
+			new_resource.register_fn(CompositorData, |_, _, _| {}, |_, _| {});
+		});
+		let mut client = TestClient::connect(&mut server, ());
+		client.bind_global::<WlCompositor>(&mut server, 3).unwrap();
+
+		let snapshot = server.snapshot();
+		let objects = snapshot.clients[0].objects.iter().map(|object| (object.id, object.interface, object.version)).collect::<Vec<_>>();
+		assert_eq!(objects, vec![(1, "wl_display", 1), (2, "wl_registry", 1), (3, "wl_compositor", 3)]);
+		assert!(snapshot.clients[0].objects[2].data_type.ends_with("CompositorData"));
+		assert_eq!(snapshot.globals[0].bound.len(), 1);
+		assert_eq!((snapshot.globals[0].bound[0].client, snapshot.globals[0].bound[0].object), (snapshot.clients[0].id, 3));
+		assert!(snapshot.to_json().contains("\"interface\": \"wl_compositor\""));
+	}
+
+	fn socket_path(name: &str) -> PathBuf {
+		env::temp_dir().join(format!("wl-test-{}-{}", process::id(), name))
+	}
+
+	#[test]
+	fn debug_socket_replaces_stale_sockets_only() {
+		let path = socket_path("debug-stale");
+		drop(UnixListener::bind(&path).unwrap());
+		let mut server = Server::new_without_socket(());
+		server.open_debug_socket(&path).unwrap();
+
+		let mut other = Server::new_without_socket(());
+		assert!(other.open_debug_socket(&path).is_err());
+		server.close_debug_socket();
+		assert!(!path.exists());
+	}
+
+	#[test]
+	fn debug_socket_writes_snapshots_from_dispatch() {
+		let path = socket_path("debug-serve");
+		let mut server = Server::new_without_socket(());
+		server.open_debug_socket(&path).unwrap();
+		let mut streams = vec![UnixStream::connect(&path).unwrap(), UnixStream::connect(&path).unwrap()];
+		server.dispatch(|_| ()).unwrap();
+		assert!(server.debug_connection_fds().is_empty());
+
+		for stream in &mut streams {
+			let mut json = String::new();
+			stream.read_to_string(&mut json).unwrap();
+			assert!(json.ends_with("}\n"));
+			assert!(json.contains("\"globals\""));
+		}
+	}
+}
@@ -5,14 +5,30 @@ pub mod resource;
 pub mod global;
 pub mod object;
 pub mod net;
+pub mod serial;
+pub mod middleware;
+pub mod limits;
+pub mod replay;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod shadow;
+pub mod introspect;
+pub mod metrics;
+pub mod remote;
+pub mod arena;
+pub mod shm;
+#[cfg(feature = "tokio")]
+mod async_server;
 pub use loaner;
 
 pub use crate::{
 	server::{Server},
-	client::{Client},
+	remote::{ServerHandle, StopHandle, RemoteResource},
+	client::{Client, ClientHandle},
 	resource::{Resource, NewResource, Untyped},
-	global::{Global},
+	global::{Global, BindContext},
 	object::{ObjectImplementation},
+	arena::{DanglingError},
 	loaner::{Owner, Handle},
 };
 
@@ -0,0 +1,167 @@
+use std::{
+	fmt,
+};
+
+use crate::{
+	protocol::{wl_display},
+};
+
+/// Quotas applied to each client. A client that exceeds any of them is sent a protocol error and disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientLimits {
+	/// The maximum number of live objects, including the display.
+	pub max_objects: usize,
+	/// The maximum number of file descriptors received from the client that haven't been consumed by a request yet.
+	pub max_fds: usize,
+	/// The maximum number of requests handled for the client in a single `Server::dispatch`.
+	pub max_requests_per_dispatch: usize,
+	/// The maximum number of bytes queued for the client while its socket isn't accepting more data.
+	pub max_outgoing_bytes: usize,
+}
+
+impl Default for ClientLimits {
+	fn default() -> Self {
+		Self {
+			max_objects: 16 * 1024,
+			max_fds: 64,
+			max_requests_per_dispatch: 4 * 1024,
+			max_outgoing_bytes: 1024 * 1024, // 1 MiB
+		}
+	}
+}
+
+/// Which of the `ClientLimits` a client exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientLimit {
+	Objects,
+	Fds,
+	Requests,
+	OutgoingBytes,
+}
+
+impl ClientLimit {
+	pub(crate) fn error_code(self) -> wl_display::Error {
+		match self {
+			ClientLimit::Objects | ClientLimit::Fds | ClientLimit::OutgoingBytes => wl_display::Error::NoMemory,
+			ClientLimit::Requests => wl_display::Error::Implementation,
+		}
+	}
+}
+
+impl fmt::Display for ClientLimit {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ClientLimit::Objects => write!(f, "client exceeded the maximum number of objects"),
+			ClientLimit::Fds => write!(f, "client exceeded the maximum number of pending file descriptors"),
+			ClientLimit::Requests => write!(f, "client exceeded the maximum number of requests per dispatch"),
+			ClientLimit::OutgoingBytes => write!(f, "client exceeded the maximum size of buffered events"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use wl_common::{
+		wire::{DynArgument},
+	};
+
+	use crate::{
+		Server,
+		protocol::*,
+		testing::{TestClient},
+	};
+	use super::*;
+
+	fn connect(server: &mut Server, limits: ClientLimits) -> TestClient {
+		server.set_default_client_limits(limits);
+		TestClient::connect(server, ())
+	}
+
+	fn sync(client: &mut TestClient) {
+		let callback = client.new_id::<WlCallback>();
+		client.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+	}
+
+	#[test]
+	fn requests_creating_too_many_objects_are_errors() {
+		let mut server = Server::new_without_socket(());
+		let mut client = connect(&mut server, ClientLimits {
+			max_objects: 2,
+			..ClientLimits::default()
+		});
+		client.get_registry().unwrap();
+		sync(&mut client);
+		client.dispatch(&mut server).unwrap();
+
+		client.expect_error(1, wl_display::Error::NoMemory);
+		client.expect_disconnected();
+	}
+
+	#[test]
+	fn objects_created_by_the_server_count_towards_the_limit() {
+		let mut server = Server::new_without_socket(());
+		let mut client = connect(&mut server, ClientLimits {
+			max_objects: 1,
+			..ClientLimits::default()
+		});
+
+		let new_resource = client.client().get().unwrap().client_map().add_new_id::<WlCallback, _>(0xff00_0000);
+		assert!(new_resource.object().is_err());
+		client.dispatch(&mut server).unwrap();
+		client.expect_error(1, wl_display::Error::NoMemory);
+		client.expect_disconnected();
+	}
+
+	#[test]
+	fn too_many_requests_in_one_dispatch_are_errors() {
+		let mut server = Server::new_without_socket(());
+		let mut client = connect(&mut server, ClientLimits {
+			max_requests_per_dispatch: 2,
+			..ClientLimits::default()
+		});
+		for _ in 0..3 {
+			sync(&mut client);
+		}
+		client.dispatch(&mut server).unwrap();
+
+		client.expect_error(1, wl_display::Error::Implementation);
+		client.expect_disconnected();
+	}
+
+	#[test]
+	fn clients_that_stop_reading_are_disconnected_without_losing_the_stream() {
+		let mut server = Server::new_without_socket(());
+		let mut client = connect(&mut server, ClientLimits {
+			max_outgoing_bytes: 4096,
+			..ClientLimits::default()
+		});
+		client.get_registry().unwrap();
+		client.dispatch(&mut server).unwrap();
+
+		// Fills the socket and then the outgoing queue, with messages that don't evenly divide the socket buffer
+		let registry = server.resources::<WlRegistry>().next().unwrap();
+		let server_client = client.client().get().unwrap();
+		let mut sent = 0;
+		while !server_client.error_posted() {
+			registry.send_event(WlRegistryEvent::GlobalRemove(wl_registry::GlobalRemoveEvent {
+				name: sent,
+			}));
+			sent += 1;
+			assert!(sent < 1_000_000, "the outgoing limit was never reached");
+		}
+		drop(server_client);
+
+		// Every event that made it into the stream arrives whole, followed by the error
+		client.read_events().unwrap();
+		client.dispatch(&mut server).unwrap();
+		let events = client.take_events();
+		let (error, events) = events.split_last().unwrap();
+		for (name, event) in events.iter().enumerate() {
+			assert_eq!((event.sender, event.name()), (2, "global_remove"));
+			assert_eq!(event.args, vec![DynArgument::Uint(name as u32)]);
+		}
+		assert_eq!((error.sender, error.name()), (1, "error"));
+		assert_eq!(error.args[1], DynArgument::Uint(wl_display::Error::NoMemory.into()));
+		client.expect_disconnected();
+	}
+}
@@ -0,0 +1,315 @@
+//! Traffic counters for finding out which client is keeping the compositor busy. Every client counts what it sends
+//! and receives from the moment it connects, and `Server::metrics` collects the counters of every connected client
+//! along with request totals the server keeps for its whole lifetime.
+
+use std::{
+	collections::{HashMap},
+	fmt::{Write},
+	time::{Duration},
+};
+
+use serde::{Serialize};
+
+use crate::{
+	net::{Credentials},
+};
+
+/// The upper bounds of the dispatch latency histogram buckets, in microseconds. Anything slower goes in a last bucket.
+pub const LATENCY_BUCKETS_MICROS: [u64; 8] = [10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000];
+
+type ClientField = fn(&ClientMetrics) -> u64;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerMetrics {
+	pub clients: Vec<ClientMetrics>,
+	/// The requests of every client that was ever connected added up, by interface and opcode.
+	pub requests: Vec<RequestMetrics>,
+}
+
+impl ServerMetrics {
+	pub fn to_json(&self) -> String {
+		serde_json::to_string_pretty(self).expect("Failed to serialize server metrics")
+	}
+
+	/// Formats the metrics in the Prometheus text format.
+	pub fn to_prometheus(&self) -> String {
+		let mut out = String::new();
+		let fields: [(&str, &str, ClientField); 8] = [
+			("wl_client_messages_received_total", "counter", |client| client.messages_in),
+			("wl_client_bytes_received_total", "counter", |client| client.bytes_in),
+			("wl_client_fds_received_total", "counter", |client| client.fds_in),
+			("wl_client_messages_sent_total", "counter", |client| client.messages_out),
+			("wl_client_bytes_sent_total", "counter", |client| client.bytes_out),
+			("wl_client_fds_sent_total", "counter", |client| client.fds_out),
+			("wl_client_outgoing_bytes_high_water", "gauge", |client| client.outgoing_high_water.bytes as u64),
+			("wl_client_outgoing_fds_high_water", "gauge", |client| client.outgoing_high_water.fds as u64),
+		];
+		for &(name, kind, field) in &fields {
+			writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+			for client in &self.clients {
+				writeln!(out, "{}{{{}}} {}", name, client.labels(), field(client)).unwrap();
+			}
+		}
+
+		writeln!(out, "# TYPE wl_client_requests_total counter").unwrap();
+		for client in &self.clients {
+			for request in &client.requests {
+				writeln!(out, "wl_client_requests_total{{{},{}}} {}", client.labels(), request.labels(), request.count).unwrap();
+			}
+		}
+
+		writeln!(out, "# TYPE wl_request_dispatch_seconds histogram").unwrap();
+		for request in &self.requests {
+			request.dispatch_latency.write_prometheus(&mut out, "wl_request_dispatch_seconds", &request.labels());
+		}
+
+		out
+	}
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientMetrics {
+	pub client: u32,
+	pub credentials: Option<Credentials>,
+	pub messages_in: u64,
+	pub bytes_in: u64,
+	pub fds_in: u64,
+	/// Events sent or queued for the client.
+	pub messages_out: u64,
+	pub bytes_out: u64,
+	pub fds_out: u64,
+	/// The most that was ever queued for the client while its socket wasn't accepting more data.
+	pub outgoing_high_water: QueueHighWater,
+	pub requests: Vec<RequestMetrics>,
+	/// How long the handlers of all the client's requests took.
+	pub dispatch_latency: LatencyHistogram,
+}
+
+impl ClientMetrics {
+	fn labels(&self) -> String {
+		match self.credentials {
+			Some(credentials) => format!("client=\"{}\",pid=\"{}\"", self.client, credentials.pid),
+			None => format!("client=\"{}\"", self.client),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct QueueHighWater {
+	pub bytes: usize,
+	pub fds: usize,
+}
+
+/// The requests received with one opcode of one interface.
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestMetrics {
+	pub interface: &'static str,
+	pub opcode: u16,
+	pub request: &'static str,
+	/// Every request received, including ones that a middleware dropped or that weren't dispatched because of an
+	/// error.
+	pub count: u64,
+	pub dispatch_latency: LatencyHistogram,
+}
+
+impl RequestMetrics {
+	fn labels(&self) -> String {
+		format!("interface=\"{}\",request=\"{}\"", self.interface, self.request)
+	}
+}
+
+/// How long request handlers took to run, in the buckets of `LATENCY_BUCKETS_MICROS`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LatencyHistogram {
+	/// The number of handlers that took at most the bucket's bound and more than the previous bound. The last
+	/// count is of handlers slower than every bound.
+	pub buckets: [u64; LATENCY_BUCKETS_MICROS.len() + 1],
+	pub count: u64,
+	pub total_micros: u64,
+	pub max_micros: u64,
+}
+
+impl LatencyHistogram {
+	pub fn record(&mut self, latency: Duration) {
+		let micros = latency.as_micros() as u64;
+		let bucket = LATENCY_BUCKETS_MICROS.iter().position(|&bound| micros <= bound).unwrap_or(LATENCY_BUCKETS_MICROS.len());
+		self.buckets[bucket] += 1;
+		self.count += 1;
+		self.total_micros += micros;
+		self.max_micros = self.max_micros.max(micros);
+	}
+
+	pub fn merge(&mut self, other: &LatencyHistogram) {
+		for (bucket, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+			*bucket += other;
+		}
+		self.count += other.count;
+		self.total_micros += other.total_micros;
+		self.max_micros = self.max_micros.max(other.max_micros);
+	}
+
+	pub fn mean(&self) -> Option<Duration> {
+		if self.count == 0 {
+			return None;
+		}
+		Some(Duration::from_micros(self.total_micros / self.count))
+	}
+
+	// Prometheus buckets are cumulative
+	fn write_prometheus(&self, out: &mut String, name: &str, labels: &str) {
+		let mut cumulative = 0;
+		for (bound, count) in LATENCY_BUCKETS_MICROS.iter().zip(self.buckets.iter()) {
+			cumulative += count;
+			writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, *bound as f64 / 1_000_000.0, cumulative).unwrap();
+		}
+		writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count).unwrap();
+		writeln!(out, "{}_sum{{{}}} {}", name, labels, self.total_micros as f64 / 1_000_000.0).unwrap();
+		writeln!(out, "{}_count{{{}}} {}", name, labels, self.count).unwrap();
+	}
+}
+
+/// The counters kept by each `NetClient`.
+#[derive(Debug, Default)]
+pub(crate) struct TrafficCounters {
+	messages_in: u64,
+	bytes_in: u64,
+	fds_in: u64,
+	messages_out: u64,
+	bytes_out: u64,
+	fds_out: u64,
+	outgoing_high_water: QueueHighWater,
+	requests: RequestCounters,
+}
+
+impl TrafficCounters {
+	pub(crate) fn record_received(&mut self, bytes: usize, fds: usize) {
+		self.messages_in += 1;
+		self.bytes_in += bytes as u64;
+		self.fds_in += fds as u64;
+	}
+
+	pub(crate) fn record_sent(&mut self, bytes: usize, fds: usize) {
+		self.messages_out += 1;
+		self.bytes_out += bytes as u64;
+		self.fds_out += fds as u64;
+	}
+
+	pub(crate) fn record_queued(&mut self, bytes: usize, fds: usize) {
+		self.outgoing_high_water.bytes = self.outgoing_high_water.bytes.max(bytes);
+		self.outgoing_high_water.fds = self.outgoing_high_water.fds.max(fds);
+	}
+
+	pub(crate) fn record_request(&mut self, interface: &'static str, opcode: u16, request: &'static str) {
+		self.requests.record_request(interface, opcode, request);
+	}
+
+	pub(crate) fn record_dispatch(&mut self, interface: &'static str, opcode: u16, request: &'static str, latency: Duration) {
+		self.requests.record_dispatch(interface, opcode, request, latency);
+	}
+
+	pub(crate) fn metrics(&self, client: u32, credentials: Option<Credentials>) -> ClientMetrics {
+		let requests = self.requests.metrics();
+		let mut dispatch_latency = LatencyHistogram::default();
+		for request in &requests {
+			dispatch_latency.merge(&request.dispatch_latency);
+		}
+
+		ClientMetrics {
+			client,
+			credentials,
+			messages_in: self.messages_in,
+			bytes_in: self.bytes_in,
+			fds_in: self.fds_in,
+			messages_out: self.messages_out,
+			bytes_out: self.bytes_out,
+			fds_out: self.fds_out,
+			outgoing_high_water: self.outgoing_high_water,
+			requests,
+			dispatch_latency,
+		}
+	}
+}
+
+/// Request counts and dispatch latencies by interface and opcode. Each client keeps one, and the server keeps one for
+/// the requests of every client, so its totals don't drop when a client disconnects.
+#[derive(Debug, Default)]
+pub(crate) struct RequestCounters {
+	requests: HashMap<(&'static str, u16), RequestMetrics>,
+}
+
+impl RequestCounters {
+	pub(crate) fn record_request(&mut self, interface: &'static str, opcode: u16, request: &'static str) {
+		self.entry(interface, opcode, request).count += 1;
+	}
+
+	pub(crate) fn record_dispatch(&mut self, interface: &'static str, opcode: u16, request: &'static str, latency: Duration) {
+		self.entry(interface, opcode, request).dispatch_latency.record(latency);
+	}
+
+	fn entry(&mut self, interface: &'static str, opcode: u16, request: &'static str) -> &mut RequestMetrics {
+		self.requests.entry((interface, opcode)).or_insert_with(|| RequestMetrics {
+			interface,
+			opcode,
+			request,
+			count: 0,
+			dispatch_latency: LatencyHistogram::default(),
+		})
+	}
+
+	pub(crate) fn metrics(&self) -> Vec<RequestMetrics> {
+		sorted_requests(self.requests.values().cloned())
+	}
+}
+
+// Orders requests by interface and opcode so the output is stable
+fn sorted_requests<I: Iterator<Item=RequestMetrics>>(requests: I) -> Vec<RequestMetrics> {
+	let mut requests = requests.collect::<Vec<_>>();
+	requests.sort_by_key(|request| (request.interface, request.opcode));
+	requests
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		Server,
+		protocol::*,
+		testing::{TestClient},
+	};
+
+	#[test]
+	fn requests_and_events_are_counted() {
+		let mut server = Server::new_without_socket(());
+		let mut client = TestClient::connect(&mut server, ());
+		for _ in 0..3 {
+			let callback = client.new_id::<WlCallback>();
+			client.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+		}
+		client.dispatch(&mut server).unwrap();
+
+		let metrics = server.metrics();
+		let client_metrics = &metrics.clients[0];
+		assert_eq!(client_metrics.messages_in, 3);
+		assert_eq!(client_metrics.bytes_in, 3 * 12);
+		assert_eq!(client_metrics.messages_out, 3);
+		assert_eq!(client_metrics.dispatch_latency.count, 3);
+		assert_eq!(metrics.requests.len(), 1);
+		assert_eq!((metrics.requests[0].interface, metrics.requests[0].request, metrics.requests[0].count), ("wl_display", "sync", 3));
+		assert!(metrics.to_prometheus().contains("wl_client_requests_total{client=\""));
+	}
+
+	#[test]
+	fn request_totals_outlive_clients() {
+		let mut server = Server::new_without_socket(());
+		let mut client = TestClient::connect(&mut server, ());
+		let callback = client.new_id::<WlCallback>();
+		client.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+		client.dispatch(&mut server).unwrap();
+		drop(client);
+		server.dispatch(|_| ()).unwrap();
+
+		let metrics = server.metrics();
+		assert!(metrics.clients.is_empty());
+		assert_eq!((metrics.requests[0].request, metrics.requests[0].count), ("sync", 1));
+		assert_eq!(metrics.requests[0].dispatch_latency.count, 1);
+	}
+}
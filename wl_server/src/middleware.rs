@@ -0,0 +1,199 @@
+use std::{
+	fmt,
+};
+
+use wl_common::{
+	wire::{DynArgument},
+};
+
+use crate::{
+	client::{Client},
+	resource::{Resource, Untyped},
+};
+
+/// What should happen to a message after a middleware has looked at it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MiddlewareAction {
+	/// Hand the message to the next middleware, and eventually to its handler or the client.
+	Pass,
+	/// Silently discard the message.
+	Drop,
+	/// Discard the message and send the client a protocol error about the target object.
+	Error {
+		code: u32,
+		message: String,
+	},
+}
+
+/// Sits between the wire and the object implementations, seeing every request before it is dispatched and every
+/// event before it is sent. Arguments may be rewritten in place.
+///
+/// Dropping a request that creates objects leaves the client believing those objects exist, so be careful.
+pub trait Middleware {
+	fn request(&mut self, _client: &Client, _target: &Resource<Untyped>, _opcode: u16, _args: &mut Vec<DynArgument>) -> MiddlewareAction {
+		MiddlewareAction::Pass
+	}
+
+	fn event(&mut self, _client: &Client, _sender: &Resource<Untyped>, _opcode: u16, _args: &mut Vec<DynArgument>) -> MiddlewareAction {
+		MiddlewareAction::Pass
+	}
+}
+
+/// Identifies a middleware added with `Server::add_middleware`, so it can be removed again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MiddlewareId(u64);
+
+pub(crate) struct MiddlewareChain {
+	middlewares: Vec<(MiddlewareId, Box<dyn Middleware>)>,
+	next_id: u64,
+}
+
+impl MiddlewareChain {
+	pub fn new() -> Self {
+		Self {
+			middlewares: Vec::new(),
+			next_id: 0,
+		}
+	}
+
+	pub fn add(&mut self, middleware: Box<dyn Middleware>) -> MiddlewareId {
+		let id = MiddlewareId(self.next_id);
+		self.next_id += 1;
+		self.middlewares.push((id, middleware));
+		id
+	}
+
+	pub fn remove(&mut self, id: MiddlewareId) -> Option<Box<dyn Middleware>> {
+		let index = self.middlewares.iter().position(|&(middleware_id, _)| middleware_id == id)?;
+		Some(self.middlewares.remove(index).1)
+	}
+
+	pub fn request(&mut self, client: &Client, target: &Resource<Untyped>, opcode: u16, args: &mut Vec<DynArgument>) -> MiddlewareAction {
+		for (_, middleware) in &mut self.middlewares {
+			match middleware.request(client, target, opcode, args) {
+				MiddlewareAction::Pass => {},
+				action => return action,
+			}
+		}
+		MiddlewareAction::Pass
+	}
+
+	pub fn event(&mut self, client: &Client, sender: &Resource<Untyped>, opcode: u16, args: &mut Vec<DynArgument>) -> MiddlewareAction {
+		for (_, middleware) in &mut self.middlewares {
+			match middleware.event(client, sender, opcode, args) {
+				MiddlewareAction::Pass => {},
+				action => return action,
+			}
+		}
+		MiddlewareAction::Pass
+	}
+}
+
+impl fmt::Debug for MiddlewareChain {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("MiddlewareChain")
+			.field("middlewares", &self.middlewares.len())
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		Server, BindContext, NewResource,
+		protocol::*,
+		testing::{TestClient},
+	};
+	use super::*;
+
+	// Applies `action` to every wl_display request, and replaces the data of every wl_callback.done with `data`
+	struct DisplayMiddleware {
+		action: MiddlewareAction,
+		data: u32,
+	}
+
+	impl Middleware for DisplayMiddleware {
+		fn request(&mut self, _client: &Client, target: &Resource<Untyped>, _opcode: u16, _args: &mut Vec<DynArgument>) -> MiddlewareAction {
+			if target.downcast::<WlDisplay>().is_some() {
+				self.action.clone()
+			} else {
+				MiddlewareAction::Pass
+			}
+		}
+
+		fn event(&mut self, _client: &Client, sender: &Resource<Untyped>, _opcode: u16, args: &mut Vec<DynArgument>) -> MiddlewareAction {
+			if sender.downcast::<WlCallback>().is_some() {
+				args[0] = DynArgument::Uint(self.data);
+			}
+			MiddlewareAction::Pass
+		}
+	}
+
+	fn sync(action: MiddlewareAction) -> (Server, TestClient) {
+		let mut server = Server::new_without_socket(());
+		server.add_middleware(DisplayMiddleware {
+			action,
+			data: 42,
+		});
+		let mut client = TestClient::connect(&mut server, ());
+		let callback = client.new_id::<WlCallback>();
+		client.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+		client.dispatch(&mut server).unwrap();
+		(server, client)
+	}
+
+	#[test]
+	fn events_can_be_rewritten() {
+		let (_server, mut client) = sync(MiddlewareAction::Pass);
+		match client.expect_event::<WlCallback>(2) {
+			WlCallbackEvent::Done(done) => assert_eq!(done.callback_data, 42),
+		}
+	}
+
+	#[test]
+	fn dropped_requests_are_not_dispatched() {
+		let (server, mut client) = sync(MiddlewareAction::Drop);
+		client.expect_no_events();
+		assert!(!client.is_disconnected());
+		assert_eq!(server.clients().count(), 1);
+	}
+
+	#[test]
+	fn rejected_requests_post_an_error() {
+		let (_server, mut client) = sync(MiddlewareAction::Error {
+			code: 7,
+			message: String::from("no syncing"),
+		});
+		assert_eq!(client.expect_error(1, 7u32), "no syncing");
+		client.expect_disconnected();
+	}
+
+	#[test]
+	fn request_arguments_can_be_rewritten() {
+		struct RenameBind;
+
+		impl Middleware for RenameBind {
+			fn request(&mut self, _client: &Client, target: &Resource<Untyped>, _opcode: u16, args: &mut Vec<DynArgument>) -> MiddlewareAction {
+				if target.downcast::<WlRegistry>().is_some() {
+					args[0] = DynArgument::Uint(1);
+				}
+				MiddlewareAction::Pass
+			}
+		}
+
+		let mut server = Server::new_without_socket(());
+		server.register_global::<WlCompositor, _>(|_: BindContext, new_resource: NewResource<WlCompositor>| {
+			new_resource.register_fn((), |_, _, _| {}, |_, _| {});
+		});
+		server.add_middleware(RenameBind);
+		let mut client = TestClient::connect(&mut server, ());
+		let registry = client.get_registry().unwrap();
+		let id = client.new_id_untyped::<WlCompositor>(1);
+		let compositor = client.id(&id);
+		client.send(registry, WlRegistryRequest::Bind(wl_registry::BindRequest { name: 42, id })).unwrap();
+		client.dispatch(&mut server).unwrap();
+
+		assert!(!client.is_disconnected());
+		assert!(client.client().get().unwrap().find_by_id::<WlCompositor>(compositor).is_some());
+	}
+}
@@ -1,22 +1,33 @@
 use std::{
-	os::unix::{net::{UnixListener,  UnixStream}, io::{RawFd, AsRawFd}},
-	io,
+	os::unix::{net::{UnixListener,  UnixStream}, io::{RawFd, AsRawFd}, fs::{FileTypeExt}},
+	io::{self, Write},
+	fs::{self, File},
+	path::{Path, PathBuf},
+	cell::{RefCell},
+	rc::{Rc},
+	fmt,
+	time::{SystemTime, Duration},
 };
 
 use nix::{
 	poll,
 	errno::Errno,
-	sys::{socket, uio::{IoVec}},
+	sys::{socket, stat, uio::{self, IoVec}},
 };
 use thiserror::{Error};
-use loaner::{Handle};
+use serde::{Serialize};
 
 use wl_common::{
 	wire::{RawMessage, MessageHeader, ArgumentType},
+	interface::{MessageSide},
+	capture::{CaptureWriter, CaptureRecord, CaptureError},
 };
 
 use crate::{
-	client::{Client, ClientManager},
+	client::{Client, ClientManager, ClientHandle},
+	limits::{ClientLimits, ClientLimit},
+	introspect::{QueueSizes},
+	metrics::{TrafficCounters},
 };
 use byteorder::{WriteBytesExt, NativeEndian};
 
@@ -24,36 +35,71 @@ const MAX_MESSAGE_SIZE: usize = 1024 * 16; // 16 KiB
 const MAX_FDS: usize = 8;
 const RECV_TRIES: u32 = 2;
 const FLUSH_TRIES: u32 = 2;
+const MAX_FD_CONTENTS: usize = 1024 * 1024 * 16; // 16 MiB
 
 pub(crate) struct ClientEvent {
-	pub client: Handle<Client>,
+	pub client: ClientHandle,
 	pub payload: ClientEventPayload,
 }
 
 pub(crate) enum ClientEventPayload {
 	ClientDisconnected,
 	Message(RawMessage),
+	LimitExceeded(ClientLimit),
+	/// The client sent something that isn't a message, so nothing more can be read from it.
+	Malformed(NetError),
 }
 
 
 #[derive(Debug)]
 pub struct NetServer {
-	listener: UnixListener,
+	listener: Option<UnixListener>,
+	// Where the listener is bound, so the socket can be removed once the server stops listening
+	path: Option<PathBuf>,
 }
 
 impl NetServer {
 	pub fn new() -> Result<Self, NetError> {
-		let listener = UnixListener::bind("/run/user/1000/wayland-0")
-			.map_err(NetError::SocketBind)?;
-		listener.set_nonblocking(true).expect("Failed to set listener as non-blocking");
+		Self::bind(Path::new("/run/user/1000/wayland-0"))
+	}
+
+	pub fn bind(path: &Path) -> Result<Self, NetError> {
+		let listener = bind_listener(path).map_err(NetError::SocketBind)?;
 
 		Ok(Self {
-			listener,
+			listener: Some(listener),
+			path: Some(path.to_owned()),
 		})
 	}
 
+	/// A server that doesn't listen for connections. Clients can only be added with `Server::add_client`.
+	pub fn without_socket() -> Self {
+		Self {
+			listener: None,
+			path: None,
+		}
+	}
+
+	/// Stops listening for connections and removes the socket.
+	pub(crate) fn close(&mut self) {
+		self.listener = None;
+		if let Some(path) = self.path.take() {
+			if let Err(e) = fs::remove_file(&path) {
+				log::warn!("Failed to remove socket {}: {}", path.display(), e);
+			}
+		}
+	}
+
+	pub(crate) fn listener_fd(&self) -> Option<RawFd> {
+		self.listener.as_ref().map(|listener| listener.as_raw_fd())
+	}
+
 	pub fn try_accept(&mut self) -> Result<Option<NetClient>, NetError> {
-		match self.listener.accept() {
+		let listener = match self.listener {
+			Some(ref listener) => listener,
+			None => return Ok(None),
+		};
+		match listener.accept() {
 			Ok((stream, _addr)) => {
 				Ok(Some(NetClient::new(stream)))
 			},
@@ -67,8 +113,10 @@ impl NetServer {
 	}
 
 	pub(crate) fn poll_clients(&mut self, client_manager: &mut ClientManager) -> Result<Option<ClientEvent>, NetError> {
-		let poll_targets = client_manager.clients
-			.iter()
+		// Clients that were sent a protocol error are about to be disconnected, so nothing more is read from them
+		let poll_targets = client_manager.live_clients()
+			.into_iter()
+			.filter(|client| !client.error_posted())
 			.map(|client| {
 				(client.handle(), client.net.borrow().stream.as_raw_fd())
 			})
@@ -88,7 +136,10 @@ impl NetServer {
 				}
 			}
 
-			let client = client_handle.get().unwrap();
+			let client = match client_handle.get() {
+				Ok(client) => client,
+				Err(_) => continue,
+			};
 			let mut net_client = client.net.borrow_mut();
 
 			match net_client.try_read_message(&*client) {
@@ -97,12 +148,83 @@ impl NetServer {
 					payload: ClientEventPayload::Message(msg),
 				})),
 				Ok(None) => {},//log::error!("Received no event from client after poll"),
-				Err(e) => return Err(e),
+				Err(NetError::LimitExceeded(limit)) => return Ok(Some(ClientEvent {
+					client: client_handle.clone(),
+					payload: ClientEventPayload::LimitExceeded(limit),
+				})),
+				// The rest of the message is read once it arrives
+				Err(NetError::InsufficientData) => {},
+				// Errors are the client's own, so the other clients are still served
+				Err(e) => return Ok(Some(ClientEvent {
+					client: client_handle.clone(),
+					payload: ClientEventPayload::Malformed(e),
+				})),
 			}
 		}
 
 		Ok(None)
 	}
+
+	/// Blocks until a client sent something, events queued for a client can be sent, a client is connecting, one of
+	/// `fds` is readable or one of `writable_fds` is writable, or until `timeout` passes.
+	pub(crate) fn wait(&self, client_manager: &ClientManager, fds: &[RawFd], writable_fds: &[RawFd], timeout: Option<Duration>) -> Result<(), NetError> {
+		let mut pollfds = client_manager.live_clients()
+			.into_iter()
+			.filter(|client| !client.error_posted())
+			.map(|client| {
+				let net = client.net.borrow();
+				let mut flags = poll::PollFlags::POLLIN;
+				if net.has_queued_events() {
+					flags |= poll::PollFlags::POLLOUT;
+				}
+				poll::PollFd::new(net.fd(), flags)
+			})
+			.collect::<Vec<_>>();
+		if let Some(listener) = self.listener_fd() {
+			pollfds.push(poll::PollFd::new(listener, poll::PollFlags::POLLIN));
+		}
+		pollfds.extend(fds.iter().map(|&fd| poll::PollFd::new(fd, poll::PollFlags::POLLIN)));
+		pollfds.extend(writable_fds.iter().map(|&fd| poll::PollFd::new(fd, poll::PollFlags::POLLOUT)));
+
+		let timeout = timeout.map(|timeout| timeout.as_millis().min(i32::MAX as u128) as i32).unwrap_or(-1);
+		match poll::poll(&mut pollfds, timeout) {
+			Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => Ok(()),
+			Err(e) => Err(NetError::PollError(e)),
+		}
+	}
+}
+
+/// Binds a non-blocking listener at `path`. A socket left behind by a server that didn't shut down cleanly is removed
+/// first, but one that something is still listening on is not.
+pub(crate) fn bind_listener(path: &Path) -> io::Result<UnixListener> {
+	let listener = match UnixListener::bind(path) {
+		Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+			let is_socket = fs::symlink_metadata(path)?.file_type().is_socket();
+			if !is_socket || UnixStream::connect(path).is_ok() {
+				return Err(e);
+			}
+			log::info!("Removing stale socket {}", path.display());
+			fs::remove_file(path)?;
+			UnixListener::bind(path)?
+		},
+		result => result?,
+	};
+	listener.set_nonblocking(true)?;
+	Ok(listener)
+}
+
+impl Drop for NetServer {
+	fn drop(&mut self) {
+		self.close();
+	}
+}
+
+/// The identity of the process on the other end of a client connection, as reported by `SO_PEERCRED`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Credentials {
+	pub pid: i32,
+	pub uid: u32,
+	pub gid: u32,
 }
 
 #[derive(Debug)]
@@ -110,14 +232,59 @@ pub struct NetClient {
 	stream: UnixStream,
 	in_buffer: MessageBuffer,
 	out_buffer: MessageBuffer,
+	credentials: Option<Credentials>,
+	pub(crate) limits: ClientLimits,
+	// Set once the client is sent a protocol error. It's disconnected after its events are flushed, so they aren't
+	// dropped for exceeding the outgoing limit anymore, the error least of all.
+	pub(crate) closing: bool,
+	pub(crate) recorder: Option<ClientRecorder>,
+	pub(crate) counters: TrafficCounters,
 }
 
 impl NetClient {
 	pub fn new(stream: UnixStream) -> Self {
+		let credentials = match socket::getsockopt(stream.as_raw_fd(), socket::sockopt::PeerCredentials) {
+			Ok(credentials) => Some(Credentials {
+				pid: credentials.pid(),
+				uid: credentials.uid(),
+				gid: credentials.gid(),
+			}),
+			Err(e) => {
+				log::warn!("Failed to get client credentials: {}", e);
+				None
+			}
+		};
+
 		Self {
 			stream,
 			in_buffer: MessageBuffer::new(),
 			out_buffer: MessageBuffer::new(),
+			credentials,
+			limits: ClientLimits::default(),
+			closing: false,
+			recorder: None,
+			counters: TrafficCounters::default(),
+		}
+	}
+
+	pub fn credentials(&self) -> Option<Credentials> {
+		self.credentials
+	}
+
+	pub(crate) fn fd(&self) -> RawFd {
+		self.stream.as_raw_fd()
+	}
+
+	pub(crate) fn has_queued_events(&self) -> bool {
+		!self.out_buffer.is_empty()
+	}
+
+	pub(crate) fn queue_sizes(&self) -> QueueSizes {
+		QueueSizes {
+			incoming_bytes: self.in_buffer.data_len,
+			incoming_fds: self.in_buffer.fds.len(),
+			outgoing_bytes: self.out_buffer.data_len,
+			outgoing_fds: self.out_buffer.fds.len(),
 		}
 	}
 
@@ -128,10 +295,18 @@ impl NetClient {
 		};
 
 		let header = MessageHeader::from_bytes(&self.in_buffer.data[..8]).unwrap();
+		let size = header.msg_size as usize;
+		if size < 8 || !size.is_multiple_of(4) || size > MAX_MESSAGE_SIZE {
+			return Err(NetError::InvalidMessage);
+		}
 
+		// Requests for unknown objects or opcodes are still read whole, so the server can answer them with an error.
+		// They have no signature to count fds with, but the client is disconnected anyway.
 		let objects = client.objects.borrow();
-		let object = objects.find(|object| object.id == header.sender).ok_or(NetError::InvalidMessage)?;
-		let expected_fds = object.interface.get().requests[header.opcode as usize].iter().filter(|arg| arg.arg_type == ArgumentType::Fd).count();
+		let request = objects.key_of(header.sender)
+			.and_then(|key| objects.get(key))
+			.and_then(|object| object.interface.get().request(header.opcode));
+		let expected_fds = request.map(|request| request.args.iter().filter(|arg| arg.arg_type == ArgumentType::Fd).count()).unwrap_or(0);
 
 		// Read the rest of the message
 		if !self.try_fill_buffer_until(header.msg_size as usize, expected_fds, RECV_TRIES)? {
@@ -144,11 +319,21 @@ impl NetClient {
 			data: data[8..].to_owned(),
 			fds,
 		};
+		self.counters.record_received(header.msg_size as usize, raw.fds.len());
+
+		if let Some(ref recorder) = self.recorder {
+			recorder.record(MessageSide::Request, &raw);
+		}
 
 		Ok(Some(raw))
 	}
 
 	pub fn try_send_message(&mut self, message: RawMessage) -> Result<bool, NetError> {
+		if let Some(ref recorder) = self.recorder {
+			recorder.record(MessageSide::Event, &message);
+		}
+		self.counters.record_sent(message.header.msg_size as usize, message.fds.len());
+
 		let mut data = Vec::with_capacity(message.header.msg_size as usize);
 		data.write_u32::<NativeEndian>(message.header.sender).unwrap();
 		data.write_u16::<NativeEndian>(message.header.opcode).unwrap();
@@ -158,11 +343,22 @@ impl NetClient {
 		if self.flush()? {
 			self.try_send_data(data, message.fds)
 		} else {
-			self.out_buffer.append(&data, &message.fds)?;
+			self.queue_outgoing(&data, &message.fds)?;
 			Ok(false)
 		}
 	}
 
+	// The outgoing limit is only checked here, for whole messages. Once part of a message was sent the rest always
+	// has to be queued, or the client would read the next message from the middle of this one.
+	fn queue_outgoing(&mut self, data: &[u8], fds: &[RawFd]) -> Result<(), NetError> {
+		if !self.closing && self.out_buffer.data_len + data.len() > self.limits.max_outgoing_bytes {
+			return Err(NetError::LimitExceeded(ClientLimit::OutgoingBytes));
+		}
+		self.out_buffer.append(data, fds)?;
+		self.counters.record_queued(self.out_buffer.data_len, self.out_buffer.fds.len());
+		Ok(())
+	}
+
 	fn try_fill_buffer(&mut self) -> Result<bool, NetError> {
 		let fd = self.stream.as_raw_fd();
 		let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_FDS]);
@@ -180,6 +376,9 @@ impl NetClient {
 				_ => {},
 			}
 		}
+		if self.in_buffer.fds.len() > self.limits.max_fds {
+			return Err(NetError::LimitExceeded(ClientLimit::Fds));
+		}
 
 		self.in_buffer.data_len += recv.bytes;
 
@@ -202,21 +401,21 @@ impl NetClient {
 		let cmsg = socket::ControlMessage::ScmRights(&fds);
 		let flags = socket::MsgFlags::MSG_DONTWAIT;
 
-		Ok(match socket::sendmsg(fd, &[iovec], &[cmsg], flags, None) {
-			Ok(n) => {
-				if n > 0 {
-					self.out_buffer.append(&data[n..], &[])?;
-					false
-				} else {
-					true
-				}
-			},
-			Err(nix::Error::Sys(Errno::EAGAIN)) => {
-				self.out_buffer.append(&data, &fds)?;
-				false
-			},
+		// Whatever isn't sent goes back in the buffer without checking the limit: it was either already queued, or is
+		// a single message that was sent while the buffer was empty
+		let sent = match socket::sendmsg(fd, &[iovec], &[cmsg], flags, None) {
+			Ok(n) => n,
+			Err(nix::Error::Sys(Errno::EAGAIN)) => 0,
 			Err(e) => return Err(NetError::SendError(e)),
-		})
+		};
+		if sent == data.len() {
+			return Ok(true);
+		}
+		// The fds went along with the first byte that was sent
+		let fds: &[RawFd] = if sent == 0 { &fds } else { &[] };
+		self.out_buffer.append(&data[sent..], fds)?;
+		self.counters.record_queued(self.out_buffer.data_len, self.out_buffer.fds.len());
+		Ok(false)
 	}
 
 	pub fn flush(&mut self) -> Result<bool, NetError> {
@@ -235,6 +434,114 @@ impl NetClient {
 	}
 }
 
+/// Writes every message sent to or received from a client to a capture file, which can be loaded with
+/// `wl_common::capture::CaptureReader`.
+///
+/// A recorder can be shared by the whole server with `Server::record_session` or attached to a single client with
+/// `Client::record_session`.
+pub struct SessionRecorder {
+	writer: CaptureWriter<Box<dyn Write>>,
+	fd_contents: bool,
+	max_fd_contents: usize,
+}
+
+impl SessionRecorder {
+	pub fn new<W: Write + 'static>(writer: W) -> Result<Self, CaptureError> {
+		Ok(Self {
+			writer: CaptureWriter::new(Box::new(writer) as Box<dyn Write>)?,
+			fd_contents: false,
+			max_fd_contents: MAX_FD_CONTENTS,
+		})
+	}
+
+	/// Creates a capture file at `path`, replacing any existing file.
+	pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, CaptureError> {
+		let file = File::create(path)?;
+		Self::new(io::BufWriter::new(file))
+	}
+
+	/// Also record the contents of file descriptors that refer to regular files, such as memfds and shm files,
+	/// as long as they are no larger than `max_fd_contents`.
+	pub fn record_fd_contents(mut self, enabled: bool) -> Self {
+		self.fd_contents = enabled;
+		self
+	}
+
+	pub fn max_fd_contents(mut self, max_fd_contents: usize) -> Self {
+		self.max_fd_contents = max_fd_contents;
+		self
+	}
+
+	pub(crate) fn record(&mut self, client: u32, direction: MessageSide, message: &RawMessage) {
+		let fds = message.fds.iter().map(|&fd| {
+			if self.fd_contents {
+				read_fd_contents(fd, self.max_fd_contents)
+			} else {
+				None
+			}
+		}).collect();
+		let record = CaptureRecord {
+			timestamp: SystemTime::now(),
+			client,
+			direction,
+			header: message.header,
+			payload: message.data.clone(),
+			fds,
+		};
+
+		// Flush every record so the capture is usable even if the compositor crashes
+		if let Err(e) = self.writer.write_record(&record).and_then(|_| self.writer.flush()) {
+			log::error!("Failed to record message: {}", e);
+		}
+	}
+}
+
+impl fmt::Debug for SessionRecorder {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("SessionRecorder")
+			.field("fd_contents", &self.fd_contents)
+			.field("max_fd_contents", &self.max_fd_contents)
+			.finish()
+	}
+}
+
+/// A recorder attached to a single client.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientRecorder {
+	pub client: u32,
+	pub recorder: Rc<RefCell<SessionRecorder>>,
+}
+
+impl ClientRecorder {
+	fn record(&self, direction: MessageSide, message: &RawMessage) {
+		self.recorder.borrow_mut().record(self.client, direction, message);
+	}
+}
+
+/// Reads the whole contents of a regular file without moving its offset, which the client may rely on.
+fn read_fd_contents(fd: RawFd, max_len: usize) -> Option<Vec<u8>> {
+	let stat = stat::fstat(fd).ok()?;
+	if stat::SFlag::from_bits_truncate(stat.st_mode) & stat::SFlag::S_IFMT != stat::SFlag::S_IFREG {
+		return None;
+	}
+	let len = stat.st_size as usize;
+	if len > max_len {
+		return None;
+	}
+
+	let mut contents = vec![0u8; len];
+	let mut offset = 0;
+	while offset < len {
+		match uio::pread(fd, &mut contents[offset..], offset as nix::libc::off_t) {
+			Ok(0) => break,
+			Ok(n) => offset += n,
+			Err(_) => return None,
+		}
+	}
+	contents.truncate(offset);
+	Some(contents)
+}
+
 #[derive(Debug)]
 struct MessageBuffer {
 	data: Vec<u8>,
@@ -273,10 +580,8 @@ impl MessageBuffer {
 		self.advance(self.data_len, self.fds.len())
 	}
 
+	// The amount of data is bounded by the caller, see `NetClient::queue_outgoing`
 	fn append(&mut self, data: &[u8], fds: &[RawFd]) -> Result<(), NetError> {
-		if self.data_len + data.len() > MAX_MESSAGE_SIZE {
-			return Err(NetError::BufferFull);
-		}
 		if self.fds.len() + fds.len() > MAX_FDS {
 			return Err(NetError::BufferFull);
 		}
@@ -311,4 +616,6 @@ pub enum NetError {
 	BufferFull,
 	#[error("Failed to parse data as a message")]
 	InvalidMessage,
+	#[error("The client exceeded a resource limit: {0}")]
+	LimitExceeded(ClientLimit),
 }
@@ -1,6 +1,8 @@
 use std::{
 	any::{Any},
 	cell::{Cell, RefCell},
+	collections::{HashMap, BTreeMap, VecDeque},
+	rc::{Rc},
 	fmt,
 };
 
@@ -11,7 +13,7 @@ use loaner::{
 };
 
 use wl_common::{
-	interface::{Interface, DynInterface, Message, FromArgsError},
+	interface::{Interface, DynInterface, InterfaceTitle, Message, FromArgsError},
 	wire::{DynArgument},
 };
 
@@ -19,48 +21,131 @@ use crate::{
 	server::{State},
 	client::{ClientMap},
 	resource::{Resource, Untyped},
+	arena::{Arena, Key},
 };
 
+pub type ObjectKey = Key<Object>;
+
+/// A client's objects, which can be looked up by key or by id.
 #[derive(Debug)]
 pub struct ObjectMap {
-	pub(crate) objects: Vec<Owner<Object>>,
+	objects: Arena<Object>,
+	ids: HashMap<u32, ObjectKey>,
+	created: u64,
+	// Objects that no live object names as its parent, by when they were created
+	childless: BTreeMap<u64, ObjectKey>,
+	// Objects marked for destruction, in the order they were marked
+	pending_destroy: VecDeque<ObjectKey>,
 }
 
 impl ObjectMap {
 	pub(crate) fn new() -> Self {
 		Self {
-			objects: Vec::new(),
+			objects: Arena::new(),
+			ids: HashMap::new(),
+			created: 0,
+			childless: BTreeMap::new(),
+			pending_destroy: VecDeque::new(),
+		}
+	}
+
+	pub(crate) fn add(&mut self, object: Object) -> ObjectKey {
+		let id = object.id;
+		self.created += 1;
+		object.created.set(self.created);
+		let key = self.objects.insert(object);
+		self.ids.insert(id, key);
+		self.childless.insert(self.created, key);
+		key
+	}
+
+	pub(crate) fn remove(&mut self, key: ObjectKey) -> Option<Rc<Object>> {
+		let object = self.objects.remove(key)?;
+		if self.ids.get(&object.id) == Some(&key) {
+			self.ids.remove(&object.id);
+		}
+		self.childless.remove(&object.created.get());
+		if let Some(parent) = object.parent.get() {
+			self.remove_child(parent);
 		}
+		Some(object)
 	}
 
-	pub fn add(&mut self, object: Owner<Object>) {
-		self.objects.push(object);
+	/// Makes `parent` the parent of `child`, replacing its previous parent.
+	pub(crate) fn set_parent(&mut self, child: ObjectKey, parent: ObjectKey) {
+		let child = match self.objects.get(child) {
+			Some(child) => child,
+			None => return,
+		};
+		if let Some(previous) = child.parent.replace(Some(parent)) {
+			self.remove_child(previous);
+		}
+		if let Some(parent) = self.objects.get(parent) {
+			if parent.children.get() == 0 {
+				self.childless.remove(&parent.created.get());
+			}
+			parent.children.set(parent.children.get() + 1);
+		}
+	}
+
+	fn remove_child(&mut self, key: ObjectKey) {
+		if let Some(parent) = self.objects.get(key) {
+			parent.children.set(parent.children.get() - 1);
+			if parent.children.get() == 0 {
+				self.childless.insert(parent.created.get(), key);
+			}
+		}
 	}
 
-	pub fn remove(&mut self, handle: Handle<Object>) -> Option<Owner<Object>> {
-		if let Some(i) = self.objects.iter().position(|object| object.handle().is(&handle)) {
-			Some(self.objects.remove(i))
-		} else {
-			None
+	/// Marks an object to be destroyed during the next dispatch.
+	pub(crate) fn mark_destroyed(&mut self, key: ObjectKey) {
+		if let Some(object) = self.objects.get(key) {
+			if !object.destroy.replace(true) {
+				self.pending_destroy.push_back(key);
+			}
 		}
 	}
 
-	pub fn remove_any(&mut self) -> Option<Owner<Object>> {
-		self.objects.pop()
+	pub fn get(&self, key: ObjectKey) -> Option<Rc<Object>> {
+		self.objects.get(key)
+	}
+
+	/// Returns the key of the live object with the id `id`.
+	pub fn key_of(&self, id: u32) -> Option<ObjectKey> {
+		self.ids.get(&id).copied()
+	}
+
+	pub fn len(&self) -> usize {
+		self.objects.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.objects.len() == 0
 	}
 
-	pub fn next_pending_destroy(&mut self) -> Option<Owner<Object>> {
-		self.objects.iter().position(|object| object.destroy.get()).map(|position| self.objects.remove(position))
+	/// Returns every object with its key, in creation order.
+	pub fn entries(&self) -> Vec<(ObjectKey, Rc<Object>)> {
+		self.objects.entries()
 	}
 
-	pub fn find<F: Fn(&Owner<Object>) -> bool>(&self, f: F) -> Option<Ref<Object>> {
-		self.objects.iter().find_map(|object| {
-			if f(object) {
-				Some(object.custom_ref())
-			} else {
-				None
+	/// Picks the next object that should be torn down when the client goes away. It's left in the map so its
+	/// destructor can still reach it.
+	///
+	/// This picks the newest object that no other live object names as its parent. Destructors therefore never
+	/// observe a parent that was already destroyed.
+	pub(crate) fn next_teardown(&self) -> Option<ObjectKey> {
+		self.childless.values().next_back().copied()
+			.or_else(|| self.entries().last().map(|&(key, _)| key)) // Fall back to reverse creation order if parents form a cycle
+	}
+
+	/// Takes the next object that was marked for destruction and is still alive.
+	pub(crate) fn next_pending_destroy(&mut self) -> Option<ObjectKey> {
+		while let Some(key) = self.pending_destroy.pop_front() {
+			if self.objects.get(key).is_some() {
+				return Some(key);
 			}
-		})
+		}
+		None
 	}
 }
 
@@ -68,9 +153,20 @@ impl ObjectMap {
 pub struct Object {
 	pub(crate) id: u32,
 	pub(crate) interface: Cell<DynInterface>,
+	pub(crate) version: Cell<u32>,
+	// The interface a client asked for when creating an object through an untyped new_id, e.g. wl_registry.bind
+	pub(crate) requested: Option<InterfaceTitle>,
 	pub(crate) dispatcher: RefCell<Option<Dispatcher>>, 
 	pub(crate) data: RefCell<Box<dyn Any>>,
+	// The type name of `data`, for introspection
+	pub(crate) data_type: Cell<&'static str>,
 	pub(crate) destroy: Cell<bool>,
+	pub(crate) parent: Cell<Option<ObjectKey>>,
+	// How many live objects name this one as their parent
+	pub(crate) children: Cell<usize>,
+	// When this object was added to its map, so teardown goes from newest to oldest
+	pub(crate) created: Cell<u64>,
+	pub(crate) destroy_listeners: RefCell<DestroyListeners>,
 }
 
 impl Object {
@@ -78,20 +174,34 @@ impl Object {
 		Self {
 			id,
 			interface: Cell::new(I::as_dyn()),
+			version: Cell::new(I::VERSION),
+			requested: None,
 			dispatcher: RefCell::new(Some(Dispatcher::null::<I, R>())),
 			data: RefCell::new(Box::new(())),
+			data_type: Cell::new("()"),
 			destroy: Cell::new(false),
+			parent: Cell::new(None),
+			children: Cell::new(0),
+			created: Cell::new(0),
+			destroy_listeners: RefCell::new(DestroyListeners::new()),
 		}
 	}
 
 	// This is dangerous because if any request or event is sent to this object before it leaves it's untyped state, errors will happen
-	pub fn new_untyped(id: u32) -> Self {
+	pub fn new_untyped(id: u32, requested: Option<InterfaceTitle>) -> Self {
 		Self {
 			id,
 			interface: Cell::new(DynInterface::new_anonymous()),
+			version: Cell::new(requested.as_ref().map(|title| title.version).unwrap_or(0)),
+			requested,
 			dispatcher: RefCell::new(None),
 			data: RefCell::new(Box::new(())),
+			data_type: Cell::new("()"),
 			destroy: Cell::new(false),
+			parent: Cell::new(None),
+			children: Cell::new(0),
+			created: Cell::new(0),
+			destroy_listeners: RefCell::new(DestroyListeners::new()),
 		}
 	}
 
@@ -99,12 +209,17 @@ impl Object {
 		let owner = Owner::new(data);
 		let handle = owner.handle();
 		*self.data.borrow_mut() = Box::new(owner);
+		self.data_type.set(std::any::type_name::<T>());
 		handle
 	}
 
 	pub fn get_data<'a, T: 'static>(&'a self) -> Option<Ref<'a, T>> {
 		self.data.borrow().downcast_ref::<Owner<T>>().map(|owner| owner.custom_ref())
 	}
+
+	pub(crate) fn take_destroy_listeners(&self) -> Vec<DestroyListener> {
+		std::mem::take(&mut self.destroy_listeners.borrow_mut().listeners)
+	}
 }
 
 impl Drop for Object {
@@ -117,6 +232,33 @@ impl Drop for Object {
 	}
 }
 
+pub(crate) type DestroyListener = Box<dyn FnOnce(&mut State, Resource<Untyped>)>;
+
+/// Callbacks that run right before an object's destructor, in the order they were added.
+pub(crate) struct DestroyListeners {
+	pub listeners: Vec<DestroyListener>,
+}
+
+impl DestroyListeners {
+	pub fn new() -> Self {
+		Self {
+			listeners: Vec::new(),
+		}
+	}
+
+	pub fn add(&mut self, listener: DestroyListener) {
+		self.listeners.push(listener);
+	}
+}
+
+impl fmt::Debug for DestroyListeners {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("DestroyListeners")
+			.field("listeners", &self.listeners.len())
+			.finish()
+	}
+}
+
 pub(crate) struct Dispatcher {
 	pub implementation: Box<dyn RawObjectImplementation>,
 	pub destroyed: bool,
@@ -170,9 +312,9 @@ impl Dispatcher {
 		if self.destroyed {
 			return Err(DispatchError::ObjectDestroyed)
 		}
-		let result = self.implementation.dispatch_destructor(state, this);
+		// Marked first so a panicking destructor isn't reported again when the object is dropped
 		self.destroyed = true;
-		result
+		self.implementation.dispatch_destructor(state, this)
 	}
 }
 
@@ -205,12 +347,29 @@ pub struct RawObjectImplementationConcrete<I> {
 impl<I: Interface> RawObjectImplementation for RawObjectImplementationConcrete<I> where I::Request: Message<ClientMap=ClientMap> + fmt::Debug {
 	fn dispatch(&mut self, state: &mut State, this: Resource<Untyped>, opcode: u16, args: Vec<DynArgument>) -> Result<(), DispatchError> {
 		let typed_resource = this.downcast::<I>().ok_or(DispatchError::TypeMismatch)?;
-		let client_map = this.client().get().unwrap().client_map();
-		let request = I::Request::from_args(client_map, opcode, args)?;
+		let version = this.object().map(|object| object.version.get()).map_err(|_| DispatchError::ObjectDestroyed)?;
+		let client_map = this.client().get().unwrap().client_map_for(version);
+		let fds = args.iter().filter_map(|arg| match *arg {
+			DynArgument::Fd(fd) => Some(fd),
+			_ => None,
+		}).collect::<Vec<_>>();
+		let request = match I::Request::from_args(client_map, opcode, args) {
+			Ok(request) => request,
+			Err(e) => {
+				// The handler would have owned the fds, so nothing else closes them
+				for fd in fds {
+					let _ = nix::unistd::close(fd);
+				}
+				return Err(e.into());
+			},
+		};
 
 		if crate::server::request_debug() {
 			log::debug!("{:?} {:?}", this, request);
 		}
+		if this.client().get().map(|client| client.tracing_enabled()).unwrap_or(false) {
+			tracing::debug!("request {:?}", request);
+		}
 
 		self.typed_implementation.handle(state, typed_resource, request);
 		Ok(())
@@ -231,4 +390,57 @@ pub enum DispatchError {
 	ObjectDestroyed,
 	#[error(transparent)]
 	ArgumentError(#[from] FromArgsError),
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn object_map(count: u32) -> (ObjectMap, Vec<ObjectKey>) {
+		let mut objects = ObjectMap::new();
+		let keys = (1..=count).map(|id| objects.add(Object::new_untyped(id, None))).collect();
+		(objects, keys)
+	}
+
+	fn teardown_order(mut objects: ObjectMap) -> Vec<u32> {
+		let mut order = Vec::new();
+		while let Some(key) = objects.next_teardown() {
+			order.push(objects.remove(key).unwrap().id);
+		}
+		order
+	}
+
+	#[test]
+	fn teardown_follows_reparenting() {
+		let (mut objects, keys) = object_map(4);
+		objects.set_parent(keys[3], keys[0]);
+		objects.set_parent(keys[2], keys[3]);
+		objects.set_parent(keys[1], keys[3]);
+		// 2 moves from 4 to 3, so 4 has only 3 left
+		objects.set_parent(keys[1], keys[2]);
+		assert_eq!(teardown_order(objects), vec![2, 3, 4, 1]);
+	}
+
+	#[test]
+	fn teardown_breaks_parent_cycles_newest_first() {
+		let (mut objects, keys) = object_map(3);
+		objects.set_parent(keys[0], keys[1]);
+		objects.set_parent(keys[1], keys[0]);
+		objects.set_parent(keys[2], keys[2]);
+		assert_eq!(teardown_order(objects), vec![3, 2, 1]);
+	}
+
+	#[test]
+	fn pending_destroys_are_taken_once_in_order() {
+		let (mut objects, keys) = object_map(3);
+		objects.mark_destroyed(keys[2]);
+		objects.mark_destroyed(keys[0]);
+		objects.mark_destroyed(keys[2]);
+		objects.mark_destroyed(keys[1]);
+		objects.remove(keys[0]);
+
+		assert_eq!(objects.next_pending_destroy(), Some(keys[2]));
+		assert_eq!(objects.next_pending_destroy(), Some(keys[1]));
+		assert_eq!(objects.next_pending_destroy(), None);
+	}
+}
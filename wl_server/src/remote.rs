@@ -0,0 +1,305 @@
+//! Posting work to the server from other threads. The server and everything it owns stay on the thread that created
+//! them, so other threads send closures that run on the server thread during the next `Server::dispatch`.
+//!
+//! ```ignore
+//! let handle = server.handle();
+//! let callback = frame_callback.remote();
+//! thread::spawn(move || {
+//!     render();
+//!     handle.send_event(&callback, WlCallbackEvent::Done(wl_callback::DoneEvent { callback_data: time }));
+//! });
+//! ```
+
+use std::{
+	os::unix::{io::{RawFd}},
+	sync::{Arc, mpsc::{self, Sender, Receiver}, atomic::{AtomicBool, Ordering}},
+	collections::{HashMap},
+	marker::{PhantomData},
+	fmt,
+};
+
+use nix::{
+	errno::Errno,
+	unistd,
+	sys::{eventfd::{self, EfdFlags}},
+};
+use thiserror::{Error};
+
+use wl_common::{
+	interface::{Interface, Message},
+};
+
+use crate::{
+	server::{Server},
+	client::{ClientMap},
+	resource::{Resource, Untyped},
+	object::{ObjectKey},
+};
+
+type Task = Box<dyn FnOnce(&mut Server) + Send>;
+
+/// A cloneable handle to a server that can be sent to other threads.
+#[derive(Clone)]
+pub struct ServerHandle {
+	sender: Sender<Task>,
+	wake: Arc<WakeFd>,
+}
+
+impl ServerHandle {
+	/// Queues `f` to run on the server thread and wakes the server up. Fails if the server was dropped.
+	pub fn run<F: FnOnce(&mut Server) + Send + 'static>(&self, f: F) -> Result<(), RemoteError> {
+		self.sender.send(Box::new(f)).map_err(|_| RemoteError::ServerGone)?;
+		self.wake.wake();
+		Ok(())
+	}
+
+	/// Queues an event to be sent on a resource. The event is dropped if the resource was destroyed in the
+	/// meantime.
+	///
+	/// Events that refer to other resources, like `wl_keyboard.enter`, aren't `Send`. Send those with `run`, and look
+	/// up the resources with `RemoteResource::get`.
+	pub fn send_event<I>(&self, resource: &RemoteResource<I>, event: I::Event) -> Result<(), RemoteError>
+	where
+		I: Interface + 'static,
+		I::Event: Message<ClientMap=ClientMap> + fmt::Debug + Send + 'static,
+	{
+		let resource = *resource;
+		self.run(move |server| {
+			if let Some(resource) = resource.get(server) {
+				resource.send_event(event);
+			}
+		})
+	}
+}
+
+impl fmt::Debug for ServerHandle {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("ServerHandle")
+			.field("wake", &self.wake)
+			.finish()
+	}
+}
+
+/// Asks the server to shut down, from another thread or from a signal handler. `Server::run` then calls
+/// `Server::shutdown` and returns.
+#[derive(Debug, Clone)]
+pub struct StopHandle {
+	stop: Arc<AtomicBool>,
+	wake: Arc<WakeFd>,
+}
+
+impl StopHandle {
+	/// Only stores a flag and writes to an eventfd, so this is safe to call from a signal handler.
+	pub fn stop(&self) {
+		self.stop.store(true, Ordering::SeqCst);
+		let _ = unistd::write(self.wake.fd, &1u64.to_ne_bytes());
+	}
+}
+
+/// A reference to a resource that can be sent to other threads, created with `Resource::remote`. It can only be
+/// turned back into a resource on the server thread.
+pub struct RemoteResource<I> {
+	token: u64,
+	_interface: PhantomData<fn() -> I>,
+}
+
+impl<I: Interface> RemoteResource<I> {
+	/// Returns the resource, unless it has been destroyed.
+	pub fn get(&self, server: &Server) -> Option<Resource<I>> {
+		server.remote_resource(self.token)?.downcast::<I>()
+	}
+}
+
+impl<I> Clone for RemoteResource<I> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+
+impl<I> Copy for RemoteResource<I> {}
+
+impl<I> fmt::Debug for RemoteResource<I> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("RemoteResource")
+			.field("token", &self.token)
+			.finish()
+	}
+}
+
+/// The resources that were handed out as `RemoteResource`s. Tokens are never reused, so a `RemoteResource` can't
+/// end up referring to a newer object that was given the same id.
+#[derive(Debug, Default)]
+pub(crate) struct RemoteResources {
+	resources: HashMap<u64, Resource<Untyped>>,
+	// The token of every shared object, so sharing the same resource repeatedly doesn't grow the map
+	tokens: HashMap<ObjectKey, u64>,
+	next_token: u64,
+}
+
+impl RemoteResources {
+	pub(crate) fn add<I>(&mut self, resource: &Resource<I>) -> RemoteResource<I> {
+		let resources = &mut self.resources;
+		let next_token = &mut self.next_token;
+		let token = *self.tokens.entry(resource.object_key()).or_insert_with(|| {
+			let token = *next_token;
+			*next_token += 1;
+			resources.insert(token, resource.to_untyped());
+			token
+		});
+		RemoteResource {
+			token,
+			_interface: PhantomData,
+		}
+	}
+
+	pub(crate) fn get(&self, token: u64) -> Option<Resource<Untyped>> {
+		self.resources.get(&token).filter(|resource| resource.is_alive()).cloned()
+	}
+
+	/// Forgets resources whose objects were destroyed.
+	pub(crate) fn remove_destroyed(&mut self) {
+		self.resources.retain(|_, resource| resource.is_alive());
+		let resources = &self.resources;
+		self.tokens.retain(|_, token| resources.contains_key(token));
+	}
+}
+
+/// The server's end of every `ServerHandle`.
+#[derive(Debug)]
+pub(crate) struct RemoteQueue {
+	receiver: Receiver<Task>,
+	sender: Sender<Task>,
+	wake: Arc<WakeFd>,
+	stop: Arc<AtomicBool>,
+}
+
+impl RemoteQueue {
+	pub(crate) fn new() -> Self {
+		let (sender, receiver) = mpsc::channel();
+		Self {
+			receiver,
+			sender,
+			wake: Arc::new(WakeFd::new()),
+			stop: Arc::new(AtomicBool::new(false)),
+		}
+	}
+
+	pub(crate) fn stop_handle(&self) -> StopHandle {
+		StopHandle {
+			stop: Arc::clone(&self.stop),
+			wake: Arc::clone(&self.wake),
+		}
+	}
+
+	pub(crate) fn stop_requested(&self) -> bool {
+		self.stop.load(Ordering::SeqCst)
+	}
+
+	pub(crate) fn request_stop(&self) {
+		self.stop.store(true, Ordering::SeqCst);
+	}
+
+	pub(crate) fn handle(&self) -> ServerHandle {
+		ServerHandle {
+			sender: self.sender.clone(),
+			wake: Arc::clone(&self.wake),
+		}
+	}
+
+	pub(crate) fn fd(&self) -> RawFd {
+		self.wake.fd
+	}
+
+	/// Takes the next queued task. The wakeup is cleared before the queue is checked, so a task queued while the
+	/// server is running tasks still wakes it up again.
+	pub(crate) fn next_task(&self) -> Option<Task> {
+		self.wake.clear();
+		self.receiver.try_recv().ok()
+	}
+}
+
+/// An eventfd that's readable while there are tasks the server hasn't looked at.
+#[derive(Debug)]
+struct WakeFd {
+	fd: RawFd,
+}
+
+impl WakeFd {
+	fn new() -> Self {
+		let fd = eventfd::eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK).expect("Failed to create eventfd");
+		Self {
+			fd,
+		}
+	}
+
+	fn wake(&self) {
+		// The counter only overflows after 2^64 wakeups without a dispatch, and EAGAIN then still leaves it readable
+		if let Err(e) = unistd::write(self.fd, &1u64.to_ne_bytes()) {
+			if e != nix::Error::Sys(Errno::EAGAIN) {
+				log::error!("Failed to wake server: {}", e);
+			}
+		}
+	}
+
+	fn clear(&self) {
+		let mut buf = [0u8; 8];
+		let _ = unistd::read(self.fd, &mut buf);
+	}
+}
+
+impl Drop for WakeFd {
+	fn drop(&mut self) {
+		let _ = unistd::close(self.fd);
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum RemoteError {
+	#[error("The server was dropped")]
+	ServerGone,
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		thread,
+		time::{Duration, Instant},
+	};
+
+	use crate::{
+		Server, BindContext, NewResource,
+		protocol::*,
+		testing::{TestClient},
+	};
+
+	#[test]
+	fn events_are_sent_from_other_threads() {
+		let mut server = Server::new_without_socket(());
+		server.register_global::<WlOutput, _>(|_context: BindContext, new_resource: NewResource<WlOutput>| {
+			new_resource.register_fn((), |_, _, _| {}, |_, _| {});
+		});
+		let mut client = TestClient::connect(&mut server, ());
+		client.bind_global::<WlOutput>(&mut server, 3).unwrap();
+		client.take_events();
+
+		let output = server.resources::<WlOutput>().next().unwrap().remote().unwrap();
+		let handle = server.handle();
+		thread::spawn(move || {
+			handle.send_event(&output, WlOutputEvent::Scale(wl_output::ScaleEvent { factor: 2 })).unwrap();
+			handle.send_event(&output, WlOutputEvent::Done).unwrap();
+		}).join().unwrap();
+
+		// The handle woke the server up, so this doesn't wait for the timeout
+		let start = Instant::now();
+		server.wait(Some(Duration::from_secs(5))).unwrap();
+		assert!(start.elapsed() < Duration::from_secs(1));
+
+		client.dispatch(&mut server).unwrap();
+		match client.expect_event::<WlOutput>(3) {
+			WlOutputEvent::Scale(scale) => assert_eq!(scale.factor, 2),
+			event => panic!("Expected a scale event, got {:?}", event),
+		}
+		assert!(matches!(client.expect_event::<WlOutput>(3), WlOutputEvent::Done));
+		client.expect_no_events();
+	}
+}
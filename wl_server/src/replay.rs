@@ -0,0 +1,508 @@
+use std::{
+	os::unix::{net::{UnixStream}, io::{RawFd, AsRawFd}},
+	collections::{HashMap, VecDeque},
+	cell::{RefCell},
+	rc::{Rc, Weak},
+	ffi::{CStr},
+	fmt,
+};
+
+use nix::{
+	errno::Errno,
+	unistd,
+	sys::{socket, memfd, uio::{IoVec}},
+};
+use thiserror::{Error};
+
+use wl_common::{
+	wire::{RawMessage, RawMessageReader, DynMessage, DynArgument, SerializeRawError},
+	interface::{DynInterface, MessageSide},
+	capture::{CaptureRecord, CaptureError},
+	socket::{MAX_FDS, send_message},
+};
+
+use crate::{
+	server::{Server, ServerError},
+	client::{Client, ClientHandle},
+	resource::{Resource, Untyped},
+	middleware::{Middleware, MiddlewareAction},
+};
+
+/// Replays a recorded session against a server and reports where the events it sends differ from the recording.
+///
+/// Every client in the capture is connected to the server over a socketpair, its requests are resent in the order
+/// they were recorded, and after each request the server is dispatched and the events it sent are compared with the
+/// recorded ones. Serials only have to be consistent with each other rather than equal to the recorded values, and
+/// serials in replayed requests are rewritten to the ones the server actually sent. Timestamps are ignored.
+#[derive(Debug)]
+pub struct Replayer {
+	serial_args: Vec<String>,
+	ignored_args: Vec<String>,
+}
+
+impl Replayer {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Treats arguments with the given name as serials.
+	pub fn serial_arg<S: Into<String>>(mut self, name: S) -> Self {
+		self.serial_args.push(name.into());
+		self
+	}
+
+	/// Doesn't compare arguments with the given name, for values like timestamps that differ on every run.
+	pub fn ignore_arg<S: Into<String>>(mut self, name: S) -> Self {
+		self.ignored_args.push(name.into());
+		self
+	}
+
+	/// Replays the records against `server`, creating state for each fake client like `Server::dispatch` does.
+	///
+	/// The server is observed through a middleware that is removed again once the run has finished.
+	pub fn run<S, F, I>(&self, server: &mut Server, records: I, mut client_state_creator: F) -> Result<ReplayReport, ReplayError>
+	where
+		S: 'static,
+		F: FnMut(ClientHandle) -> S,
+		I: IntoIterator<Item=Result<CaptureRecord, CaptureError>>,
+	{
+		let log = Rc::new(RefCell::new(Vec::new()));
+		let event_log = server.add_middleware(EventLog {
+			events: Rc::downgrade(&log),
+		});
+		let result = self.replay(server, records, &log, &mut client_state_creator);
+		server.remove_middleware(event_log);
+		result
+	}
+
+	fn replay<S, F, I>(&self, server: &mut Server, records: I, log: &EventLogEntries, client_state_creator: &mut F) -> Result<ReplayReport, ReplayError>
+	where
+		S: 'static,
+		F: FnMut(ClientHandle) -> S,
+		I: IntoIterator<Item=Result<CaptureRecord, CaptureError>>,
+	{
+
+		let mut fakes: Vec<FakeClient> = Vec::new();
+		let mut serials = HashMap::new();
+		let mut report = ReplayReport {
+			requests: 0,
+			events: 0,
+			mismatches: Vec::new(),
+		};
+
+		for record in records {
+			let record = record?;
+			let index = match fakes.iter().position(|fake| fake.recorded_id == record.client) {
+				Some(index) => index,
+				None => {
+					let (ours, theirs) = UnixStream::pair().map_err(ReplayError::SocketPair)?;
+					let client = server.add_client(theirs, &mut *client_state_creator);
+					fakes.push(FakeClient {
+						recorded_id: record.client,
+						client,
+						stream: ours,
+						expected: VecDeque::new(),
+						actual: VecDeque::new(),
+					});
+					fakes.len() - 1
+				},
+			};
+
+			match record.direction {
+				MessageSide::Event => {
+					report.events += 1;
+					fakes[index].expected.push_back(record);
+				},
+				MessageSide::Request => {
+					report.requests += 1;
+					self.send_request(&fakes[index], &record, &serials)?;
+					server.dispatch(&mut *client_state_creator)?;
+					self.collect(&mut fakes, log, &mut serials, &mut report)?;
+				},
+			}
+		}
+
+		server.dispatch(&mut *client_state_creator)?;
+		self.collect(&mut fakes, log, &mut serials, &mut report)?;
+		for fake in &mut fakes {
+			for expected in fake.expected.drain(..) {
+				report.mismatches.push(Mismatch {
+					client: fake.recorded_id,
+					reason: format!("the server did not send event {} on object {}", expected.header.opcode, expected.header.sender),
+					expected: Some(expected),
+					actual: None,
+				});
+			}
+			for actual in fake.actual.drain(..) {
+				report.mismatches.push(Mismatch {
+					client: fake.recorded_id,
+					reason: format!("the server sent an unexpected event {}", actual),
+					expected: None,
+					actual: Some(actual),
+				});
+			}
+		}
+
+		// Hang up and let the server tear the fake clients down
+		drop(fakes);
+		server.dispatch(&mut *client_state_creator)?;
+
+		Ok(report)
+	}
+
+	fn is_serial(&self, name: &str) -> bool {
+		self.serial_args.iter().any(|serial| serial == name)
+	}
+
+	fn is_ignored(&self, name: &str) -> bool {
+		self.ignored_args.iter().any(|ignored| ignored == name)
+	}
+
+	fn send_request(&self, fake: &FakeClient, record: &CaptureRecord, serials: &HashMap<u32, u32>) -> Result<(), ReplayError> {
+		// Recorded fds are recreated as memfds, filled with their contents if those were recorded
+		let fds = record.fds.iter()
+			.map(|contents| create_memfd(contents.as_ref().map(|contents| contents.as_slice()).unwrap_or(&[])))
+			.collect::<Result<Vec<_>, _>>()?;
+		let mut raw = RawMessage {
+			header: record.header,
+			data: record.payload.clone(),
+			fds,
+		};
+
+		let interface = fake.client.get().ok().and_then(|client| client.object_interface(record.header.sender));
+		if let Some(request) = interface.as_ref().and_then(|interface| interface.request(record.header.opcode)) {
+			if let Ok(mut args) = DynMessage::parse_dyn_args(request.args, RawMessageReader::new(&raw)) {
+				for (desc, arg) in request.args.iter().zip(args.iter_mut()) {
+					if let DynArgument::Uint(ref mut serial) = *arg {
+						if self.is_serial(desc.name) {
+							*serial = serials.get(serial).copied().unwrap_or(*serial);
+						}
+					}
+				}
+				raw = DynMessage::new(record.header.sender, record.header.opcode, args).into_raw()?;
+			}
+		}
+
+		let result = send_message(&fake.stream, &raw).map_err(ReplayError::Socket);
+		for fd in raw.fds {
+			let _ = unistd::close(fd);
+		}
+		result
+	}
+
+	fn collect(&self, fakes: &mut [FakeClient], log: &EventLogEntries, serials: &mut HashMap<u32, u32>, report: &mut ReplayReport) -> Result<(), ReplayError> {
+		for (client, event) in log.borrow_mut().drain(..) {
+			if let Some(fake) = fakes.iter_mut().find(|fake| fake.client.get().map(|c| c.id() == client).unwrap_or(false)) {
+				fake.actual.push_back(event);
+			}
+		}
+
+		for fake in fakes {
+			drain_socket(&fake.stream)?;
+			while !fake.expected.is_empty() && !fake.actual.is_empty() {
+				let expected = fake.expected.pop_front().unwrap();
+				let actual = fake.actual.pop_front().unwrap();
+				if let Err(reason) = self.compare(&expected, &actual, serials) {
+					report.mismatches.push(Mismatch {
+						client: fake.recorded_id,
+						expected: Some(expected),
+						actual: Some(actual),
+						reason,
+					});
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn compare(&self, expected: &CaptureRecord, actual: &ReplayedEvent, serials: &mut HashMap<u32, u32>) -> Result<(), String> {
+		if expected.header.sender != actual.sender || expected.header.opcode != actual.opcode {
+			return Err(format!("expected event {} on object {}, got {}", expected.header.opcode, expected.header.sender, actual));
+		}
+		let event = actual.interface.event(actual.opcode).ok_or_else(|| format!("unknown event {}", actual))?;
+
+		let raw = RawMessage {
+			header: expected.header,
+			data: expected.payload.clone(),
+			fds: vec![-1; expected.fd_count()],
+		};
+		let expected_args = DynMessage::parse_dyn_args(event.args, RawMessageReader::new(&raw))
+			.map_err(|e| format!("failed to parse recorded event: {}", e))?;
+
+		for ((desc, expected_arg), actual_arg) in event.args.iter().zip(&expected_args).zip(&actual.args) {
+			if self.is_ignored(desc.name) {
+				continue;
+			}
+			match (expected_arg, actual_arg) {
+				(DynArgument::Uint(expected), DynArgument::Uint(actual)) if self.is_serial(desc.name) => {
+					match serials.get(expected) {
+						Some(mapped) if mapped != actual => {
+							return Err(format!("serial {} was replayed as {} before, but is now {}", expected, mapped, actual));
+						},
+						Some(_) => {},
+						None => {
+							serials.insert(*expected, *actual);
+						},
+					}
+				},
+				(DynArgument::Fd(_), DynArgument::Fd(_)) => {},
+				(expected_arg, actual_arg) if expected_arg == actual_arg => {},
+				_ => {
+					return Err(format!("argument {} of {}.{} differs: expected {:?}, got {:?}", desc.name, actual.interface.name, event.name, expected_arg, actual_arg));
+				},
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl Default for Replayer {
+	/// `callback_data` counts as a serial, since the server hands out serials for `wl_display.sync` callbacks.
+	fn default() -> Self {
+		Self {
+			serial_args: vec![String::from("serial"), String::from("callback_data")],
+			ignored_args: vec![String::from("time")],
+		}
+	}
+}
+
+/// An event the server sent while replaying.
+#[derive(Debug, Clone)]
+pub struct ReplayedEvent {
+	pub sender: u32,
+	pub opcode: u16,
+	pub interface: DynInterface,
+	pub args: Vec<DynArgument>,
+}
+
+impl fmt::Display for ReplayedEvent {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let message = self.interface.event(self.opcode).map(|event| event.name).unwrap_or("[unknown]");
+		write!(f, "{}@{}.{}", self.interface.name, self.sender, message)
+	}
+}
+
+/// A recorded event that the replayed server didn't send the same way.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+	/// The id of the client in the capture.
+	pub client: u32,
+	/// The recorded event, or `None` if the server sent an event that wasn't recorded.
+	pub expected: Option<CaptureRecord>,
+	/// The replayed event, or `None` if the server didn't send the recorded event.
+	pub actual: Option<ReplayedEvent>,
+	pub reason: String,
+}
+
+impl fmt::Display for Mismatch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "client {}: {}", self.client, self.reason)
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayReport {
+	/// The number of requests that were resent.
+	pub requests: usize,
+	/// The number of recorded events that were compared.
+	pub events: usize,
+	pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+	/// Whether the server sent exactly the recorded events.
+	pub fn is_match(&self) -> bool {
+		self.mismatches.is_empty()
+	}
+}
+
+impl fmt::Display for ReplayReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "replayed {} requests, compared {} events, {} mismatches", self.requests, self.events, self.mismatches.len())?;
+		for mismatch in &self.mismatches {
+			write!(f, "\n\t{}", mismatch)?;
+		}
+		Ok(())
+	}
+}
+
+struct FakeClient {
+	recorded_id: u32,
+	client: ClientHandle,
+	stream: UnixStream,
+	expected: VecDeque<CaptureRecord>,
+	actual: VecDeque<ReplayedEvent>,
+}
+
+// Events the server sent, with the id of the client they were sent to
+type EventLogEntries = Rc<RefCell<Vec<(u32, ReplayedEvent)>>>;
+
+/// Writes down every event the server sends while a replay is running.
+struct EventLog {
+	events: Weak<RefCell<Vec<(u32, ReplayedEvent)>>>,
+}
+
+impl Middleware for EventLog {
+	fn event(&mut self, client: &Client, sender: &Resource<Untyped>, opcode: u16, args: &mut Vec<DynArgument>) -> MiddlewareAction {
+		if let (Some(events), Some(object)) = (self.events.upgrade(), sender.object().ok()) {
+			events.borrow_mut().push((client.id(), ReplayedEvent {
+				sender: object.id,
+				opcode,
+				interface: object.interface.get(),
+				args: args.clone(),
+			}));
+		}
+		MiddlewareAction::Pass
+	}
+}
+
+fn create_memfd(contents: &[u8]) -> Result<RawFd, ReplayError> {
+	let name = CStr::from_bytes_with_nul(b"wl-replay\0").unwrap();
+	let fd = memfd::memfd_create(name, memfd::MemFdCreateFlag::MFD_CLOEXEC).map_err(ReplayError::Socket)?;
+	let mut written = 0;
+	while written < contents.len() {
+		match unistd::write(fd, &contents[written..]) {
+			Ok(n) => written += n,
+			Err(e) => {
+				let _ = unistd::close(fd);
+				return Err(ReplayError::Socket(e));
+			},
+		}
+	}
+	Ok(fd)
+}
+
+/// Throws away everything the server sent to a fake client, since the events were already seen by `EventLog`.
+fn drain_socket(stream: &UnixStream) -> Result<(), ReplayError> {
+	let mut buf = [0u8; 4096];
+	loop {
+		let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_FDS]);
+		let flags = socket::MsgFlags::MSG_DONTWAIT | socket::MsgFlags::MSG_CMSG_CLOEXEC;
+		match socket::recvmsg(stream.as_raw_fd(), &[IoVec::from_mut_slice(&mut buf)], Some(&mut cmsg_buf), flags) {
+			Ok(recv) => {
+				for cmsg in recv.cmsgs() {
+					if let socket::ControlMessageOwned::ScmRights(fds) = cmsg {
+						for fd in fds {
+							let _ = unistd::close(fd);
+						}
+					}
+				}
+				if recv.bytes == 0 {
+					return Ok(());
+				}
+			},
+			Err(nix::Error::Sys(Errno::EAGAIN)) => return Ok(()),
+			Err(e) => return Err(ReplayError::Socket(e)),
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+	#[error("Failed to read capture\n\t{0}")]
+	Capture(#[from] CaptureError),
+	#[error("Failed to create client socket\n\t{0}")]
+	SocketPair(#[source] std::io::Error),
+	#[error("Failed to talk to the server\n\t{0}")]
+	Socket(#[source] nix::Error),
+	#[error("Failed to serialize a replayed request\n\t{0}")]
+	Serialize(#[from] SerializeRawError),
+	#[error(transparent)]
+	Server(#[from] ServerError),
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		io::{self, Write},
+	};
+
+	use wl_common::{
+		capture::{CaptureReader},
+	};
+
+	use crate::{
+		BindContext, NewResource,
+		net::{SessionRecorder},
+		protocol::*,
+		testing::{TestClient},
+	};
+	use super::*;
+
+	#[derive(Clone, Default)]
+	struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.borrow_mut().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn server_with_compositor() -> Server {
+		let mut server = Server::new_without_socket(());
+		server.register_global::<WlCompositor, _>(|_: BindContext, new_resource: NewResource<WlCompositor>| {
+			new_resource.register_fn((), |_, _, _| {}, |_, _| {});
+		});
+		server
+	}
+
+	// Records a client listing the globals of a server with a compositor and syncing twice
+	fn record_session() -> Vec<u8> {
+		let buffer = SharedBuffer::default();
+		let mut server = server_with_compositor();
+		server.record_session(SessionRecorder::new(buffer.clone()).unwrap());
+		let mut client = TestClient::connect(&mut server, ());
+		client.get_registry().unwrap();
+		for _ in 0..2 {
+			let callback = client.new_id::<WlCallback>();
+			client.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+			client.dispatch(&mut server).unwrap();
+		}
+		server.stop_recording();
+		let capture = buffer.0.borrow().clone();
+		capture
+	}
+
+	fn replay(server: &mut Server, capture: &[u8]) -> ReplayReport {
+		let records = CaptureReader::new(io::Cursor::new(capture)).unwrap();
+		Replayer::new().run(server, records, |_| ()).unwrap()
+	}
+
+	#[test]
+	fn matching_sessions_replay_cleanly() {
+		let capture = record_session();
+		let mut server = server_with_compositor();
+		// Callback data only has to be consistent, not equal to what was recorded
+		for _ in 0..10 {
+			server.next_serial();
+		}
+
+		let report = replay(&mut server, &capture);
+		assert!(report.is_match(), "{}", report);
+		assert_eq!((report.requests, report.events), (3, 3));
+		// Running again works the same, since the first run cleaned up after itself
+		assert!(replay(&mut server, &capture).is_match());
+		assert_eq!(server.clients().count(), 0);
+	}
+
+	#[test]
+	fn differences_are_reported() {
+		let capture = record_session();
+		let mut server = Server::new_without_socket(());
+
+		// Without the global, every event after it is compared with the one recorded before it
+		let report = replay(&mut server, &capture);
+		assert_eq!(report.mismatches.len(), 3, "{}", report);
+		let mismatch = &report.mismatches[0];
+		assert_eq!(mismatch.expected.as_ref().map(|record| (record.header.sender, record.header.opcode)), Some((2, 0)));
+		assert_eq!(mismatch.actual.as_ref().map(|event| (event.sender, event.opcode)), Some((3, 0)));
+		assert!(report.mismatches[2].actual.is_none());
+	}
+}
@@ -1,9 +1,10 @@
 use std::{
 	fmt,
+	rc::{Rc},
 	marker::PhantomData,
 };
 
-use loaner::{Owner, Handle, Ref};
+use loaner::{Owner, Ref};
 
 use wl_common::{
 	interface::{Interface, Message},
@@ -11,15 +12,17 @@ use wl_common::{
 
 use crate::{
 	server::{State},
-	client::{Client, ClientMap},
-	object::{Object, ObjectImplementation, Dispatcher}, server::SendEventError,
+	client::{ClientHandle, ClientMap},
+	object::{Object, ObjectKey, ObjectImplementation, Dispatcher}, server::SendEventError,
+	arena::{DanglingError},
+	remote::{RemoteResource},
 };
 
 // TODO: rename to WlResource to avoid confusion? or make more confusion...
 #[derive(Clone)]
 pub struct Resource<I> {
-	client: Handle<Client>,
-	object: Handle<Object>,
+	client: ClientHandle,
+	object: ObjectKey,
 	interface: I,
 }
 
@@ -31,43 +34,88 @@ impl<I> Resource<I> {
 		&self.interface
 	}
 
-	pub fn client(&self) -> Handle<Client> {
+	pub fn client(&self) -> ClientHandle {
 		self.client.clone()
 	}
 
-	pub fn object(&self) -> Handle<Object> {
-		self.object.clone()
+	/// Returns the resource's object, or an error if the object was destroyed or its client disconnected.
+	pub fn object(&self) -> Result<Rc<Object>, DanglingError> {
+		self.client.object(self.object)
+	}
+
+	pub fn object_key(&self) -> ObjectKey {
+		self.object
+	}
+
+	pub fn is_alive(&self) -> bool {
+		self.object().is_ok()
+	}
+
+	/// Returns a new serial recorded as sent on this resource, for use in an event about to be sent.
+	pub fn next_serial(&self) -> Option<u32> {
+		let client = self.client.get().ok()?;
+		Some(client.next_serial(self))
+	}
+
+	/// The version of the interface this resource was created with.
+	pub fn version(&self) -> Option<u32> {
+		self.object().ok().map(|object| object.version.get())
 	}
 
 	pub fn is(&self, other: &Resource<I>) -> bool {
-		self.object.is(&other.object)
+		self.object == other.object && self.client.is(&other.client)
 	}
 
 	pub fn destroy(&self) {
-		if let Some(object) = self.object.get() {
-			object.destroy.set(true);
+		if let Ok(client) = self.client.get() {
+			client.objects.borrow_mut().mark_destroyed(self.object);
+		}
+	}
+
+	/// Sends a protocol error about this resource to its client, which is then disconnected.
+	pub fn post_error<C: Into<u32>>(&self, code: C, message: &str) {
+		if let Ok(client) = self.client.get() {
+			client.post_error(self.to_untyped(), code.into(), message);
 		}
 	}
 
 	pub fn get_data<'a, T: 'static>(&'a self) -> Option<Ref<'a, T>> {
-		self.object.get()?.data.borrow().downcast_ref::<Owner<T>>().map(|owner| owner.custom_ref())
+		self.object().ok()?.data.borrow().downcast_ref::<Owner<T>>().map(|owner| owner.custom_ref())
 	}
 
-	pub fn with<T, F: FnOnce(Ref<Object>) -> T>(&self, f: F) -> Option<T> {
-		self.object.get().map(f)
+	pub fn with<T, F: FnOnce(&Object) -> T>(&self, f: F) -> Option<T> {
+		self.object().ok().map(|object| f(&object))
+	}
+
+	/// Marks this resource as a child of `parent`. When the client disconnects, children are always torn down
+	/// before their parents.
+	pub fn set_parent<P>(&self, parent: &Resource<P>) {
+		if let Ok(client) = self.client.get() {
+			client.objects.borrow_mut().set_parent(self.object, parent.object);
+		}
+	}
+
+	pub fn parent(&self) -> Option<Resource<Untyped>> {
+		let parent = self.object().ok()?.parent.get()?;
+		Some(Resource::new_untyped(self.client.clone(), parent))
+	}
+
+	/// Returns a reference to this resource that can be sent to other threads and used with a `ServerHandle`.
+	pub fn remote(&self) -> Option<RemoteResource<I>> {
+		self.client.get().ok()?.remote_resource(self)
 	}
 
 	pub fn to_untyped(&self) -> Resource<Untyped> {
 		Resource {
 			client: self.client.clone(),
-			object: self.object.clone(),
+			object: self.object,
 			interface: Untyped,
 		}
 	}
 }
 
 impl<I: Interface> Resource<I> {
-	pub(crate) fn new(client: Handle<Client>, object: Handle<Object>) -> Self {
+	pub(crate) fn new(client: ClientHandle, object: ObjectKey) -> Self {
 		Self {
 			client,
 			object,
@@ -79,12 +127,26 @@ impl<I: Interface> Resource<I> {
 impl<I: Interface + 'static> Resource<I> where I::Request: Message<ClientMap=ClientMap> + fmt::Debug {
 	pub fn set_implementation<Impl: ObjectImplementation<I> + 'static>(&self, implementation: Impl) {
 		let dispatcher = Dispatcher::new(implementation);
-		if let Some(object) = self.object.get() {
+		if let Ok(object) = self.object() {
 			*object.dispatcher.borrow_mut() = Some(dispatcher);
 		};
 	}
 }
 
+impl<I: Interface + 'static> Resource<I> {
+	/// Adds a callback that runs when this resource is destroyed, either by request or because its client went away.
+	/// Listeners run before the resource's own destructor, while the object and its data are still reachable.
+	pub fn add_destroy_listener<F: FnOnce(&mut State, Resource<I>) + 'static>(&self, listener: F) {
+		if let Ok(object) = self.object() {
+			object.destroy_listeners.borrow_mut().add(Box::new(move |state, this: Resource<Untyped>| {
+				if let Some(this) = this.downcast::<I>() {
+					listener(state, this);
+				}
+			}));
+		}
+	}
+}
+
 impl<I: Interface> Resource<I> where I::Event: Message<ClientMap=ClientMap> + fmt::Debug {
 	pub fn send_event(&self, event: I::Event) {
 		match self.try_send_event(event) {
@@ -105,15 +167,15 @@ impl<I: Interface> Resource<I> where I::Event: Message<ClientMap=ClientMap> + fm
 			event=event
 		); */
 		
-		let client = self.client.get().ok_or(SendEventError::ClientMissing)?;
-		client.try_send_event::<I>(self.object.clone(), event)?;
+		let client = self.client.get().map_err(|_| SendEventError::ClientMissing)?;
+		client.try_send_event::<I>(self.object, event)?;
 
 		Ok(())
 	}
 }
 
 impl Resource<Untyped> {
-	pub(crate) fn new_untyped(client: Handle<Client>, object: Handle<Object>) -> Self {
+	pub(crate) fn new_untyped(client: ClientHandle, object: ObjectKey) -> Self {
 		Resource {
 			client,
 			object,
@@ -122,7 +184,7 @@ impl Resource<Untyped> {
 	}
 
 	pub fn downcast<I: Interface>(&self) -> Option<Resource<I>> {
-		let object = self.object.get()?;
+		let object = self.object().ok()?;
 		// TODO: version/subset checking too?
 		if I::as_dyn() == object.interface.get() {
 			Some(self.downcast_unchecked())
@@ -134,7 +196,7 @@ impl Resource<Untyped> {
 	fn downcast_unchecked<I: Interface>(&self) -> Resource<I> {
 		Resource {
 			client: self.client.clone(),
-			object: self.object.clone(),
+			object: self.object,
 			interface: I::new(),
 		}
 	}
@@ -142,19 +204,16 @@ impl Resource<Untyped> {
 
 impl<I> fmt::Debug for Resource<I> {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		match (self.client.get(), self.object.get()) {
-			(Some(client), Some(object)) => {
+		// An object can't outlive its client, so a dead client means a dead object
+		match (self.client.get(), self.object()) {
+			(Ok(client), Ok(object)) => {
 				let interface = object.interface.get();
 				write!(f, "Resource@{}({}@{})", client.id(), interface.name, object.id)
 			},
-			(None, Some(object)) => {
-				let interface = object.interface.get();
-				write!(f, "Resource@<dead>({}@{})", interface.name, object.id)
-			},
-			(Some(client), None) => {
+			(Ok(client), Err(_)) => {
 				write!(f, "Resource@{}(<dead>)", client.id())
 			},
-			(None, None) => {
+			(Err(_), _) => {
 				write!(f, "Resource@<dead>(<dead>)")
 			}
 		}
@@ -164,24 +223,36 @@ impl<I> fmt::Debug for Resource<I> {
 // This is close to a resource owner. Exactly one gets created for every protocol object, unlike `Resource`.
 #[derive(Debug)]
 pub struct NewResource<I> {
-	pub(crate) client: Handle<Client>,
-	pub(crate) object: Handle<Object>,
+	pub(crate) client: ClientHandle,
+	pub(crate) object: ObjectKey,
 	_phantom: PhantomData<I>,
 }
 
 impl<I> NewResource<I> {
-	pub(crate) fn new(client: Handle<Client>, object: Handle<Object>) -> Self {
+	pub(crate) fn new(client: ClientHandle, object: ObjectKey) -> Self {
 		Self {
 			client,
 			object,
 			_phantom: PhantomData,
 		}
 	}
+
+	pub fn client(&self) -> ClientHandle {
+		self.client.clone()
+	}
+
+	pub fn object(&self) -> Result<Rc<Object>, DanglingError> {
+		self.client.object(self.object)
+	}
+
+	pub fn version(&self) -> Option<u32> {
+		self.object().ok().map(|object| object.version.get())
+	}
 }
 
 impl NewResource<Untyped> {
 	pub(crate) fn downcast<I: Interface>(self) -> Option<NewResource<I>> {
-		let object = self.object.get()?;
+		let object = self.object().ok()?;
 		// TODO: version/subset checking too?
 		if I::as_dyn() == object.interface.get() {
 			Some(NewResource {
@@ -197,7 +268,7 @@ impl NewResource<Untyped> {
 
 impl<I: Interface + 'static> NewResource<I> where I::Request: Message<ClientMap=ClientMap> + fmt::Debug {
 	pub fn register<Impl: ObjectImplementation<I> + 'static, T: 'static>(self, data: T, implementation: Impl) -> Resource<I> {
-		if let Some(object) = self.object.get() {
+		if let Ok(object) = self.object() {
 			let dispatcher = Dispatcher::new(implementation);
 			*object.dispatcher.borrow_mut() = Some(dispatcher);
 			object.set_data(data);
@@ -230,3 +301,35 @@ impl<I: Interface, F, D> ObjectImplementation<I> for ObjectImplementationFn<I, F
         (self.destructor)(state, this)
     }
 }
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		Server,
+		arena::{DanglingError},
+		protocol::*,
+		testing::{TestClient},
+	};
+
+	#[test]
+	fn resources_of_disconnected_clients_are_dangling() {
+		let mut server = Server::new_without_socket(());
+		let mut client = TestClient::connect(&mut server, ());
+		let registry_id = client.get_registry().unwrap();
+		client.dispatch(&mut server).unwrap();
+		let registry = server.resources::<WlRegistry>().next().unwrap();
+		assert!(registry.is_alive());
+
+		let id = client.new_id_untyped::<WlCompositor>(1);
+		client.send(registry_id, WlRegistryRequest::Bind(wl_registry::BindRequest { name: 42, id })).unwrap();
+		client.dispatch(&mut server).unwrap();
+		client.expect_disconnected();
+
+		// The new client takes over the old client's slot, which the old resource must not resolve to
+		let _other = TestClient::connect(&mut server, ());
+		assert_eq!(registry.object().err(), Some(DanglingError::Client));
+		assert!(registry.client().get().is_err());
+		assert_eq!(format!("{:?}", registry), "Resource@<dead>(<dead>)");
+		registry.post_error(wl_display::Error::InvalidObject, "gone");
+	}
+}
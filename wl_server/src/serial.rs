@@ -0,0 +1,146 @@
+use std::{
+	collections::{HashMap, VecDeque},
+};
+
+/// How many serial-bearing events are remembered per client and interface before the oldest ones are forgotten.
+const SERIAL_HISTORY: usize = 64;
+
+/// Returns true if serial `a` was handed out after serial `b`, taking wrapping into account.
+pub fn serial_is_after(a: u32, b: u32) -> bool {
+	let distance = a.wrapping_sub(b);
+	distance != 0 && distance < 1 << 31
+}
+
+/// A serial that was sent to a client along with the object it was sent on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialRecord {
+	pub serial: u32,
+	pub interface: &'static str,
+	pub object: u32,
+}
+
+/// Hands out wrapping serials and remembers which serials were sent to which client, so that requests carrying
+/// a serial (e.g. `wl_data_device.set_selection`, `xdg_toplevel.move`, `xdg_surface.ack_configure`) can be
+/// checked against events the client actually received.
+///
+/// Each interface has its own history, so a burst of input serials can't push out a pending
+/// `xdg_surface.configure`.
+#[derive(Debug)]
+pub struct SerialManager {
+	next_serial: u32,
+	history: HashMap<u32, HashMap<&'static str, VecDeque<SerialRecord>>>,
+}
+
+impl SerialManager {
+	pub(crate) fn new() -> Self {
+		Self {
+			next_serial: 1,
+			history: HashMap::new(),
+		}
+	}
+
+	/// Returns a new serial without recording it for any client. Serials wrap around and skip zero.
+	pub fn next_serial(&mut self) -> u32 {
+		let serial = self.next_serial;
+		self.next_serial = self.next_serial.wrapping_add(1);
+		if self.next_serial == 0 {
+			self.next_serial = 1;
+		}
+		serial
+	}
+
+	/// Returns a new serial and records that it was sent to `client` on the given object.
+	pub fn next_serial_for(&mut self, client: u32, interface: &'static str, object: u32) -> u32 {
+		let serial = self.next_serial();
+		self.record(client, SerialRecord {
+			serial,
+			interface,
+			object,
+		});
+		serial
+	}
+
+	pub fn record(&mut self, client: u32, record: SerialRecord) {
+		let history = self.history.entry(client).or_default().entry(record.interface).or_default();
+		if history.len() >= SERIAL_HISTORY {
+			history.pop_front();
+		}
+		history.push_back(record);
+	}
+
+	/// Looks up a serial that was previously sent to `client`.
+	pub fn find(&self, client: u32, serial: u32) -> Option<SerialRecord> {
+		self.history.get(&client)?.values()
+			.find_map(|history| history.iter().rev().find(|record| record.serial == serial))
+			.copied()
+	}
+
+	pub fn is_valid(&self, client: u32, serial: u32) -> bool {
+		self.find(client, serial).is_some()
+	}
+
+	/// Returns the most recent serial sent to `client` on an object with the given interface.
+	pub fn latest(&self, client: u32, interface: &str) -> Option<SerialRecord> {
+		self.history.get(&client)?.get(interface)?.back().copied()
+	}
+
+	pub(crate) fn remove_client(&mut self, client: u32) {
+		self.history.remove(&client);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		Server,
+		protocol::*,
+		testing::{TestClient},
+	};
+	use super::*;
+
+	#[test]
+	fn serials_wrap_around_and_skip_zero() {
+		let mut serial_manager = SerialManager::new();
+		serial_manager.next_serial = u32::MAX;
+		assert_eq!(serial_manager.next_serial(), u32::MAX);
+		assert_eq!(serial_manager.next_serial(), 1);
+		assert!(serial_is_after(1, u32::MAX));
+		assert!(!serial_is_after(u32::MAX, 1));
+		assert!(!serial_is_after(1, 1));
+	}
+
+	#[test]
+	fn history_forgets_the_oldest_serials() {
+		let mut serial_manager = SerialManager::new();
+		let serials = (0..SERIAL_HISTORY + 1).map(|_| serial_manager.next_serial_for(1, "wl_pointer", 3)).collect::<Vec<_>>();
+		assert!(!serial_manager.is_valid(1, serials[0]));
+		assert!(serial_manager.is_valid(1, serials[SERIAL_HISTORY]));
+		assert!(!serial_manager.is_valid(2, serials[SERIAL_HISTORY]));
+	}
+
+	#[test]
+	fn history_is_kept_per_interface() {
+		let mut serial_manager = SerialManager::new();
+		let configure = serial_manager.next_serial_for(1, "xdg_surface", 4);
+		for _ in 0..SERIAL_HISTORY * 2 {
+			serial_manager.next_serial_for(1, "wl_keyboard", 5);
+		}
+		assert_eq!(serial_manager.find(1, configure).map(|record| record.object), Some(4));
+		assert_eq!(serial_manager.latest(1, "xdg_surface").map(|record| record.serial), Some(configure));
+	}
+
+	#[test]
+	fn sync_callback_data_is_not_recorded() {
+		let mut server = Server::new_without_socket(());
+		let mut client = TestClient::connect(&mut server, ());
+		let callback = client.new_id::<WlCallback>();
+		client.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+		client.dispatch(&mut server).unwrap();
+
+		let serial = match client.expect_event::<WlCallback>(2) {
+			WlCallbackEvent::Done(done) => done.callback_data,
+		};
+		assert_ne!(serial, 0);
+		assert_eq!(client.client().get().unwrap().validate_serial(serial), None);
+	}
+}
@@ -12,39 +12,62 @@ use std::{
 	ffi::{CString},
 	collections::{HashMap, VecDeque},
 	cell::{RefCell},
+	rc::{Rc},
 	any::{Any},
 	sync::{
 		atomic::{Ordering, AtomicBool},
 	},
+	path::{Path},
+	time::{Instant, Duration},
 	env,
 	fmt,
+	panic::{self, AssertUnwindSafe},
 };
 
-use loaner::{Owner, Handle, Ref};
+use loaner::{Owner, Handle};
 use thiserror::{Error};
 
 use wl_common::{
-	wire::{RawMessageReader, SerializeRawError, ParseDynError, RawMessage},
-	interface::{Interface, IntoArgsError},
+	wire::{RawMessageReader, SerializeRawError, ParseDynError, RawMessage, DynArgument, DynMessage},
+	interface::{Interface, IntoArgsError, MessageSide},
+	debug::{DebugMessage},
 };
 
 use crate::{
-	net::{NetServer, NetError, ClientEvent, ClientEventPayload},
-	client::{Client, ClientManager},
-	global::{GlobalImplementation, GlobalManager, Global}, object::Object, Resource,
+	net::{NetServer, NetClient, NetError, ClientEvent, ClientEventPayload, SessionRecorder, ClientRecorder},
+	client::{Client, ClientManager, ClientHandle},
+	global::{GlobalImplementation, GlobalManager, Global}, object::ObjectKey, Resource, Untyped,
+	arena::{DanglingError},
+	serial::{SerialManager},
+	middleware::{Middleware, MiddlewareChain, MiddlewareAction, MiddlewareId},
+	limits::{ClientLimits, ClientLimit},
+	introspect::{self, ServerSnapshot, DebugSocket},
+	metrics::{ServerMetrics, RequestCounters},
+	remote::{RemoteQueue, ServerHandle, StopHandle},
+	protocol::{wl_display},
 };
 
 pub(crate) static REQUEST_DEBUG: AtomicBool = AtomicBool::new(false);
 pub(crate) static RAW_REQUEST_DEBUG: AtomicBool = AtomicBool::new(false);
 pub(crate) static EVENT_DEBUG: AtomicBool = AtomicBool::new(false);
 pub(crate) static RAW_EVENT_DEBUG: AtomicBool = AtomicBool::new(false);
+pub(crate) static WAYLAND_DEBUG: AtomicBool = AtomicBool::new(false);
 
 pub(crate) fn request_debug() -> bool { REQUEST_DEBUG.load(Ordering::Relaxed) }
 pub(crate) fn raw_request_debug() -> bool { RAW_REQUEST_DEBUG.load(Ordering::Relaxed) }
 pub(crate) fn event_debug() -> bool { EVENT_DEBUG.load(Ordering::Relaxed) }
 pub(crate) fn raw_event_debug() -> bool { RAW_EVENT_DEBUG.load(Ordering::Relaxed) }
+pub(crate) fn wayland_debug() -> bool { WAYLAND_DEBUG.load(Ordering::Relaxed) }
 
 fn set_debug_switches() {
+	// Same rules as libwayland's server side
+	if let Some(var) = env::var_os("WAYLAND_DEBUG") {
+		let var = var.to_string_lossy();
+		if var.contains('1') || var.contains("server") {
+			WAYLAND_DEBUG.store(true, Ordering::Relaxed);
+		}
+	}
+
 	if let Some(var) = env::var_os("WL_DEBUG") {
 		REQUEST_DEBUG.store(true, Ordering::Relaxed);
 		EVENT_DEBUG.store(true, Ordering::Relaxed);
@@ -102,29 +125,57 @@ pub struct Server {
 	net: NetServer,
 	client_manager: Owner<RefCell<ClientManager>>,
 	global_manager: Owner<RefCell<GlobalManager>>,
-	next_serial: u32,
+	serial_manager: Owner<RefCell<SerialManager>>,
+	middleware: Owner<RefCell<MiddlewareChain>>,
+	debug_socket: Option<DebugSocket>,
+	remote: RemoteQueue,
+	// The requests of every client, kept after they disconnect
+	request_totals: RequestCounters,
 }
 
 impl Server {
 	pub fn new<S: 'static>(state: S) -> Result<Self, ServerCreateError> {
-		set_debug_switches();
-
 		let net = NetServer::new()?;
+		Ok(Self::with_net(net, state))
+	}
+
+	/// Creates a server listening on the socket at `path`.
+	pub fn bind<P: AsRef<Path>, S: 'static>(path: P, state: S) -> Result<Self, ServerCreateError> {
+		let net = NetServer::bind(path.as_ref())?;
+		Ok(Self::with_net(net, state))
+	}
+
+	/// Creates a server that doesn't bind a socket. Clients are connected with `Server::add_client`, as the
+	/// `testing` module does.
+	pub fn new_without_socket<S: 'static>(state: S) -> Self {
+		Self::with_net(NetServer::without_socket(), state)
+	}
+
+	fn with_net<S: 'static>(net: NetServer, state: S) -> Self {
+		set_debug_switches();
 
 		let client_manager = Owner::new(RefCell::new(ClientManager::new()));
 		let global_manager = Owner::new(RefCell::new(GlobalManager::new(client_manager.handle())));
+		let serial_manager = Owner::new(RefCell::new(SerialManager::new()));
 		client_manager.borrow_mut().set_global_manager(global_manager.handle());
+		client_manager.borrow_mut().set_serial_manager(serial_manager.handle());
+		let middleware = Owner::new(RefCell::new(MiddlewareChain::new()));
+		client_manager.borrow_mut().set_middleware(middleware.handle());
 		client_manager.borrow_mut().set_this(client_manager.handle());
 
 		let state = State::new(state);
 
-		Ok(Self {
+		Self {
 			state,
 			net,
 			client_manager,
 			global_manager,
-			next_serial: 1,
-		})
+			serial_manager,
+			middleware,
+			debug_socket: None,
+			remote: RemoteQueue::new(),
+			request_totals: RequestCounters::default(),
+		}
 	}
 
 	// TODO!: accept and propagate version number
@@ -132,149 +183,547 @@ impl Server {
 		self.global_manager.borrow_mut().add_global(global_implementation)
 	}
 
-	pub fn run<S: 'static, F: FnMut(Handle<Client>) -> S>(&mut self, mut client_state_creator: F) -> Result<(), ServerError> {
-		loop {
+	/// Sets the limits applied to clients that connect from now on. Use `Client::set_limits` to change the
+	/// limits of a connected client.
+	pub fn set_default_client_limits(&mut self, limits: ClientLimits) {
+		self.client_manager.borrow_mut().default_limits = limits;
+	}
+
+	/// Turns tracing on or off for every connected client and for clients that connect from now on. Tracing starts
+	/// out enabled if `WL_DEBUG`, `WL_REQUEST_DEBUG` or `WL_EVENT_DEBUG` is set.
+	pub fn set_tracing(&mut self, enabled: bool) {
+		self.client_manager.borrow_mut().default_tracing = enabled;
+		for client in self.clients() {
+			if let Ok(client) = client.get() {
+				client.set_tracing(enabled);
+			}
+		}
+	}
+
+	/// Turns tracing on or off for a single client.
+	pub fn set_client_tracing(&self, client: &ClientHandle, enabled: bool) {
+		if let Ok(client) = client.get() {
+			client.set_tracing(enabled);
+		}
+	}
+
+	/// Records the traffic of every connected client and of clients that connect from now on to one capture.
+	pub fn record_session(&mut self, recorder: SessionRecorder) {
+		let recorder = Rc::new(RefCell::new(recorder));
+		self.client_manager.borrow_mut().default_recorder = Some(Rc::clone(&recorder));
+		for client in self.clients() {
+			if let Ok(client) = client.get() {
+				client.net.borrow_mut().recorder = Some(ClientRecorder {
+					client: client.id(),
+					recorder: Rc::clone(&recorder),
+				});
+			}
+		}
+	}
+
+	/// Stops recording every client, including clients that were given their own recorder.
+	pub fn stop_recording(&mut self) {
+		self.client_manager.borrow_mut().default_recorder = None;
+		for client in self.clients() {
+			if let Ok(client) = client.get() {
+				client.stop_recording();
+			}
+		}
+	}
+
+	/// Adds a middleware to the end of the chain that sees every request and event.
+	pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) -> MiddlewareId {
+		self.middleware.borrow_mut().add(Box::new(middleware))
+	}
+
+	/// Removes a middleware from the chain, returning it if it was still there.
+	pub fn remove_middleware(&mut self, id: MiddlewareId) -> Option<Box<dyn Middleware>> {
+		self.middleware.borrow_mut().remove(id)
+	}
+
+	/// Returns handles to every connected client, in the order they connected.
+	pub fn clients(&self) -> impl Iterator<Item=ClientHandle> {
+		let clients = self.client_manager.borrow().clients().collect::<Vec<_>>();
+		clients.into_iter()
+	}
+
+	/// Returns every live resource with the interface `I` across all clients.
+	pub fn resources<I: Interface>(&self) -> impl Iterator<Item=Resource<I>> {
+		let resources = self.clients()
+			.filter_map(|client| client.get().ok().map(|client| client.resources::<I>().collect::<Vec<_>>()))
+			.flatten()
+			.collect::<Vec<_>>();
+		resources.into_iter()
+	}
+
+	/// Returns a handle that other threads can use to run code on the server thread.
+	pub fn handle(&self) -> ServerHandle {
+		self.remote.handle()
+	}
+
+	/// Returns a handle that makes `run` shut the server down and return.
+	pub fn stop_handle(&self) -> StopHandle {
+		self.remote.stop_handle()
+	}
+
+	/// Whether a `StopHandle` or `shutdown` asked the server to stop, for servers that call `dispatch` themselves.
+	pub fn stop_requested(&self) -> bool {
+		self.remote.stop_requested()
+	}
+
+	/// A file descriptor that becomes readable when a `ServerHandle` queued work, for servers that are dispatched
+	/// from another event loop. `Server::wait` already includes it.
+	pub fn wake_fd(&self) -> RawFd {
+		self.remote.fd()
+	}
+
+	/// Blocks until there is something for `dispatch` to do or until `timeout` passes.
+	pub fn wait(&mut self, timeout: Option<Duration>) -> Result<(), ServerError> {
+		let mut fds = vec![self.remote.fd()];
+		fds.extend(self.debug_socket_fd());
+		let writable_fds = self.debug_connection_fds();
+		self.net.wait(&self.client_manager.borrow(), &fds, &writable_fds, timeout)?;
+		Ok(())
+	}
+
+	#[cfg(feature = "tokio")]
+	pub(crate) fn listener_fd(&self) -> Option<RawFd> {
+		self.net.listener_fd()
+	}
+
+	pub(crate) fn debug_socket_fd(&self) -> Option<RawFd> {
+		self.debug_socket.as_ref().map(DebugSocket::fd)
+	}
+
+	/// Debug socket connections that are waiting to be written the rest of their snapshot.
+	pub(crate) fn debug_connection_fds(&self) -> Vec<RawFd> {
+		self.debug_socket.as_ref().map(DebugSocket::pending_fds).unwrap_or_default()
+	}
+
+	/// Dispatches the server until it's stopped with a `StopHandle` or `shutdown`, then shuts it down.
+	pub fn run<S: 'static, F: FnMut(ClientHandle) -> S>(&mut self, mut client_state_creator: F) -> Result<(), ServerError> {
+		while !self.stop_requested() {
+			if let Err(e) = self.wait(None) {
+				log::error!("{}", e);
+			}
+			if self.stop_requested() {
+				break;
+			}
 			match self.dispatch(&mut client_state_creator) {
 				Ok(()) => {},
 				Err(e) => log::error!("{}", e),
 			}
 		}
+		self.shutdown();
+		Ok(())
 	}
 
-	pub fn dispatch<S: 'static, F: FnMut(Handle<Client>) -> S>(&mut self, mut client_state_creator: F) -> Result<(), ServerError> {
+	/// Stops accepting clients and removes the socket, runs the destructors of every client's objects, flushes the
+	/// events that are left and disconnects every client. `run` returns after this was called, for example from a
+	/// request handler or a `ServerHandle` task.
+	pub fn shutdown(&mut self) {
+		self.remote.request_stop();
+		self.net.close();
+		self.debug_socket = None;
+
+		// Destructors can send events to other clients, so they all run before any client is disconnected
+		let clients = self.client_manager.borrow().live_clients();
+		for client in &clients {
+			self.teardown_objects(client);
+		}
+		for client in clients {
+			match client.net.borrow_mut().flush() {
+				Ok(true) => {},
+				Ok(false) => log::warn!("Could not flush every event to client {} before shutting down", client.id()),
+				Err(e) => log::warn!("Failed to flush events to client {} before shutting down: {}", client.id(), e),
+			}
+			let _ = self.client_manager.borrow_mut().remove_client(&client.handle());
+		}
+		self.client_manager.borrow_mut().remote_resources.remove_destroyed();
+	}
+
+	pub fn dispatch<S: 'static, F: FnMut(ClientHandle) -> S>(&mut self, mut client_state_creator: F) -> Result<(), ServerError> {
 		self.client_manager.borrow().flush_clients()?;
 
-		match self.try_accept(&mut client_state_creator) {
-			Ok(Some(client)) => log::info!("Client {} connected", client.id()),
-			Ok(None) => {},
-			Err(e) => log::error!("Client connection error: {:?}", e),
+		// Everyone waiting is accepted, as the listener may only be reported readable once for all of them
+		loop {
+			match self.try_accept(&mut client_state_creator) {
+				Ok(Some(client)) => log::info!("Client {} connected", client.id()),
+				Ok(None) => break,
+				Err(e) => {
+					log::error!("Client connection error: {:?}", e);
+					break;
+				},
+			}
 		}
+
+		while let Some(task) = self.remote.next_task() {
+			task(self);
+		}
+		self.destroy_pending();
 		
-		let client_event = self.net.poll_clients(&mut *self.client_manager.borrow_mut())?;
-		if let Some(ClientEvent {
-			client,
-			payload,
-		}) = client_event {
-			let client = client.get().expect("Client doesn't exist");
+		for client in self.clients() {
+			if let Ok(client) = client.get() {
+				client.requests_this_dispatch.set(0);
+			}
+		}
+
+		// Handle everything the clients have sent so far. A client flooding requests is stopped by its
+		// `max_requests_per_dispatch` limit.
+		loop {
+			let client_event = self.net.poll_clients(&mut self.client_manager.borrow_mut())?;
+			let ClientEvent {
+				client,
+				payload,
+			} = match client_event {
+				Some(client_event) => client_event,
+				None => break,
+			};
+			// A client that is already gone has nothing left to handle
+			let client = match client.get() {
+				Ok(client) => client,
+				Err(_) => continue,
+			};
 			match payload {
 				ClientEventPayload::ClientDisconnected => self.handle_client_disconnect(client)?,
 				ClientEventPayload::Message(msg) => self.handle_client_message(client, msg)?,
+				ClientEventPayload::LimitExceeded(limit) => client.post_limit_error(limit),
+				ClientEventPayload::Malformed(e) => {
+					log::warn!("Disconnecting client {}: {}", client.id(), e);
+					self.handle_client_disconnect(client)?;
+				},
 			}
+
+			self.destroy_pending();
 		}
 
-		self.destroy_pending();
+		self.disconnect_errored()?;
+		self.client_manager.borrow_mut().remote_resources.remove_destroyed();
+
+		if let Some(ref debug_socket) = self.debug_socket {
+			debug_socket.serve(|| self.snapshot());
+		}
 
 		Ok(())
 	}
 
-	pub fn handle_client_disconnect(&mut self, client: Ref<Client>) -> Result<(), ServerError> {
+	pub fn handle_client_disconnect(&mut self, client: Rc<Client>) -> Result<(), ServerError> {
 		log::info!("Client {} disconnected", client.id());
 		self.cleanup_client(client)?;
 
 		Ok(())
 	}
 
-	pub fn handle_client_message(&mut self, client: Ref<Client>, raw: RawMessage) -> Result<(), ServerError> {
+	pub fn handle_client_message(&mut self, client: Rc<Client>, raw: RawMessage) -> Result<(), ServerError> {
 		if raw_request_debug() {
 			log::debug!("client: {}, sender: {}, opcode: {}, len: {}\n\tcontents: {:?}", client.id(), raw.header.sender, raw.header.opcode, raw.header.msg_size, raw.data);
 		}
 
-		let resource = match client.find_by_id_untyped(raw.header.sender) {
-			Some(resource) => resource,
-			None => return Err(ServerError::RequestReceiverDoesntExist),
+		if client.error_posted() {
+			return Ok(());
+		}
+
+		let limits = client.limits();
+		client.requests_this_dispatch.set(client.requests_this_dispatch.get() + 1);
+		if client.requests_this_dispatch.get() > limits.max_requests_per_dispatch {
+			client.post_limit_error(ClientLimit::Requests);
+			return Ok(());
+		}
+
+		// A destroyed object is gone from the client's ids, so a request sent before the client learned of the
+		// destruction ends up here too
+		let resource = client.find_by_id_untyped(raw.header.sender);
+		let (resource, object) = match resource.and_then(|resource| Some((resource.object().ok()?, resource))) {
+			Some((object, resource)) => (resource, object),
+			None => {
+				close_fds(&raw.fds);
+				client.post_display_error(wl_display::Error::InvalidObject, &format!("invalid object {}", raw.header.sender));
+				return Ok(());
+			},
 		};
-		let object_handle = resource.object();
-		// This will fail if the client has sent a request before learning of the object's destruction
-		let object = object_handle.get().ok_or(ServerError::RequestReceiverDoesntExist)?;
 
 		let reader = RawMessageReader::new(&raw);
 		let opcode = raw.header.opcode;
-		let args = wl_common::wire::DynMessage::parse_dyn_args(object.interface.get().requests[raw.header.opcode as usize], reader)?;
-
-		// wtf
-		if false {} else {
-			if let Some(dispatcher) = &mut *object.dispatcher.borrow_mut() {
-				match dispatcher.dispatch(&mut self.state, resource.clone(), opcode, args) {
-					Ok(_) => {},
-					Err(e) => {
-						log::error!("Failed to dispatch object request: {}", e);
-					}
+		let interface = object.interface.get();
+		let request = match interface.request(opcode) {
+			Some(request) => request,
+			None => {
+				close_fds(&raw.fds);
+				client.post_error(resource, wl_display::Error::InvalidMethod.into(), &format!("invalid method {}, object {}@{}", opcode, interface.name, object.id));
+				return Ok(());
+			},
+		};
+		client.net.borrow_mut().counters.record_request(interface.name, opcode, request.name);
+		self.request_totals.record_request(interface.name, opcode, request.name);
+
+		let span = if client.tracing_enabled() {
+			tracing::debug_span!(
+				parent: &client.span,
+				"request",
+				client = client.id(),
+				pid = client.credentials().map(|credentials| credentials.pid).unwrap_or(0),
+				object = object.id,
+				interface = interface.name,
+				opcode,
+				message = request.name,
+			)
+		} else {
+			tracing::Span::none()
+		};
+		let _entered = span.enter();
+
+		let mut args = match DynMessage::parse_dyn_args(request.args, reader) {
+			Ok(args) => args,
+			Err(e) => {
+				log::debug!("Failed to parse request from client {}: {}", client.id(), e);
+				close_fds(&raw.fds);
+				match e {
+					ParseDynError::NullInterfaceName | ParseDynError::InvalidInterfaceName => {
+						client.post_error(resource, wl_display::Error::InvalidObject.into(), &format!("invalid interface for new object in {}@{}.{}", interface.name, object.id, request.name));
+					},
+					_ => {
+						client.post_error(resource, wl_display::Error::InvalidMethod.into(), &format!("invalid arguments for {}@{}.{}", interface.name, object.id, request.name));
+					},
 				}
-			} else {
-				log::error!("Received a request for an object with no associated dispatcher");
-			}
+				return Ok(());
+			},
+		};
+
+		let new_objects = args.iter().filter(|arg| matches!(arg, DynArgument::NewId(..))).count();
+		if client.objects.borrow().len() + new_objects > limits.max_objects {
+			client.post_limit_error(ClientLimit::Objects);
+			return Ok(());
+		}
+
+		let action = self.middleware.borrow_mut().request(&client, &resource, opcode, &mut args);
+
+		if wayland_debug() {
+			let message = DynMessage::new(raw.header.sender, opcode, args.clone());
+			eprintln!("{}", DebugMessage::new(&interface, &message, MessageSide::Request)
+				.discarded(action != MiddlewareAction::Pass)
+				.object_interface(|id| client.object_interface_name(id)));
+		}
+
+		match action {
+			MiddlewareAction::Pass => {},
+			MiddlewareAction::Drop => return Ok(()),
+			MiddlewareAction::Error { code, message } => {
+				client.post_error(resource, code, &message);
+				return Ok(());
+			},
+		}
 
-			if object.destroy.get() {
-				self.destroy_object(client, object);
+		// The dispatcher borrow is taken outside of the unwind boundary so it is released normally even if the
+		// handler panics
+		if let Some(dispatcher) = &mut *object.dispatcher.borrow_mut() {
+			let state = &mut self.state;
+			let start = Instant::now();
+			let result = catch_handler_panic(&client, &resource, || dispatcher.dispatch(state, resource.clone(), opcode, args));
+			let latency = start.elapsed();
+			client.net.borrow_mut().counters.record_dispatch(interface.name, opcode, request.name, latency);
+			self.request_totals.record_dispatch(interface.name, opcode, request.name, latency);
+			match result {
+				Some(Ok(_)) => {},
+				Some(Err(e)) => {
+					log::error!("Failed to dispatch object request: {}", e);
+				},
+				None => {},
 			}
+		} else {
+			log::error!("Received a request for an object with no associated dispatcher");
+		}
+
+		if object.destroy.get() {
+			self.destroy_object(&client, resource.object_key());
 		}
 		
 		Ok(())
 	}
 
-	pub(crate) fn cleanup_client(&mut self, client: Ref<Client>) -> Result<(), ServerError> {
-		while let Some(object) = client.objects.borrow_mut().remove_any() {
-			self.run_object_destructor(client.clone(), object.custom_ref());
+	// Clients that were sent a protocol error get their remaining events flushed and are then disconnected
+	fn disconnect_errored(&mut self) -> Result<(), ServerError> {
+		for client in self.clients() {
+			let client = match client.get() {
+				Ok(client) => client,
+				Err(_) => continue,
+			};
+			if client.error_posted() {
+				if let Err(e) = client.net.borrow_mut().flush() {
+					log::warn!("Failed to flush events to client {} before disconnecting: {}", client.id(), e);
+				}
+				log::info!("Disconnecting client {} after protocol error", client.id());
+				self.cleanup_client(client)?;
+			}
 		}
 
-		let _ = self.client_manager.borrow_mut().remove_client(client.handle());
+		Ok(())
+	}
+
+	pub(crate) fn cleanup_client(&mut self, client: Rc<Client>) -> Result<(), ServerError> {
+		self.teardown_objects(&client);
+		let _ = self.client_manager.borrow_mut().remove_client(&client.handle());
 		
 		Ok(())
 	}
 
+	fn teardown_objects(&mut self, client: &Client) {
+		// Objects are removed only after their destructors ran, so destructors can still reach them. The object map
+		// isn't borrowed while a destructor runs.
+		loop {
+			let next = client.objects.borrow().next_teardown();
+			let key = match next {
+				Some(key) => key,
+				None => break,
+			};
+			self.run_object_destructor(client, key);
+			client.objects.borrow_mut().remove(key);
+		}
+	}
+
 	fn destroy_pending(&mut self) {
-		// This is written funny to bypass the borrow checker. It would probably be more friendly to the compile
-		// and better in general to make `run_object_destructor` a method of Object.
-		let client_count = self.client_manager.borrow().clients.len();
-		for i in 0..client_count {
-			let client = self.client_manager.borrow().clients[i].custom_ref();
-			while let Some(object) = client.objects.borrow_mut().next_pending_destroy() {
-				self.run_object_destructor(client.clone(), object.custom_ref());
+		let clients = self.client_manager.borrow().live_clients();
+		for client in clients {
+			loop {
+				let next = client.objects.borrow_mut().next_pending_destroy();
+				let key = match next {
+					Some(key) => key,
+					None => break,
+				};
+				self.run_object_destructor(&client, key);
+				client.objects.borrow_mut().remove(key);
 			}
 		}
 	}
 
-	pub(crate) fn destroy_object(&mut self, client: Ref<Client>, object: Ref<Object>) {
-		self.run_object_destructor(client.clone(), object.clone());
-		let _ = client.remove_object(object);
+	pub(crate) fn destroy_object(&mut self, client: &Client, key: ObjectKey) {
+		self.run_object_destructor(client, key);
+		let _ = client.remove_object(key);
 	}
 
-	fn run_object_destructor(&mut self, client: Ref<Client>, object: Ref<Object>) {
+	fn run_object_destructor(&mut self, client: &Client, key: ObjectKey) {
+		let object = match client.objects.borrow().get(key) {
+			Some(object) => object,
+			None => return,
+		};
+		let resource = Resource::new_untyped(client.handle(), key);
+		for listener in object.take_destroy_listeners() {
+			let state = &mut self.state;
+			let listener_resource = resource.clone();
+			catch_handler_panic(client, &resource, || listener(state, listener_resource));
+		}
+
 		if let Some(ref mut dispatcher) = *object.dispatcher.borrow_mut() {
-			let resource = Resource::new_untyped(client.handle(), object.handle());
-			match dispatcher.dispatch_destructor(&mut self.state, resource) {
-				Ok(()) => {},
-				Err(e) => {
+			let state = &mut self.state;
+			let result = catch_handler_panic(client, &resource, || dispatcher.dispatch_destructor(state, resource.clone()));
+			match result {
+				Some(Ok(())) => {},
+				Some(Err(e)) => {
 					log::error!("Failed to run object destructor: {}", e);
-				}
+				},
+				None => {},
 			}
-		}
+		};
 	}
 
-	pub fn try_accept<S: 'static, F: FnOnce(Handle<Client>) -> S>(&mut self, state_creator: F) -> Result<Option<Ref<Client>>, ServerError> {
+	pub fn try_accept<S: 'static, F: FnOnce(ClientHandle) -> S>(&mut self, state_creator: F) -> Result<Option<Rc<Client>>, ServerError> {
 		if let Some(net) = self.net.try_accept()? {
-			let handle = self.client_manager.borrow_mut().create_client(net, ());
-			handle.get().unwrap().set_state(state_creator(handle.clone()));
-			Ok(Some(handle.upgrade().unwrap().custom_ref()))
+			let handle = self.add_net_client(net, state_creator);
+			Ok(Some(handle.get()?))
 		} else {
 			Ok(None)
 		}
 	}
+
+	/// Adds a client connected over an existing socket, for example one end of a `UnixStream::pair`.
+	pub fn add_client<S: 'static, F: FnOnce(ClientHandle) -> S>(&mut self, stream: UnixStream, state_creator: F) -> ClientHandle {
+		let handle = self.add_net_client(NetClient::new(stream), state_creator);
+		log::info!("Client {} connected", handle.get().unwrap().id());
+		handle
+	}
+
+	fn add_net_client<S: 'static, F: FnOnce(ClientHandle) -> S>(&mut self, net: NetClient, state_creator: F) -> ClientHandle {
+		let handle = self.client_manager.borrow_mut().create_client(net, ());
+		handle.get().unwrap().set_state(state_creator(handle.clone()));
+		handle
+	}
 	
-	// TODO: wonder about serials
+	/// Returns a new serial that isn't recorded for any client. Use `Resource::next_serial` for serials that
+	/// clients are expected to send back.
 	pub fn next_serial(&mut self) -> u32 {
-		let serial = self.next_serial;
-		// How should we handle serial exhaustion
-		self.next_serial = self.next_serial.checked_add(1).expect("Serials exhausted");
-		serial
+		self.serial_manager.borrow_mut().next_serial()
+	}
+
+	pub fn serial_manager(&self) -> Handle<RefCell<SerialManager>> {
+		self.serial_manager.handle()
+	}
+
+	pub(crate) fn remote_resource(&self, token: u64) -> Option<Resource<Untyped>> {
+		self.client_manager.borrow().remote_resources.get(token)
+	}
+
+	/// Takes a snapshot of every client, its objects and every global.
+	pub fn snapshot(&self) -> ServerSnapshot {
+		let clients = self.client_manager.borrow().live_clients().iter().map(|client| introspect::snapshot_client(client)).collect();
+		let globals = self.global_manager.borrow().globals.iter().map(|global| introspect::snapshot_global(global)).collect();
+		ServerSnapshot {
+			clients,
+			globals,
+		}
+	}
+
+	/// Collects the traffic counters of every connected client, along with the request totals of the server. Client
+	/// counters start at zero when a client connects and are gone once it disconnects, while the totals are kept for
+	/// as long as the server runs.
+	pub fn metrics(&self) -> ServerMetrics {
+		let clients = self.client_manager.borrow().live_clients().iter().map(|client| {
+			let net = client.net.borrow();
+			net.counters.metrics(client.id(), net.credentials())
+		}).collect();
+		ServerMetrics {
+			clients,
+			requests: self.request_totals.metrics(),
+		}
+	}
+
+	/// Listens on `path` for debugging tools. Every connection is sent a JSON snapshot of the server, taken during
+	/// the next `dispatch`, and closed. The socket is removed when the server is dropped or the socket is closed.
+	pub fn open_debug_socket<P: AsRef<Path>>(&mut self, path: P) -> Result<(), ServerError> {
+		self.debug_socket = Some(DebugSocket::bind(path).map_err(ServerError::DebugSocket)?);
+		Ok(())
+	}
+
+	pub fn close_debug_socket(&mut self) {
+		self.debug_socket = None;
 	}
 
 	pub fn print_debug_info(&self) {
-		for client in &self.client_manager.borrow().clients {
-			eprintln!("Client {}:", client.id());
-			for object in &client.objects.borrow().objects {
-				eprintln!("\t{}@{}", object.interface.get().name, object.id);
-			}
+		eprint!("{}", self.snapshot());
+	}
+}
+
+// Closes the fds of a request that no handler will see
+fn close_fds(fds: &[RawFd]) {
+	for &fd in fds {
+		let _ = nix::unistd::close(fd);
+	}
+}
+
+/// Runs a user-provided handler, treating a panic as an implementation error of the client whose request caused it.
+/// The client is sent a `wl_display.error` and disconnected, while the server and every other client stay up.
+fn catch_handler_panic<T, F: FnOnce() -> T>(client: &Client, resource: &Resource<Untyped>, f: F) -> Option<T> {
+	match panic::catch_unwind(AssertUnwindSafe(f)) {
+		Ok(t) => Some(t),
+		Err(payload) => {
+			let message = if let Some(message) = payload.downcast_ref::<&str>() {
+				message
+			} else if let Some(message) = payload.downcast_ref::<String>() {
+				message.as_str()
+			} else {
+				"<unknown panic payload>"
+			};
+			log::error!("Handler for {:?} panicked: {}", resource, message);
+			client.post_error(resource.clone(), wl_display::Error::Implementation.into(), "internal compositor error");
+			None
 		}
 	}
 }
@@ -291,6 +740,12 @@ pub enum ServerError {
 	UnknownIoError(#[from] io::Error),
 	#[error("A client sent a request to an object that doesn't exist")]
 	RequestReceiverDoesntExist,
+	#[error(transparent)]
+	Dangling(#[from] DanglingError),
+	#[error("Failed to open debug socket\n\t{0}")]
+	DebugSocket(#[source] io::Error),
+	#[error("Failed to register a socket with the async runtime\n\t{0}")]
+	Reactor(#[source] io::Error),
 }
 
 #[derive(Debug, Error)]
@@ -314,3 +769,253 @@ pub enum SendEventError {
 	#[error(transparent)]
 	Net(#[from] NetError),
 }
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		cell::{Cell, RefCell},
+		rc::{Rc},
+		thread,
+	};
+
+	use wl_common::{
+		wire::{RawMessage, MessageHeader},
+	};
+
+	use crate::{
+		Server, BindContext, NewResource,
+		protocol::*,
+		testing::{TestClient},
+	};
+
+	type Log = Rc<RefCell<Vec<&'static str>>>;
+
+	// Binds a wl_compositor global as wl_compositor@3, through wl_registry@2. The compositor's destructor is logged
+	fn bind_compositor(server: &mut Server, client: &mut TestClient, log: &Log) {
+		let log = Rc::clone(log);
+		server.register_global::<WlCompositor, _>(move |_: BindContext, new_resource: NewResource<WlCompositor>| {
+			let log = Rc::clone(&log);
+			new_resource.register_fn((), |_, _, _| {}, move |_, _| log.borrow_mut().push("compositor"));
+		});
+		client.bind_global::<WlCompositor>(server, 1).unwrap();
+	}
+
+	#[test]
+	fn destroy_listeners_run_in_order_before_the_destructor() {
+		let mut server = Server::new_without_socket(());
+		let mut client = TestClient::connect(&mut server, ());
+		let log = Log::default();
+		bind_compositor(&mut server, &mut client, &log);
+
+		let compositor = server.resources::<WlCompositor>().next().unwrap();
+		for &name in &["listener 1", "listener 2"] {
+			let log = Rc::clone(&log);
+			compositor.add_destroy_listener(move |_, _| log.borrow_mut().push(name));
+		}
+		drop(client);
+		server.dispatch(|_| ()).unwrap();
+
+		assert_eq!(*log.borrow(), vec!["listener 1", "listener 2", "compositor"]);
+	}
+
+	#[test]
+	fn children_are_torn_down_before_their_parents() {
+		let mut server = Server::new_without_socket(());
+		let mut client = TestClient::connect(&mut server, ());
+		let log = Log::default();
+		bind_compositor(&mut server, &mut client, &log);
+
+		// The registry is older than the compositor, so it would be torn down after it if it weren't its child
+		let compositor = server.resources::<WlCompositor>().next().unwrap();
+		let registry = server.resources::<WlRegistry>().next().unwrap();
+		registry.set_parent(&compositor);
+		let log_clone = Rc::clone(&log);
+		registry.add_destroy_listener(move |_, _| log_clone.borrow_mut().push("registry"));
+		drop(client);
+		server.dispatch(|_| ()).unwrap();
+
+		assert_eq!(*log.borrow(), vec!["registry", "compositor"]);
+	}
+
+	// Connects a new client and binds wl_compositor@3 for it
+	fn connect_with_panicking_compositor(server: &mut Server) -> TestClient {
+		let mut client = TestClient::connect(server, ());
+		client.bind_global::<WlCompositor>(server, 1).unwrap();
+		client.take_events();
+		client
+	}
+
+	// Creating a surface panics in the handler if `panic_in_handler` is set, otherwise destroying it panics in its
+	// destructor
+	fn register_panicking_compositor(server: &mut Server, panic_in_handler: bool) {
+		server.register_global::<WlCompositor, _>(move |_: BindContext, new_resource: NewResource<WlCompositor>| {
+			new_resource.register_fn((), move |_, _, request| {
+				if let WlCompositorRequest::CreateSurface(create_surface) = request {
+					if panic_in_handler {
+						panic!("create_surface failed");
+					}
+					create_surface.id.register_fn((), |_, this, request| {
+						if let WlSurfaceRequest::Destroy = request {
+							this.destroy();
+						}
+					}, |_, _| panic!("destructor failed"));
+				}
+			}, |_, _| {});
+		});
+	}
+
+	#[test]
+	fn panicking_handlers_only_disconnect_their_client() {
+		let mut server = Server::new_without_socket(());
+		register_panicking_compositor(&mut server, true);
+		let mut client = connect_with_panicking_compositor(&mut server);
+		let mut other = connect_with_panicking_compositor(&mut server);
+
+		let id = client.new_id::<WlSurface>();
+		client.send(3, WlCompositorRequest::CreateSurface(wl_compositor::CreateSurfaceRequest { id })).unwrap();
+		client.dispatch(&mut server).unwrap();
+		client.expect_error(3, wl_display::Error::Implementation);
+		client.expect_disconnected();
+
+		let callback = other.new_id::<WlCallback>();
+		other.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+		other.dispatch(&mut server).unwrap();
+		assert_eq!(other.next_event().unwrap().name(), "done");
+		assert!(!other.is_disconnected());
+		assert_eq!(server.clients().count(), 1);
+	}
+
+	#[test]
+	fn panicking_destructors_only_disconnect_their_client() {
+		let mut server = Server::new_without_socket(());
+		register_panicking_compositor(&mut server, false);
+		let mut client = connect_with_panicking_compositor(&mut server);
+		let mut other = connect_with_panicking_compositor(&mut server);
+
+		let id = client.new_id::<WlSurface>();
+		let surface = client.id(&id);
+		client.send(3, WlCompositorRequest::CreateSurface(wl_compositor::CreateSurfaceRequest { id })).unwrap();
+		client.send(surface, WlSurfaceRequest::Destroy).unwrap();
+		client.dispatch(&mut server).unwrap();
+		client.expect_error(surface, wl_display::Error::Implementation);
+		client.expect_disconnected();
+
+		let callback = other.new_id::<WlCallback>();
+		other.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+		other.dispatch(&mut server).unwrap();
+		assert_eq!(other.next_event().unwrap().name(), "done");
+		assert_eq!(server.clients().count(), 1);
+	}
+
+	#[test]
+	fn stopping_runs_destructors_and_disconnects_clients() {
+		let mut server = Server::new_without_socket(());
+		let destroyed = Rc::new(Cell::new(false));
+		let destroyed_clone = Rc::clone(&destroyed);
+		server.register_global::<WlCompositor, _>(move |_: BindContext, new_resource: NewResource<WlCompositor>| {
+			let destroyed = Rc::clone(&destroyed_clone);
+			new_resource.register_fn((), |_, _, _| {}, move |_, _| destroyed.set(true));
+		});
+		let mut client = TestClient::connect(&mut server, ());
+		client.bind_global::<WlCompositor>(&mut server, 1).unwrap();
+
+		let stop = server.stop_handle();
+		thread::spawn(move || stop.stop()).join().unwrap();
+		server.run(|_| ()).unwrap();
+
+		assert!(destroyed.get());
+		assert_eq!(server.clients().count(), 0);
+		client.dispatch(&mut server).unwrap();
+		client.expect_disconnected();
+	}
+
+	fn raw_message(sender: u32, opcode: u16, msg_size: u16, data: Vec<u8>) -> RawMessage {
+		RawMessage {
+			header: MessageHeader {
+				sender,
+				opcode,
+				msg_size,
+			},
+			data,
+			fds: Vec::new(),
+		}
+	}
+
+	// `bad` connects first, so it's read from first
+	fn expect_good_client_served(server: &mut Server, bad: &mut TestClient, good: &mut TestClient) {
+		let callback = good.new_id::<WlCallback>();
+		let id = good.id(&callback);
+		good.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+		bad.dispatch(server).unwrap();
+		good.read_events().unwrap();
+		good.expect_event::<WlCallback>(id);
+	}
+
+	#[test]
+	fn requests_to_unknown_objects_only_disconnect_their_client() {
+		let mut server = Server::new_without_socket(());
+		let mut bad = TestClient::connect(&mut server, ());
+		let mut good = TestClient::connect(&mut server, ());
+		bad.send_raw(&raw_message(7, 0, 12, vec![0; 4])).unwrap();
+		expect_good_client_served(&mut server, &mut bad, &mut good);
+
+		assert_eq!(bad.expect_error(1, wl_display::Error::InvalidObject), "invalid object 7");
+		bad.dispatch(&mut server).unwrap();
+		bad.expect_disconnected();
+	}
+
+	#[test]
+	fn unknown_opcodes_are_invalid_methods() {
+		let mut server = Server::new_without_socket(());
+		let mut bad = TestClient::connect(&mut server, ());
+		let mut good = TestClient::connect(&mut server, ());
+		bad.send_raw(&raw_message(1, 5, 12, vec![0; 4])).unwrap();
+		expect_good_client_served(&mut server, &mut bad, &mut good);
+
+		assert_eq!(bad.expect_error(1, wl_display::Error::InvalidMethod), "invalid method 5, object wl_display@1");
+		bad.dispatch(&mut server).unwrap();
+		bad.expect_disconnected();
+	}
+
+	#[test]
+	fn unparseable_requests_only_disconnect_their_client() {
+		let mut server = Server::new_without_socket(());
+		let mut bad = TestClient::connect(&mut server, ());
+		let mut good = TestClient::connect(&mut server, ());
+		// wl_display.sync without its new_id
+		bad.send_raw(&raw_message(1, 0, 8, Vec::new())).unwrap();
+		expect_good_client_served(&mut server, &mut bad, &mut good);
+
+		assert_eq!(bad.expect_error(1, wl_display::Error::InvalidMethod), "invalid arguments for wl_display@1.sync");
+		bad.dispatch(&mut server).unwrap();
+		bad.expect_disconnected();
+	}
+
+	#[test]
+	fn binds_without_a_valid_interface_name_are_invalid_objects() {
+		for (name, msg_size) in [(vec![0], 24), (vec![2, u32::from_ne_bytes([0xff, 0, 0, 0])], 28)] {
+			let mut server = Server::new_without_socket(());
+			let mut client = TestClient::connect(&mut server, ());
+			// wl_display.get_registry(2), then wl_registry.bind(1, name, 1, 3)
+			client.send_raw(&raw_message(1, 1, 12, 2u32.to_ne_bytes().to_vec())).unwrap();
+			let data = [vec![1], name, vec![1, 3]].concat().iter().flat_map(|word: &u32| word.to_ne_bytes()).collect();
+			client.send_raw(&raw_message(2, 0, msg_size, data)).unwrap();
+			client.dispatch(&mut server).unwrap();
+
+			assert_eq!(client.expect_error(2, wl_display::Error::InvalidObject), "invalid interface for new object in wl_registry@2.bind");
+			client.dispatch(&mut server).unwrap();
+			client.expect_disconnected();
+		}
+	}
+
+	#[test]
+	fn malformed_messages_only_disconnect_their_client() {
+		let mut server = Server::new_without_socket(());
+		let mut bad = TestClient::connect(&mut server, ());
+		let mut good = TestClient::connect(&mut server, ());
+		// A size too small for the header it's in
+		bad.send_raw(&raw_message(1, 0, 4, vec![0; 4])).unwrap();
+		expect_good_client_served(&mut server, &mut bad, &mut good);
+		bad.expect_disconnected();
+	}
+}
@@ -0,0 +1,127 @@
+//! A client's own view of its objects, for code that talks to a server as a client with the generated message types,
+//! like `testing::TestClient` and `wl-info`. The objects live in a client of a server of their own that never
+//! dispatches, so requests can be serialized and events parsed with the same `ClientMap` the server uses.
+
+use std::{
+	os::unix::{net::{UnixStream}},
+	fmt,
+};
+
+use wl_common::{
+	wire::{DynArgument},
+	interface::{Interface, DynInterface, Message, IntoArgsError, FromArgsError},
+};
+
+use crate::{
+	server::{Server},
+	client::{ClientMap, ClientHandle},
+	object::{Object, ObjectKey},
+	resource::{Resource, NewResource, Untyped},
+};
+
+/// The objects a client created or received, by id.
+pub struct ShadowObjects {
+	_server: Server,
+	client: ClientHandle,
+	next_id: u32,
+}
+
+impl ShadowObjects {
+	/// Creates the objects of a newly connected client, which only has the display.
+	pub fn new() -> Self {
+		// The client is never read from, so its socket doesn't need a peer
+		let mut server = Server::new_without_socket(());
+		let (stream, _) = UnixStream::pair().expect("Failed to create socketpair");
+		let client = server.add_client(stream, |_| ());
+		Self {
+			_server: server,
+			client,
+			next_id: 2,
+		}
+	}
+
+	/// Allocates an id for a new object with the interface `I`, to be passed in a request.
+	pub fn new_id<I: Interface>(&mut self) -> NewResource<I> {
+		self.new_id_dyn(I::as_dyn())
+	}
+
+	/// Allocates an id for an untyped new_id argument, as in `wl_registry.bind`, requesting `version` of `interface`.
+	pub fn new_id_untyped(&mut self, mut interface: DynInterface, version: u32) -> NewResource<Untyped> {
+		interface.version = version;
+		self.new_id_dyn(interface)
+	}
+
+	fn new_id_dyn<I>(&mut self, interface: DynInterface) -> NewResource<I> {
+		let id = self.next_id;
+		self.next_id += 1;
+		NewResource::new(self.client.clone(), self.add(id, interface))
+	}
+
+	/// The id that was allocated for `new_resource`, if it's one of these objects.
+	pub fn id<I>(&self, new_resource: &NewResource<I>) -> Option<u32> {
+		new_resource.object().ok().map(|object| object.id)
+	}
+
+	/// The id of `resource`, if it's one of these objects.
+	pub fn object_id<I>(&self, resource: &Resource<I>) -> Option<u32> {
+		resource.object().ok().map(|object| object.id)
+	}
+
+	/// Returns the object `id`, to be passed as an object argument in a request, if it exists and has the interface
+	/// `I`.
+	pub fn object<I: Interface>(&self, id: u32) -> Option<Resource<I>> {
+		self.client.get().ok()?.find_by_id(id)
+	}
+
+	/// Adds an object the server created, like one from a new_id in an event.
+	pub fn add(&self, id: u32, interface: DynInterface) -> ObjectKey {
+		let object = Object::new_untyped(id, None);
+		object.interface.set(interface);
+		object.version.set(interface.version);
+		let client = self.client.get().expect("Shadow client is gone");
+		let key = client.objects.borrow_mut().add(object);
+		key
+	}
+
+	/// Removes the object `id`, once the server sent `wl_display.delete_id` for it.
+	pub fn remove(&self, id: u32) {
+		if let Ok(client) = self.client.get() {
+			let mut objects = client.objects.borrow_mut();
+			if let Some(key) = objects.key_of(id) {
+				objects.remove(key);
+			}
+		}
+	}
+
+	pub fn interface(&self, id: u32) -> Option<DynInterface> {
+		self.client.get().ok()?.object_interface(id)
+	}
+
+	/// Converts a request into its opcode and arguments. Resources in it must be these objects.
+	pub fn request_args<R: Message<ClientMap=ClientMap>>(&self, request: &R) -> Result<(u16, Vec<DynArgument>), IntoArgsError> {
+		let client = self.client.get().map_err(|_| IntoArgsError::ResourceDoesntExist)?;
+		request.into_args(client.client_map())
+	}
+
+	/// Converts the arguments of an event sent to an object with the interface `I`. Object arguments are resolved to
+	/// these objects.
+	pub fn event<I: Interface>(&self, opcode: u16, args: Vec<DynArgument>) -> Result<I::Event, FromArgsError> where I::Event: Message<ClientMap=ClientMap> {
+		let client = self.client.get().map_err(|_| FromArgsError::ResourceDoesntExist)?;
+		I::Event::from_args(client.client_map(), opcode, args)
+	}
+}
+
+impl Default for ShadowObjects {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl fmt::Debug for ShadowObjects {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("ShadowObjects")
+			.field("client", &self.client)
+			.field("next_id", &self.next_id)
+			.finish()
+	}
+}
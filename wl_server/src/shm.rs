@@ -0,0 +1,573 @@
+//! A ready-made `wl_shm` global. Pools are memory-mapped when a client creates them and remapped when it grows them,
+//! and buffers created from them can be read as byte slices. A client that shrinks the file behind a pool while a
+//! buffer is read gets a protocol error instead of crashing the compositor with `SIGBUS`.
+//!
+//! ```ignore
+//! server.register_global(Shm::new(&[wl_shm::Format::Rgb565]));
+//!
+//! // Later, for example when a surface is committed
+//! if let Some(buffer) = attached.get_data::<ShmBuffer>() {
+//!     buffer.with_data(|pixels| upload(pixels, buffer.width(), buffer.height(), buffer.stride()));
+//! }
+//! ```
+
+use std::{
+	os::unix::{io::{RawFd}},
+	cell::{Cell, RefCell},
+	rc::{Rc},
+	sync::{Once, OnceLock},
+	ptr, slice,
+};
+
+use nix::{
+	libc::{self, c_int, c_void},
+	unistd,
+	errno::Errno,
+	sys::{
+		mman::{self, ProtFlags, MapFlags},
+		signal::{self, Signal, SigAction, SigHandler, SaFlags, SigSet},
+	},
+};
+
+use wl_common::{
+	interface::{WEnum},
+};
+
+use crate::{
+	server::{State},
+	resource::{Resource, NewResource},
+	global::{GlobalImplementation, BindContext},
+	object::{ObjectImplementation},
+	protocol::{*, wl_shm::Format},
+};
+
+/// The formats every compositor has to support.
+const REQUIRED_FORMATS: [Format; 2] = [Format::Argb8888, Format::Xrgb8888];
+
+/// The `wl_shm` global, registered with `Server::register_global`.
+#[derive(Debug, Clone)]
+pub struct Shm {
+	formats: Rc<Vec<Format>>,
+}
+
+impl Shm {
+	/// Advertises `formats` along with argb8888 and xrgb8888, which are always supported.
+	pub fn new(formats: &[Format]) -> Self {
+		let mut all = REQUIRED_FORMATS.to_vec();
+		for &format in formats {
+			if !all.contains(&format) {
+				all.push(format);
+			}
+		}
+		Self {
+			formats: Rc::new(all),
+		}
+	}
+
+	pub fn formats(&self) -> &[Format] {
+		&self.formats
+	}
+}
+
+impl Default for Shm {
+	fn default() -> Self {
+		Self::new(&[])
+	}
+}
+
+impl GlobalImplementation<WlShm> for Shm {
+	fn handle(&mut self, _context: BindContext, this: NewResource<WlShm>) {
+		let shm = this.register((), ShmImplementation {
+			formats: Rc::clone(&self.formats),
+		});
+		for &format in self.formats.iter() {
+			shm.send_event(WlShmEvent::Format(wl_shm::FormatEvent {
+				format,
+			}));
+		}
+	}
+}
+
+struct ShmImplementation {
+	formats: Rc<Vec<Format>>,
+}
+
+impl ObjectImplementation<WlShm> for ShmImplementation {
+	fn handle(&mut self, _state: &mut State, this: Resource<WlShm>, request: WlShmRequest) {
+		match request {
+			WlShmRequest::CreatePool(create_pool) => {
+				// These checks and messages follow libwayland's shm_create_pool
+				if create_pool.size <= 0 {
+					let _ = unistd::close(create_pool.fd);
+					this.post_error(wl_shm::Error::InvalidStride, &format!("invalid size ({})", create_pool.size));
+					return;
+				}
+				let pool = match ShmPool::new(create_pool.fd, create_pool.size as usize) {
+					Ok(pool) => Rc::new(pool),
+					Err(e) => {
+						this.post_error(wl_shm::Error::InvalidFd, &format!("failed mmap fd {}: {}", create_pool.fd, e));
+						return;
+					},
+				};
+				create_pool.id.register(Rc::clone(&pool), ShmPoolImplementation {
+					pool,
+					formats: Rc::clone(&self.formats),
+				});
+			},
+		}
+	}
+
+	fn handle_destructor(&mut self, _state: &mut State, _this: Resource<WlShm>) {
+
+	}
+}
+
+/// The memory of a `wl_shm_pool`. It's shared by the pool and every buffer created from it, so it stays mapped
+/// until the pool and all its buffers are destroyed.
+#[derive(Debug)]
+pub struct ShmPool {
+	mapping: RefCell<Mapping>,
+}
+
+impl ShmPool {
+	/// Takes ownership of `fd`. It's closed once it's mapped, or if it can't be, so clients can't run the compositor
+	/// out of fds by creating pools.
+	fn new(fd: RawFd, size: usize) -> nix::Result<Self> {
+		let mapping = Mapping::new(fd, size);
+		let _ = unistd::close(fd);
+		Ok(Self {
+			mapping: RefCell::new(mapping?),
+		})
+	}
+
+	pub fn size(&self) -> usize {
+		self.mapping.borrow().len
+	}
+
+	fn resize(&self, size: usize) -> nix::Result<()> {
+		self.mapping.borrow_mut().grow(size)
+	}
+}
+
+/// A read-only shared mapping of a client's file. The mapping keeps the file alive, and growing it maps more of the
+/// same file, so the fd isn't needed after it's created.
+#[derive(Debug)]
+struct Mapping {
+	ptr: *mut u8,
+	len: usize,
+}
+
+impl Mapping {
+	fn new(fd: RawFd, len: usize) -> nix::Result<Self> {
+		// SAFETY: A new mapping is created, so no existing memory is affected
+		let ptr = unsafe { mman::mmap(ptr::null_mut(), len, ProtFlags::PROT_READ, MapFlags::MAP_SHARED, fd, 0) }?;
+		Ok(Self {
+			ptr: ptr as *mut u8,
+			len,
+		})
+	}
+
+	fn grow(&mut self, len: usize) -> nix::Result<()> {
+		// SAFETY: The mapping is borrowed mutably, so no slices of it are alive while it moves
+		let ptr = unsafe { libc::mremap(self.ptr as *mut c_void, self.len, len, libc::MREMAP_MAYMOVE) };
+		if ptr == libc::MAP_FAILED {
+			return Err(nix::Error::Sys(Errno::last()));
+		}
+		self.ptr = ptr as *mut u8;
+		self.len = len;
+		Ok(())
+	}
+
+	fn as_slice(&self) -> &[u8] {
+		// SAFETY: The mapping is `len` bytes long and stays mapped until it's dropped
+		unsafe { slice::from_raw_parts(self.ptr, self.len) }
+	}
+}
+
+impl Drop for Mapping {
+	fn drop(&mut self) {
+		// SAFETY: Slices of the mapping can't outlive it
+		let _ = unsafe { mman::munmap(self.ptr as *mut _, self.len) };
+	}
+}
+
+struct ShmPoolImplementation {
+	pool: Rc<ShmPool>,
+	formats: Rc<Vec<Format>>,
+}
+
+impl ObjectImplementation<WlShmPool> for ShmPoolImplementation {
+	fn handle(&mut self, _state: &mut State, this: Resource<WlShmPool>, request: WlShmPoolRequest) {
+		match request {
+			WlShmPoolRequest::CreateBuffer(create_buffer) => {
+				// These checks and messages follow libwayland's shm_pool_create_buffer
+				let format = match create_buffer.format {
+					WEnum::Value(format) if self.formats.contains(&format) => format,
+					_ => {
+						this.post_error(wl_shm::Error::InvalidFormat, &format!("invalid format 0x{:x}", u32::from(create_buffer.format)));
+						return;
+					},
+				};
+				let (offset, width, height, stride) = (create_buffer.offset, create_buffer.width, create_buffer.height, create_buffer.stride);
+				let min_stride = width as i64 * bytes_per_pixel(format) as i64;
+				if offset < 0 || width <= 0 || height <= 0 || (stride as i64) < min_stride || i32::MAX / stride < height
+					|| offset as i64 > self.pool.size() as i64 - stride as i64 * height as i64 {
+					this.post_error(wl_shm::Error::InvalidStride, &format!("invalid width, height or stride ({}x{}, {})", width, height, stride));
+					return;
+				}
+				let resource = Resource::new(create_buffer.id.client(), create_buffer.id.object);
+				create_buffer.id.register(ShmBuffer {
+					resource,
+					pool: Rc::clone(&self.pool),
+					offset: offset as usize,
+					width,
+					height,
+					stride,
+					format,
+				}, ShmBufferImplementation);
+			},
+			WlShmPoolRequest::Resize(resize) => {
+				if resize.size < 0 || (resize.size as usize) < self.pool.size() {
+					this.post_error(wl_shm::Error::InvalidFd, "shrinking pool invalid");
+					return;
+				}
+				if let Err(e) = self.pool.resize(resize.size as usize) {
+					this.post_error(wl_shm::Error::InvalidFd, &format!("failed to remap pool: {}", e));
+				}
+			},
+			WlShmPoolRequest::Destroy => {
+				this.destroy();
+			},
+		}
+	}
+
+	fn handle_destructor(&mut self, _state: &mut State, _this: Resource<WlShmPool>) {
+
+	}
+}
+
+/// The data of every `wl_buffer` created from a `wl_shm_pool`, reached with `Resource::get_data::<ShmBuffer>`.
+#[derive(Debug)]
+pub struct ShmBuffer {
+	// Where errors accessing the pool are reported
+	resource: Resource<WlBuffer>,
+	pool: Rc<ShmPool>,
+	offset: usize,
+	width: i32,
+	height: i32,
+	stride: i32,
+	format: Format,
+}
+
+impl ShmBuffer {
+	pub fn width(&self) -> i32 {
+		self.width
+	}
+
+	pub fn height(&self) -> i32 {
+		self.height
+	}
+
+	/// The number of bytes from the start of one row to the start of the next.
+	pub fn stride(&self) -> i32 {
+		self.stride
+	}
+
+	pub fn format(&self) -> Format {
+		self.format
+	}
+
+	/// Calls `f` with the buffer's pixels, `stride * height` bytes.
+	///
+	/// The client can write to the memory while `f` reads it. If the client shrank the file backing the pool, the
+	/// pool reads as zeros from then on and the client is sent an `invalid_fd` error once `f` returns.
+	pub fn with_data<T, F: FnOnce(&[u8]) -> T>(&self, f: F) -> T {
+		let mapping = self.pool.mapping.borrow();
+		let len = self.stride as usize * self.height as usize;
+		let access = Access::new(&mapping);
+		let result = access.run(|| f(&mapping.as_slice()[self.offset..self.offset + len]));
+		if access.faulted.get() {
+			self.resource.post_error(wl_shm::Error::InvalidFd, "error accessing SHM buffer");
+		}
+		result
+	}
+}
+
+thread_local! {
+	// The innermost pool access on this thread, for the SIGBUS handler
+	static ACCESS: Cell<*const Access> = const { Cell::new(ptr::null()) };
+}
+
+static INSTALL_SIGBUS_HANDLER: Once = Once::new();
+// The action installed before ours, which gets every fault outside of a pool access
+static PREVIOUS_SIGBUS_ACTION: OnceLock<SigAction> = OnceLock::new();
+
+/// A mapping being read on this thread, like libwayland's `wl_shm_buffer_begin_access`. Accesses can nest, so each
+/// one points to the access it interrupted.
+struct Access {
+	ptr: usize,
+	len: usize,
+	faulted: Cell<bool>,
+	outer: Cell<*const Access>,
+}
+
+impl Access {
+	fn new(mapping: &Mapping) -> Self {
+		Self {
+			ptr: mapping.ptr as usize,
+			len: mapping.len,
+			faulted: Cell::new(false),
+			outer: Cell::new(ptr::null()),
+		}
+	}
+
+	/// Calls `f` with faults in this mapping caught by the SIGBUS handler.
+	fn run<T, F: FnOnce() -> T>(&self, f: F) -> T {
+		// Restores the outer access even if `f` panics, as this one is about to go out of scope
+		struct Restore(*const Access);
+		impl Drop for Restore {
+			fn drop(&mut self) {
+				ACCESS.with(|access| access.set(self.0));
+			}
+		}
+
+		INSTALL_SIGBUS_HANDLER.call_once(install_sigbus_handler);
+		self.outer.set(ACCESS.with(|access| access.replace(self)));
+		let _restore = Restore(self.outer.get());
+		f()
+	}
+}
+
+fn install_sigbus_handler() {
+	let action = SigAction::new(SigHandler::SigAction(handle_sigbus), SaFlags::SA_NODEFER, SigSet::empty());
+	// SAFETY: The handler only reads this thread's accesses and only calls async-signal-safe functions
+	match unsafe { signal::sigaction(Signal::SIGBUS, &action) } {
+		Ok(previous) => {
+			let _ = PREVIOUS_SIGBUS_ACTION.set(previous);
+		},
+		Err(e) => log::error!("Failed to install the SIGBUS handler for shm pools: {}", e),
+	}
+}
+
+extern "C" fn handle_sigbus(signal: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+	// SAFETY: The kernel passes a valid siginfo to SA_SIGINFO handlers
+	let address = unsafe { (*info).si_addr() } as usize;
+	let mut access = ACCESS.try_with(Cell::get).unwrap_or(ptr::null());
+	while !access.is_null() {
+		// SAFETY: Accesses are unlinked before they go out of scope
+		let current = unsafe { &*access };
+		if (current.ptr..current.ptr + current.len).contains(&address) {
+			// Zeroed memory replaces the mapping, so the faulting read is retried and succeeds
+			// SAFETY: Only the mapping of the pool being read is replaced
+			let ptr = unsafe {
+				libc::mmap(current.ptr as *mut c_void, current.len, libc::PROT_READ, libc::MAP_PRIVATE | libc::MAP_FIXED | libc::MAP_ANONYMOUS, -1, 0)
+			};
+			if ptr != libc::MAP_FAILED {
+				current.faulted.set(true);
+				return;
+			}
+			break;
+		}
+		access = current.outer.get();
+	}
+	// Anything else is handled like it would have been without this handler
+	let previous = PREVIOUS_SIGBUS_ACTION.get();
+	match previous.map(SigAction::handler) {
+		Some(SigHandler::SigAction(handler)) => handler(signal, info, context),
+		Some(SigHandler::Handler(handler)) => handler(signal),
+		// The previous action takes over once the faulting read is retried, which crashes the process by default
+		_ => {
+			let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+			// SAFETY: sigaction is async-signal-safe
+			let _ = unsafe { signal::sigaction(Signal::SIGBUS, previous.unwrap_or(&default)) };
+		},
+	}
+}
+
+struct ShmBufferImplementation;
+
+impl ObjectImplementation<WlBuffer> for ShmBufferImplementation {
+	fn handle(&mut self, _state: &mut State, this: Resource<WlBuffer>, request: WlBufferRequest) {
+		match request {
+			WlBufferRequest::Destroy => {
+				this.destroy();
+			},
+		}
+	}
+
+	fn handle_destructor(&mut self, _state: &mut State, _this: Resource<WlBuffer>) {
+
+	}
+}
+
+/// The smallest number of bytes a pixel of `format` takes, for checking strides. Formats with several planes only
+/// count their first plane.
+fn bytes_per_pixel(format: Format) -> u32 {
+	use Format::*;
+	match format {
+		Argb8888 | Xrgb8888 | Xbgr8888 | Rgbx8888 | Bgrx8888 | Abgr8888 | Rgba8888 | Bgra8888
+			| Xrgb2101010 | Xbgr2101010 | Rgbx1010102 | Bgrx1010102
+			| Argb2101010 | Abgr2101010 | Rgba1010102 | Bgra1010102 => 4,
+		Rgb888 | Bgr888 => 3,
+		Xrgb4444 | Xbgr4444 | Rgbx4444 | Bgrx4444 | Argb4444 | Abgr4444 | Rgba4444 | Bgra4444
+			| Xrgb1555 | Xbgr1555 | Rgbx5551 | Bgrx5551 | Argb1555 | Abgr1555 | Rgba5551 | Bgra5551
+			| Rgb565 | Bgr565 => 2,
+		_ => 1,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		os::unix::{io::{RawFd}},
+		ffi::{CString},
+	};
+
+	use nix::{
+		unistd,
+		sys::{memfd::{self, MemFdCreateFlag}},
+	};
+
+	use wl_common::{
+		wire::{DynArgument},
+	};
+
+	use crate::{
+		Server,
+		testing::{TestClient},
+	};
+	use super::*;
+
+	fn memfd(contents: &[u8]) -> RawFd {
+		let fd = memfd::memfd_create(&CString::new("shm-test").unwrap(), MemFdCreateFlag::MFD_CLOEXEC).unwrap();
+		unistd::write(fd, contents).unwrap();
+		fd
+	}
+
+	// Binds wl_shm as wl_shm@3, returning the advertised formats
+	fn bind_shm(server: &mut Server, client: &mut TestClient) -> Vec<u32> {
+		let shm = client.bind_global::<WlShm>(server, 1).unwrap();
+		client.take_events().into_iter().filter(|event| event.sender == shm).map(|event| match event.args[0] {
+			DynArgument::Uint(format) => format,
+			ref arg => panic!("Unexpected argument {:?}", arg),
+		}).collect()
+	}
+
+	// Binds wl_shm as wl_shm@3 and creates wl_shm_pool@4 of `size` bytes over `fd`, returning the advertised formats
+	fn create_pool(server: &mut Server, client: &mut TestClient, fd: RawFd, size: i32) -> Vec<u32> {
+		let formats = bind_shm(server, client);
+		let id = client.new_id::<WlShmPool>();
+		client.send(3, WlShmRequest::CreatePool(wl_shm::CreatePoolRequest { id, fd, size })).unwrap();
+		client.dispatch(server).unwrap();
+		formats
+	}
+
+	// Creates wl_buffer@5 from wl_shm_pool@4
+	fn create_buffer(server: &mut Server, client: &mut TestClient, offset: i32, width: i32, height: i32, stride: i32, format: WEnum<Format>) {
+		let id = client.new_id::<WlBuffer>();
+		client.send(4, WlShmPoolRequest::CreateBuffer(wl_shm_pool::CreateBufferRequest { id, offset, width, height, stride, format })).unwrap();
+		client.dispatch(server).unwrap();
+	}
+
+	#[test]
+	fn buffers_read_pool_memory() {
+		let mut server = Server::new_without_socket(());
+		server.register_global(Shm::new(&[Format::Rgb565]));
+		let mut client = TestClient::connect(&mut server, ());
+		let pixels = (0..32).collect::<Vec<u8>>();
+		let formats = create_pool(&mut server, &mut client, memfd(&pixels), 16);
+		assert_eq!(formats, vec![Format::Argb8888 as u32, Format::Xrgb8888 as u32, Format::Rgb565 as u32]);
+
+		// The buffer is only in bounds once the pool has grown
+		client.send(4, WlShmPoolRequest::Resize(wl_shm_pool::ResizeRequest { size: 32 })).unwrap();
+		create_buffer(&mut server, &mut client, 16, 2, 2, 8, Format::Argb8888.into());
+		client.expect_no_events();
+
+		let buffer = server.resources::<WlBuffer>().next().unwrap();
+		let buffer = buffer.get_data::<ShmBuffer>().unwrap();
+		assert_eq!((buffer.width(), buffer.height(), buffer.stride(), buffer.format()), (2, 2, 8, Format::Argb8888));
+		buffer.with_data(|data| assert_eq!(data, &pixels[16..32]));
+	}
+
+	#[test]
+	fn invalid_buffers_are_protocol_errors() {
+		let mut server = Server::new_without_socket(());
+		server.register_global(Shm::default());
+		let buffers = [
+			(Format::Rgb565, 0, 4, wl_shm::Error::InvalidFormat),
+			(Format::Argb8888, 0, 3, wl_shm::Error::InvalidStride),
+			(Format::Argb8888, 4, 4, wl_shm::Error::InvalidStride),
+		];
+		for &(format, offset, stride, error) in &buffers {
+			let mut client = TestClient::connect(&mut server, ());
+			create_pool(&mut server, &mut client, memfd(&[0; 16]), 16);
+			create_buffer(&mut server, &mut client, offset, 1, 4, stride, format.into());
+			client.expect_error(4, error);
+		}
+
+		let mut client = TestClient::connect(&mut server, ());
+		create_pool(&mut server, &mut client, memfd(&[0; 16]), 16);
+		client.send(4, WlShmPoolRequest::Resize(wl_shm_pool::ResizeRequest { size: 8 })).unwrap();
+		client.dispatch(&mut server).unwrap();
+		client.expect_error(4, wl_shm::Error::InvalidFd);
+	}
+
+	#[test]
+	fn unknown_formats_are_invalid() {
+		let mut server = Server::new_without_socket(());
+		server.register_global(Shm::default());
+		let mut client = TestClient::connect(&mut server, ());
+		create_pool(&mut server, &mut client, memfd(&[0; 16]), 16);
+		create_buffer(&mut server, &mut client, 0, 1, 1, 4, WEnum::Unknown(0xdead_beef));
+		let message = client.expect_error(4, wl_shm::Error::InvalidFormat);
+		assert_eq!(message, "invalid format 0xdeadbeef");
+	}
+
+	#[test]
+	fn buffers_can_end_at_the_end_of_a_grown_pool() {
+		let mut server = Server::new_without_socket(());
+		server.register_global(Shm::default());
+		let mut client = TestClient::connect(&mut server, ());
+		let contents = (0..=255).collect::<Vec<u8>>();
+		create_pool(&mut server, &mut client, memfd(&contents), 16);
+		client.send(4, WlShmPoolRequest::Resize(wl_shm_pool::ResizeRequest { size: 64 })).unwrap();
+		client.send(4, WlShmPoolRequest::Resize(wl_shm_pool::ResizeRequest { size: 256 })).unwrap();
+		create_buffer(&mut server, &mut client, 240, 2, 2, 8, Format::Xrgb8888.into());
+		client.expect_no_events();
+
+		let buffer = server.resources::<WlBuffer>().next().unwrap();
+		let buffer = buffer.get_data::<ShmBuffer>().unwrap();
+		buffer.with_data(|data| assert_eq!(data, &contents[240..]));
+	}
+
+	#[test]
+	fn empty_pools_are_invalid() {
+		let mut server = Server::new_without_socket(());
+		server.register_global(Shm::default());
+		for &size in &[0, -1] {
+			let mut client = TestClient::connect(&mut server, ());
+			create_pool(&mut server, &mut client, memfd(&[0; 16]), size);
+			let message = client.expect_error(3, wl_shm::Error::InvalidStride);
+			assert_eq!(message, format!("invalid size ({})", size));
+		}
+	}
+
+	#[test]
+	fn truncated_pools_read_as_zeros_and_are_errors() {
+		let mut server = Server::new_without_socket(());
+		server.register_global(Shm::default());
+		let mut client = TestClient::connect(&mut server, ());
+		let fd = memfd(&[0xff; 8192]);
+		create_pool(&mut server, &mut client, fd, 8192);
+		create_buffer(&mut server, &mut client, 4096, 1024, 1, 4096, Format::Argb8888.into());
+		client.expect_no_events();
+		unistd::ftruncate(fd, 0).unwrap();
+
+		let buffer = server.resources::<WlBuffer>().next().unwrap();
+		let zeros = buffer.get_data::<ShmBuffer>().unwrap().with_data(|data| data.iter().all(|&byte| byte == 0));
+		assert!(zeros);
+		client.read_events().unwrap();
+		let message = client.expect_error(5, wl_shm::Error::InvalidFd);
+		assert_eq!(message, "error accessing SHM buffer");
+	}
+}
@@ -0,0 +1,486 @@
+//! Support for testing compositors in-process. A `TestClient` talks to a `Server` created with
+//! `Server::new_without_socket` over a socketpair, sends the generated request types and checks the events that come
+//! back. Requests are serialized with the client's own view of its objects, so sending one never touches the state of
+//! the server under test.
+//!
+//! ```ignore
+//! let mut server = Server::new_without_socket(());
+//! let mut client = TestClient::connect(&mut server, ());
+//! let callback = client.new_id::<WlCallback>();
+//! client.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+//! client.dispatch(&mut server).unwrap();
+//! match client.expect_event::<WlCallback>(2) {
+//!     WlCallbackEvent::Done(_) => {},
+//! }
+//! ```
+
+use std::{
+	os::unix::{net::{UnixStream}},
+	collections::{VecDeque},
+	fmt,
+};
+
+use nix::{
+	errno::Errno,
+};
+use thiserror::{Error};
+
+use wl_common::{
+	wire::{RawMessage, RawMessageReader, DynMessage, DynArgument, ArgumentType, ParseDynError, SerializeRawError},
+	interface::{Interface, DynInterface, Message, IntoArgsError, FromArgsError},
+	socket::{MessageBuffer, send_message},
+};
+
+use crate::{
+	server::{Server, ServerError},
+	client::{ClientMap, ClientHandle},
+	resource::{Resource, NewResource, Untyped},
+	shadow::{ShadowObjects},
+	protocol::{
+		wl_display::{self, WlDisplay, WlDisplayRequest},
+		wl_registry::{self, WlRegistry, WlRegistryRequest},
+	},
+};
+
+/// An event received by a `TestClient`.
+#[derive(Debug, Clone)]
+pub struct ReceivedEvent {
+	pub sender: u32,
+	pub interface: DynInterface,
+	pub opcode: u16,
+	pub args: Vec<DynArgument>,
+}
+
+impl ReceivedEvent {
+	pub fn name(&self) -> &'static str {
+		self.interface.event(self.opcode).map(|event| event.name).unwrap_or("[unknown]")
+	}
+}
+
+impl fmt::Display for ReceivedEvent {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}@{}.{}{:?}", self.interface.name, self.sender, self.name(), self.args)
+	}
+}
+
+/// A client connected to an in-process server over a socketpair.
+///
+/// Object ids are allocated by the client like libwayland does, starting at 2. Events are decoded with the interfaces
+/// of the objects the client created, so they can be checked even after the server destroyed the objects.
+pub struct TestClient {
+	client: ClientHandle,
+	stream: UnixStream,
+	// Resources in requests refer to the client's own objects rather than to the server's
+	objects: ShadowObjects,
+	registry: Option<u32>,
+	buffer: MessageBuffer,
+	events: VecDeque<ReceivedEvent>,
+	disconnected: bool,
+}
+
+impl TestClient {
+	/// Connects a new client to `server`, with `state` as its client state.
+	pub fn connect<S: 'static>(server: &mut Server, state: S) -> Self {
+		let (ours, theirs) = UnixStream::pair().expect("Failed to create socketpair");
+		ours.set_nonblocking(true).expect("Failed to set socket as non-blocking");
+		let client = server.add_client(theirs, |_| state);
+
+		Self {
+			client,
+			stream: ours,
+			objects: ShadowObjects::new(),
+			registry: None,
+			buffer: MessageBuffer::new(),
+			events: VecDeque::new(),
+			disconnected: false,
+		}
+	}
+
+	/// The server's side of this client.
+	pub fn client(&self) -> ClientHandle {
+		self.client.clone()
+	}
+
+	/// Allocates an id for a new object with the interface `I`, to be passed in a request.
+	pub fn new_id<I: Interface>(&mut self) -> NewResource<I> {
+		self.objects.new_id()
+	}
+
+	/// Allocates an id for an untyped new_id argument, as in `wl_registry.bind`, requesting `version` of `I`.
+	pub fn new_id_untyped<I: Interface>(&mut self, version: u32) -> NewResource<Untyped> {
+		self.objects.new_id_untyped(I::as_dyn(), version)
+	}
+
+	/// The id the client allocated for `new_resource`.
+	pub fn id<I>(&self, new_resource: &NewResource<I>) -> u32 {
+		self.objects.id(new_resource).expect("Object isn't one of the client's")
+	}
+
+	/// Returns the client's object `id`, to be passed as an object argument in a request.
+	///
+	/// Panics if the client doesn't know of the object, or it doesn't have the interface `I`.
+	pub fn object<I: Interface>(&self, id: u32) -> Resource<I> {
+		let interface = self.objects.interface(id).unwrap_or_else(|| panic!("Object {} is unknown to the client", id));
+		if interface.name != I::NAME {
+			panic!("Expected {}@{} to be a {}", interface.name, id, I::NAME);
+		}
+		self.objects.object(id).unwrap()
+	}
+
+	/// Sends `request` to the object `object`. Resources in the request are the client's, from `new_id`,
+	/// `new_id_untyped` and `object`.
+	pub fn send<R: Message<ClientMap=ClientMap>>(&mut self, object: u32, request: R) -> Result<(), TestError> {
+		let (opcode, args) = self.objects.request_args(&request)?;
+		let raw = DynMessage::new(object, opcode, args).into_raw()?;
+		self.send_raw(&raw)
+	}
+
+	/// Sends a message as is, for testing how the server deals with malformed requests.
+	pub fn send_raw(&mut self, raw: &RawMessage) -> Result<(), TestError> {
+		send_message(&self.stream, raw).map_err(TestError::Socket)
+	}
+
+	/// Returns the id of the client's registry, getting one first if the client has none.
+	pub fn get_registry(&mut self) -> Result<u32, TestError> {
+		if let Some(registry) = self.registry {
+			return Ok(registry);
+		}
+		let registry = self.new_id::<WlRegistry>();
+		let id = self.id(&registry);
+		self.send(1, WlDisplayRequest::GetRegistry(wl_display::GetRegistryRequest { registry }))?;
+		self.registry = Some(id);
+		Ok(id)
+	}
+
+	/// Binds `version` of the global advertised with the interface `I`, and returns the id of the new object. Events
+	/// received along the way are kept, so they can still be checked.
+	pub fn bind_global<I: Interface>(&mut self, server: &mut Server, version: u32) -> Result<u32, TestError> {
+		let registry = self.get_registry()?;
+		self.dispatch(server)?;
+		let name = self.events.iter()
+			.filter(|event| event.sender == registry && event.opcode == 0)
+			.find_map(|event| match event.args.as_slice() {
+				[DynArgument::Uint(name), DynArgument::String(Some(interface)), _] if interface.strip_suffix(&[0]) == Some(I::NAME.as_bytes()) => Some(*name),
+				_ => None,
+			})
+			.ok_or(TestError::NoGlobal(I::NAME))?;
+		let id = self.new_id_untyped::<I>(version);
+		let object = self.id(&id);
+		self.send(registry, WlRegistryRequest::Bind(wl_registry::BindRequest { name, id }))?;
+		self.dispatch(server)?;
+		Ok(object)
+	}
+
+	/// Lets the server handle everything sent so far, then reads the events it sent back.
+	pub fn dispatch(&mut self, server: &mut Server) -> Result<(), TestError> {
+		server.dispatch(|_| ())?;
+		self.read_events()
+	}
+
+	/// Reads the events the server sent so far, without letting it dispatch.
+	pub fn read_events(&mut self) -> Result<(), TestError> {
+		loop {
+			match self.buffer.recv(&self.stream) {
+				Ok(true) => {},
+				Ok(false) => {
+					self.disconnected = true;
+					break;
+				},
+				Err(nix::Error::Sys(Errno::EAGAIN)) => break,
+				Err(e) => return Err(TestError::Socket(e)),
+			}
+		}
+
+		while let Some(header) = self.buffer.peek() {
+			let interface = self.objects.interface(header.sender).ok_or(TestError::UnknownObject(header.sender))?;
+			let event = interface.event(header.opcode).ok_or(TestError::UnknownOpcode(interface.name, header.opcode))?;
+			let fd_count = event.args.iter().filter(|arg| arg.arg_type == ArgumentType::Fd).count();
+			let raw = self.buffer.take(fd_count).unwrap();
+			let args = DynMessage::parse_dyn_args(event.args, RawMessageReader::new(&raw))?;
+			self.track_objects(interface, header.opcode, &args);
+			self.events.push_back(ReceivedEvent {
+				sender: header.sender,
+				interface,
+				opcode: header.opcode,
+				args,
+			});
+		}
+
+		Ok(())
+	}
+
+	fn track_objects(&mut self, interface: DynInterface, opcode: u16, args: &[DynArgument]) {
+		if interface == WlDisplay::as_dyn() && opcode == 1 {
+			if let Some(DynArgument::Uint(id)) = args.first() {
+				self.objects.remove(*id);
+			}
+			return;
+		}
+		// Objects created by the server exist on the server side by the time their event is read
+		for arg in args {
+			if let DynArgument::NewId(id, _) = *arg {
+				let interface = self.client.get().ok().and_then(|client| client.object_interface(id));
+				if let Some(interface) = interface {
+					self.objects.add(id, interface);
+				}
+			}
+		}
+	}
+
+	/// Returns the next event that was received, if any.
+	pub fn next_event(&mut self) -> Option<ReceivedEvent> {
+		self.events.pop_front()
+	}
+
+	/// Returns every event that was received and not looked at yet.
+	pub fn take_events(&mut self) -> Vec<ReceivedEvent> {
+		self.events.drain(..).collect()
+	}
+
+	/// Takes the next event, checks that it was sent by `object` with the interface `I`, and converts it to the
+	/// generated event type. Object arguments are resolved to the server's resources, so the client must still be
+	/// connected.
+	///
+	/// Panics if there is no such event.
+	pub fn expect_event<I: Interface>(&mut self, object: u32) -> I::Event where I::Event: Message<ClientMap=ClientMap> {
+		let event = self.next_event().unwrap_or_else(|| panic!("Expected an event from {}@{}, but there are no events", I::NAME, object));
+		if event.sender != object || event.interface.name != I::NAME {
+			panic!("Expected an event from {}@{}, got {}", I::NAME, object, event);
+		}
+		let client = self.client.get().expect("Client was disconnected, check the arguments of `next_event` instead");
+		match I::Event::from_args(client.client_map(), event.opcode, event.args.clone()) {
+			Ok(typed) => typed,
+			Err(e) => panic!("Failed to convert {}: {}", event, e),
+		}
+	}
+
+	/// Panics if any events were received that weren't looked at.
+	pub fn expect_no_events(&mut self) {
+		if let Some(event) = self.events.front() {
+			panic!("Expected no events, got {}", event);
+		}
+	}
+
+	/// Takes events until a `wl_display.error`, checks that it is about `object` and has the error `code`, and returns
+	/// its message. Events before the error are skipped.
+	///
+	/// Panics if no error was received.
+	pub fn expect_error<C: Into<u32>>(&mut self, object: u32, code: C) -> String {
+		let code = code.into();
+		while let Some(event) = self.next_event() {
+			if event.sender != 1 || event.interface != WlDisplay::as_dyn() || event.opcode != 0 {
+				continue;
+			}
+			match event.args.as_slice() {
+				[DynArgument::Object(Some(error_object)), DynArgument::Uint(error_code), DynArgument::String(Some(message))] => {
+					let message = String::from_utf8_lossy(message.strip_suffix(&[0]).unwrap_or(message)).into_owned();
+					if *error_object != object || *error_code != code {
+						panic!("Expected error {} on object {}, got error {} on object {}: {}", code, object, error_code, error_object, message);
+					}
+					return message;
+				},
+				_ => panic!("Malformed error event {}", event),
+			}
+		}
+		panic!("Expected error {} on object {}, but no error was received", code, object);
+	}
+
+	/// Whether the server closed the connection.
+	pub fn is_disconnected(&self) -> bool {
+		self.disconnected
+	}
+
+	/// Panics if the server hasn't closed the connection.
+	pub fn expect_disconnected(&mut self) {
+		if !self.disconnected {
+			panic!("Expected the server to disconnect the client");
+		}
+	}
+}
+
+impl fmt::Debug for TestClient {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("TestClient")
+			.field("client", &self.client.get().ok().map(|client| client.id()))
+			.field("objects", &self.objects)
+			.field("events", &self.events.len())
+			.field("disconnected", &self.disconnected)
+			.finish()
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum TestError {
+	#[error("Failed to use the client socket\n\t{0}")]
+	Socket(#[source] nix::Error),
+	#[error("Failed to convert the event from arguments\n\t{0}")]
+	FromArgs(#[from] FromArgsError),
+	#[error("Failed to convert the request to arguments\n\t{0}")]
+	IntoArgs(#[from] IntoArgsError),
+	#[error("Failed to serialize the request\n\t{0}")]
+	Serialize(#[from] SerializeRawError),
+	#[error("Failed to parse an event\n\t{0}")]
+	Parse(#[from] ParseDynError),
+	#[error("Object {0} is unknown to the client")]
+	UnknownObject(u32),
+	#[error("No {0} global was advertised")]
+	NoGlobal(&'static str),
+	#[error("Received unknown event {1} from a {0}")]
+	UnknownOpcode(&'static str, u16),
+	#[error(transparent)]
+	Server(#[from] ServerError),
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		cell::{RefCell},
+		rc::{Rc},
+	};
+
+	use wl_common::{
+		interface::{Interface},
+	};
+
+	use crate::{
+		Server, BindContext, NewResource,
+		protocol::*,
+		testing::{TestClient, TestError},
+	};
+
+	#[test]
+	fn sync_is_answered() {
+		let mut server = Server::new_without_socket(());
+		let mut client = TestClient::connect(&mut server, ());
+
+		let callback = client.new_id::<WlCallback>();
+		client.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+		client.dispatch(&mut server).unwrap();
+
+		match client.expect_event::<WlCallback>(2) {
+			WlCallbackEvent::Done(_) => {},
+		}
+		client.expect_no_events();
+	}
+
+	#[test]
+	fn globals_are_advertised_and_bound() {
+		let mut server = Server::new_without_socket(());
+		let bound = Rc::new(RefCell::new(Vec::new()));
+		let bound_clone = Rc::clone(&bound);
+		server.register_global::<WlCompositor, _>(move |context: BindContext, new_resource: NewResource<WlCompositor>| {
+			bound_clone.borrow_mut().push(context.version);
+			new_resource.register_fn((), |_, _, _| {}, |_, _| {});
+		});
+		let mut client = TestClient::connect(&mut server, ());
+
+		client.get_registry().unwrap();
+		client.dispatch(&mut server).unwrap();
+		let name = match client.expect_event::<WlRegistry>(2) {
+			WlRegistryEvent::Global(global) => {
+				assert_eq!(global.interface, b"wl_compositor\0");
+				global.name
+			},
+			event => panic!("Unexpected event {:?}", event),
+		};
+
+		let id = client.new_id_untyped::<WlCompositor>(1);
+		client.send(2, WlRegistryRequest::Bind(wl_registry::BindRequest { name, id })).unwrap();
+		client.dispatch(&mut server).unwrap();
+
+		assert_eq!(*bound.borrow(), vec![1]);
+		assert!(client.client().get().unwrap().find_by_id::<WlCompositor>(3).is_some());
+		client.expect_no_events();
+	}
+
+	#[test]
+	fn created_objects_inherit_the_version_of_their_parent() {
+		let mut server = Server::new_without_socket(());
+		server.register_global::<WlCompositor, _>(|_: BindContext, new_resource: NewResource<WlCompositor>| {
+			new_resource.register_fn((), |_, _, request| {
+				if let WlCompositorRequest::CreateSurface(create_surface) = request {
+					create_surface.id.register_fn((), |_, _, _| {}, |_, _| {});
+				}
+			}, |_, _| {});
+		});
+		let mut client = TestClient::connect(&mut server, ());
+
+		let compositor = client.bind_global::<WlCompositor>(&mut server, 2).unwrap();
+		let id = client.new_id::<WlSurface>();
+		let surface = client.id(&id);
+		client.send(compositor, WlCompositorRequest::CreateSurface(wl_compositor::CreateSurfaceRequest { id })).unwrap();
+		client.dispatch(&mut server).unwrap();
+
+		let surface = client.client().get().unwrap().find_by_id::<WlSurface>(surface).unwrap();
+		assert_eq!(surface.version(), Some(2));
+	}
+
+	#[test]
+	fn binding_an_unknown_global_is_an_error() {
+		let mut server = Server::new_without_socket(());
+		let mut client = TestClient::connect(&mut server, ());
+
+		let registry = client.get_registry().unwrap();
+		let id = client.new_id_untyped::<WlCompositor>(1);
+		client.send(registry, WlRegistryRequest::Bind(wl_registry::BindRequest { name: 42, id })).unwrap();
+		client.dispatch(&mut server).unwrap();
+
+		client.expect_error(2, wl_display::Error::InvalidObject);
+		client.expect_disconnected();
+		assert!(client.client().get().is_err());
+	}
+
+	#[test]
+	fn binding_with_the_wrong_interface_or_version_is_an_error() {
+		let mut server = Server::new_without_socket(());
+		server.register_global::<WlCompositor, _>(|_: BindContext, new_resource: NewResource<WlCompositor>| {
+			new_resource.register_fn((), |_, _, _| {}, |_, _| {});
+		});
+
+		let mut client = TestClient::connect(&mut server, ());
+		let registry = client.get_registry().unwrap();
+		let id = client.new_id_untyped::<WlShm>(1);
+		client.send(registry, WlRegistryRequest::Bind(wl_registry::BindRequest { name: 1, id })).unwrap();
+		client.dispatch(&mut server).unwrap();
+		let message = client.expect_error(2, wl_display::Error::InvalidObject);
+		assert_eq!(message, "invalid interface for global 1: have wl_shm, wanted wl_compositor");
+		client.expect_disconnected();
+
+		let mut client = TestClient::connect(&mut server, ());
+		let registry = client.get_registry().unwrap();
+		let id = client.new_id_untyped::<WlCompositor>(WlCompositor::VERSION + 1);
+		client.send(registry, WlRegistryRequest::Bind(wl_registry::BindRequest { name: 1, id })).unwrap();
+		client.dispatch(&mut server).unwrap();
+		client.expect_error(2, wl_display::Error::InvalidObject);
+		client.expect_disconnected();
+	}
+
+	#[test]
+	fn globals_are_bound_by_interface() {
+		let mut server = Server::new_without_socket(());
+		server.register_global::<WlCompositor, _>(|_: BindContext, new_resource: NewResource<WlCompositor>| {
+			new_resource.register_fn((), |_, _, _| {}, |_, _| {});
+		});
+		let mut client = TestClient::connect(&mut server, ());
+
+		let compositor = client.bind_global::<WlCompositor>(&mut server, 2).unwrap();
+		assert_eq!(compositor, 3);
+		let compositor = client.client().get().unwrap().find_by_id::<WlCompositor>(compositor).unwrap();
+		assert_eq!(compositor.version(), Some(2));
+		assert!(matches!(client.bind_global::<WlShm>(&mut server, 1), Err(TestError::NoGlobal("wl_shm"))));
+	}
+
+	#[test]
+	fn new_ids_are_only_created_on_the_server_by_their_request() {
+		let mut server = Server::new_without_socket(());
+		let mut client = TestClient::connect(&mut server, ());
+
+		let callback = client.new_id::<WlCallback>();
+		assert!(client.client().get().unwrap().find_by_id_untyped(2).is_none());
+		client.send(1, WlDisplayRequest::Sync(wl_display::SyncRequest { callback })).unwrap();
+		client.dispatch(&mut server).unwrap();
+
+		client.expect_event::<WlCallback>(2);
+		assert!(!client.is_disconnected());
+	}
+}